@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// Read when no admin socket is given, both by the server and by its clients.
+pub const DEFAULT_ADMIN_SOCKET: &str = "chat.sock";
+
+/// A request to the admin socket of the server. Every request is a line of json,
+/// answered by a line of json with a [`Response`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Request {
+    Connections,
+    Rooms,
+    Kick {
+        nick: String,
+        #[serde(default)]
+        reason: String,
+    },
+    /// `target` is either a nick or an ip address
+    Ban {
+        target: String,
+        secs: u64,
+        #[serde(default)]
+        reason: String,
+    },
+    /// Sent to every logged in user
+    Announce {
+        text: String,
+    },
+    Limits,
+    /// Changes only the given limits, and replies with all of them
+    SetLimits(LimitsUpdate),
+    Stats,
+    /// Loads the configuration file again
+    Reload,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Ok(Reply),
+    Error(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reply {
+    Done,
+    Connections(Vec<ConnectionInfo>),
+    Rooms(Vec<RoomInfo>),
+    Limits(Limits),
+    Stats(Stats),
+    /// The settings that changed but cannot be applied until the server restarts
+    Reloaded {
+        restart_needed: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub addr: SocketAddr,
+    pub nick: String,
+    pub room: String,
+    pub account: Option<String>,
+    pub moderator: bool,
+    pub authenticated: bool,
+    pub muted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: usize,
+}
+
+/// The limits that can be changed while the server runs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    pub max_connections: usize,
+    pub max_conns_per_subnet: usize,
+    pub max_msg_len: usize,
+    pub read_timeout_ms: u64,
+    pub msgs_per_sec: f64,
+    pub msg_burst: f64,
+    pub bytes_per_sec: f64,
+    pub byte_burst: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LimitsUpdate {
+    pub max_connections: Option<usize>,
+    pub max_conns_per_subnet: Option<usize>,
+    pub max_msg_len: Option<usize>,
+    pub read_timeout_ms: Option<u64>,
+    pub msgs_per_sec: Option<f64>,
+    pub msg_burst: Option<f64>,
+    pub bytes_per_sec: Option<f64>,
+    pub byte_burst: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    pub uptime_secs: u64,
+    pub connections: usize,
+    pub rooms: usize,
+    /// Since the server started
+    pub total_connections: u64,
+    /// Broadcast to the rooms since the server started
+    pub total_messages: u64,
+}
+
+#[cfg(test)]
+mod admin_tests {
+    use super::*;
+
+    #[test]
+    fn json_test() {
+        let request = serde_json::from_str::<Request>(r#"{"kick":{"nick":"bob"}}"#).unwrap();
+        assert_eq!(
+            request,
+            Request::Kick {
+                nick: "bob".to_string(),
+                reason: String::new()
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<Request>(r#""stats""#).unwrap(),
+            Request::Stats
+        );
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"set_limits":{"max_connections":5}}"#).unwrap(),
+            Request::SetLimits(LimitsUpdate {
+                max_connections: Some(5),
+                ..LimitsUpdate::default()
+            })
+        );
+        assert!(serde_json::from_str::<Request>(r#""reboot""#).is_err());
+
+        let response = Response::Ok(Reply::Rooms(vec![RoomInfo {
+            name: "lobby".to_string(),
+            members: 2,
+        }]));
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"ok":{"rooms":[{"name":"lobby","members":2}]}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Response::Error("no".to_string())).unwrap(),
+            r#"{"error":"no"}"#
+        );
+    }
+}
@@ -0,0 +1,355 @@
+use async_chat::{
+    admin::{
+        ConnectionInfo, Limits, LimitsUpdate, Reply, Request, Response, RoomInfo, Stats,
+        DEFAULT_ADMIN_SOCKET,
+    },
+    command::parse_duration,
+};
+use clap::{Parser, Subcommand};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
+/// Talks to the admin socket of a running server.
+#[derive(Debug, Parser)]
+#[command(about = "Administer a running async chat server")]
+struct Args {
+    /// Admin socket of the server
+    #[arg(short, long, default_value = DEFAULT_ADMIN_SOCKET)]
+    socket: PathBuf,
+    /// Print the responses of the server as json, one per line
+    #[arg(long)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the connected users
+    Users,
+    /// List the rooms and how many users are in them
+    Rooms,
+    /// Disconnect a user
+    Kick {
+        nick: String,
+        /// Told to the user
+        reason: Vec<String>,
+    },
+    /// Disconnect a user and keep it out. A logged in user is banned by account, a guest by address
+    Ban {
+        /// A nick or an ip address
+        target: String,
+        /// Like 30s, 10m, 2h or 7d
+        #[arg(value_parser = duration_arg)]
+        duration: Duration,
+        /// Told to the user
+        reason: Vec<String>,
+    },
+    /// Send a notice to every logged in user
+    Announce {
+        #[arg(required = true)]
+        text: Vec<String>,
+    },
+    /// Show the limits, after changing the given ones
+    Limits(LimitsFlags),
+    /// Load the configuration file of the server again
+    Reload,
+    /// Show the statistics of the server
+    Stats,
+}
+
+#[derive(Debug, Default, clap::Args)]
+struct LimitsFlags {
+    #[arg(long)]
+    max_connections: Option<usize>,
+    #[arg(long)]
+    max_conns_per_subnet: Option<usize>,
+    #[arg(long)]
+    max_msg_len: Option<usize>,
+    #[arg(long)]
+    read_timeout_ms: Option<u64>,
+    #[arg(long)]
+    msgs_per_sec: Option<f64>,
+    #[arg(long)]
+    msg_burst: Option<f64>,
+    #[arg(long)]
+    bytes_per_sec: Option<f64>,
+    #[arg(long)]
+    byte_burst: Option<f64>,
+}
+
+fn duration_arg(text: &str) -> Result<Duration, String> {
+    parse_duration(text)
+        .ok_or_else(|| format!("invalid duration '{}', use like 30s, 10m, 2h or 7d", text))
+}
+
+impl Command {
+    fn into_request(self) -> Request {
+        match self {
+            Self::Users => Request::Connections,
+            Self::Rooms => Request::Rooms,
+            Self::Kick { nick, reason } => Request::Kick {
+                nick,
+                reason: reason.join(" "),
+            },
+            Self::Ban {
+                target,
+                duration,
+                reason,
+            } => Request::Ban {
+                target,
+                secs: duration.as_secs(),
+                reason: reason.join(" "),
+            },
+            Self::Announce { text } => Request::Announce {
+                text: text.join(" "),
+            },
+            Self::Limits(flags) => {
+                let update = LimitsUpdate {
+                    max_connections: flags.max_connections,
+                    max_conns_per_subnet: flags.max_conns_per_subnet,
+                    max_msg_len: flags.max_msg_len,
+                    read_timeout_ms: flags.read_timeout_ms,
+                    msgs_per_sec: flags.msgs_per_sec,
+                    msg_burst: flags.msg_burst,
+                    bytes_per_sec: flags.bytes_per_sec,
+                    byte_burst: flags.byte_burst,
+                };
+                if update == LimitsUpdate::default() {
+                    Request::Limits
+                } else {
+                    Request::SetLimits(update)
+                }
+            }
+            Self::Reload => Request::Reload,
+            Self::Stats => Request::Stats,
+        }
+    }
+}
+
+fn send(socket: &Path, request: &Request) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(socket)?;
+    let mut json = serde_json::to_string(request)?;
+    json.push('\n');
+    stream.write_all(json.as_bytes())?;
+    let mut line = String::new();
+    let _ = BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Lays out `rows` in columns under `header`.
+fn table(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths = header.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let header = header.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+    std::iter::once(&header)
+        .chain(rows)
+        .map(|row| {
+            row.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn users(connections: &[ConnectionInfo]) -> String {
+    let rows = connections
+        .iter()
+        .map(|conn| {
+            let mut flags = vec![];
+            if conn.moderator {
+                flags.push("moderator");
+            }
+            if conn.muted {
+                flags.push("muted");
+            }
+            if !conn.authenticated {
+                flags.push("logging in");
+            }
+            vec![
+                conn.addr.to_string(),
+                conn.nick.clone(),
+                conn.room.clone(),
+                conn.account.clone().unwrap_or_else(|| "-".to_string()),
+                flags.join(","),
+            ]
+        })
+        .collect::<Vec<_>>();
+    table(&["ADDRESS", "NICK", "ROOM", "ACCOUNT", "FLAGS"], &rows)
+}
+
+fn rooms(rooms: &[RoomInfo]) -> String {
+    let rows = rooms
+        .iter()
+        .map(|room| vec![room.name.clone(), room.members.to_string()])
+        .collect::<Vec<_>>();
+    table(&["ROOM", "USERS"], &rows)
+}
+
+fn limits(limits: &Limits) -> String {
+    [
+        ("max_connections", limits.max_connections.to_string()),
+        (
+            "max_conns_per_subnet",
+            limits.max_conns_per_subnet.to_string(),
+        ),
+        ("max_msg_len", limits.max_msg_len.to_string()),
+        ("read_timeout_ms", limits.read_timeout_ms.to_string()),
+        ("msgs_per_sec", limits.msgs_per_sec.to_string()),
+        ("msg_burst", limits.msg_burst.to_string()),
+        ("bytes_per_sec", limits.bytes_per_sec.to_string()),
+        ("byte_burst", limits.byte_burst.to_string()),
+    ]
+    .iter()
+    .map(|(name, value)| format!("{}: {}", name, value))
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+fn stats(stats: &Stats) -> String {
+    let secs = stats.uptime_secs;
+    format!(
+        "uptime: {}d {}h {}m {}s\nconnections: {}\nrooms: {}\ntotal connections: {}\ntotal messages: {}",
+        secs / 86_400,
+        secs % 86_400 / 3_600,
+        secs % 3_600 / 60,
+        secs % 60,
+        stats.connections,
+        stats.rooms,
+        stats.total_connections,
+        stats.total_messages
+    )
+}
+
+fn render(reply: &Reply) -> String {
+    match reply {
+        Reply::Done => "Done".to_string(),
+        Reply::Connections(connections) => users(connections),
+        Reply::Rooms(list) => rooms(list),
+        Reply::Limits(list) => limits(list),
+        Reply::Stats(list) => stats(list),
+        Reply::Reloaded { restart_needed } if restart_needed.is_empty() => "Reloaded".to_string(),
+        Reply::Reloaded { restart_needed } => format!(
+            "Reloaded. Restart the server to apply: {}",
+            restart_needed.join(", ")
+        ),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let response = match send(&args.socket, &args.command.into_request()) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!(
+                "Cannot reach the server at {}: {}",
+                args.socket.display(),
+                e
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    if args.json {
+        match serde_json::to_string(&response) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("Cannot encode the response: {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    match response {
+        Response::Ok(reply) => {
+            if !args.json {
+                println!("{}", render(&reply));
+            }
+            ExitCode::SUCCESS
+        }
+        Response::Error(e) => {
+            if !args.json {
+                eprintln!("Error: {}", e);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod chatctl_tests {
+    use super::*;
+
+    fn request(args: &[&str]) -> Request {
+        let args = Args::try_parse_from(std::iter::once("chatctl").chain(args.iter().copied()))
+            .expect("Invalid arguments");
+        args.command.into_request()
+    }
+
+    #[test]
+    fn request_test() {
+        assert_eq!(request(&["users"]), Request::Connections);
+        assert_eq!(
+            request(&["ban", "bob", "2h", "too", "rude"]),
+            Request::Ban {
+                target: "bob".to_string(),
+                secs: 7_200,
+                reason: "too rude".to_string()
+            }
+        );
+        assert_eq!(request(&["limits"]), Request::Limits);
+        assert_eq!(
+            request(&["limits", "--max-connections", "10"]),
+            Request::SetLimits(LimitsUpdate {
+                max_connections: Some(10),
+                ..LimitsUpdate::default()
+            })
+        );
+        assert!(Args::try_parse_from(["chatctl", "ban", "bob", "forever"]).is_err());
+        assert!(Args::try_parse_from(["chatctl", "announce"]).is_err());
+    }
+
+    #[test]
+    fn render_test() {
+        let connections = vec![ConnectionInfo {
+            addr: "127.0.0.1:5000".parse().unwrap(),
+            nick: "alice".to_string(),
+            room: "lobby".to_string(),
+            account: None,
+            moderator: true,
+            authenticated: true,
+            muted: true,
+        }];
+        assert_eq!(
+            render(&Reply::Connections(connections)),
+            "ADDRESS         NICK   ROOM   ACCOUNT  FLAGS\n\
+             127.0.0.1:5000  alice  lobby  -        moderator,muted"
+        );
+        assert_eq!(
+            render(&Reply::Reloaded {
+                restart_needed: vec!["port".to_string(), "tls".to_string()]
+            }),
+            "Reloaded. Restart the server to apply: port, tls"
+        );
+        let stats = Stats {
+            uptime_secs: 90_061,
+            connections: 1,
+            rooms: 1,
+            total_connections: 2,
+            total_messages: 3,
+        };
+        assert!(render(&Reply::Stats(stats)).starts_with("uptime: 1d 1h 1m 1s\n"));
+    }
+}
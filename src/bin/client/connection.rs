@@ -1,68 +1,82 @@
-use async_chat::message::{ParsedMsg, SerializedMessage, MAX_MSG_LEN};
+use crate::tls;
+use async_chat::message::{AuthKind, FrameDecoder, ParsedMsg, SerializedMessage, MAX_MSG_LEN};
+use rustls::ClientConfig;
 use std::{
     io::{self, ErrorKind, Read, Write},
     net::TcpStream,
-    sync::mpsc::{channel, Receiver},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
     thread::spawn,
-    time::Duration,
+    time::{Duration, Instant},
 };
+use tracing::warn;
+
+// Written to by the ui, and by the reader thread for the heartbeats
+type SharedWriter = Arc<Mutex<Box<dyn Write + Send>>>;
+type Latency = Arc<Mutex<Option<Duration>>>;
+
+// The server relays the messages of up to its own limit, along with the room and the nick
+const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// How long the server may stay silent before it is pinged, and how many pings it may leave
+/// unanswered before the connection is given up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub missed: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            missed: 3,
+        }
+    }
+}
 
 pub struct Connection {
-    stream: TcpStream,
+    stream: SharedWriter,
     msg_receiver: Receiver<io::Result<ParsedMsg>>,
+    latency: Latency,
 }
 
 impl Connection {
-    #[must_use]
-    pub fn new(ip: &str, port: u16) -> io::Result<Self> {
+    pub fn new(
+        ip: &str,
+        port: u16,
+        tls: Option<&Arc<ClientConfig>>,
+        heartbeat: Heartbeat,
+    ) -> io::Result<Self> {
         let (msg_sender, msg_receiver) = channel();
-        let mut stream = TcpStream::connect(format!("{}:{}", ip, port))?;
+        let stream = TcpStream::connect(format!("{}:{}", ip, port))?;
         stream
             .set_write_timeout(Some(Duration::from_millis(100)))
             .unwrap();
-        let stream_clone = stream.try_clone()?;
-        spawn(move || {
-            let mut payload = vec![0; 256];
-            loop {
-                let mut buf = [0; SerializedMessage::size_of_len()];
-                if let Err(e) = stream.read_exact(&mut buf) {
-                    if let Err(_) = msg_sender.send(Err(e)) {}
-                    break;
-                }
-                let size = u32::from_be_bytes(buf);
-                assert!(size > SerializedMessage::size_of_len() as u32);
-                payload.resize(size as usize, 0);
-                buf.into_iter()
-                    .enumerate()
-                    .for_each(|(i, b)| payload[i] = b);
-
-                // The message type
-                if let Err(e) = stream.read_exact(
-                    &mut payload
-                        [SerializedMessage::size_of_len()..SerializedMessage::size_of_header()],
-                ) {
-                    if let Err(_) = msg_sender.send(Err(e)) {}
-                    break;
-                }
-                if let Err(e) =
-                    stream.read_exact(&mut payload[SerializedMessage::size_of_header()..])
-                {
-                    if let Err(_) = msg_sender.send(Err(e)) {}
-                    break;
-                }
-                if let Some(msg) = ParsedMsg::from_bytes(&payload) {
-                    if let Err(_) = msg_sender.send(Ok(msg)) {
-                        break;
-                    }
-                    payload.clear();
-                } else {
-                    break;
-                };
+        // Waiting for the next message times out once a heartbeat is due
+        stream.set_read_timeout(Some(heartbeat.interval))?;
+        let (stream, stream_clone): (Box<dyn Read + Send>, Box<dyn Write + Send>) = match tls {
+            Some(config) => {
+                let (reader, writer) = tls::connect(stream, ip, Arc::clone(config))?;
+                (Box::new(reader), Box::new(writer))
             }
-        });
+            None => (Box::new(stream.try_clone()?), Box::new(stream)),
+        };
+        let stream_clone: SharedWriter = Arc::new(Mutex::new(stream_clone));
+        let latency = Latency::default();
+        let reader = MsgReader {
+            writer: Arc::clone(&stream_clone),
+            heartbeat,
+            started: Instant::now(),
+            latency: Arc::clone(&latency),
+        };
+        spawn(move || reader.read_msgs(stream, &msg_sender));
         Ok(Self {
             stream: stream_clone,
             msg_receiver,
+            latency,
         })
     }
 
@@ -74,41 +88,259 @@ impl Connection {
             },
             Reader {
                 msg_receiver: self.msg_receiver,
+                latency: self.latency,
             },
         )
     }
 }
 
 pub struct Writer {
-    stream: TcpStream,
+    stream: SharedWriter,
 }
 
 impl Writer {
     // TODO: use a channel to queue several messages
-    #[must_use]
     pub fn try_send_msg(&mut self, msg: &str) -> io::Result<()> {
-        if msg.as_bytes().len() > MAX_MSG_LEN {
-            return Err(io::Error::new(
-                ErrorKind::Other,
-                format!("Message too long. Max lenght in bytes is {}", MAX_MSG_LEN),
-            ));
+        if msg.len() > MAX_MSG_LEN {
+            return Err(io::Error::other(format!(
+                "Message too long. Max lenght in bytes is {}",
+                MAX_MSG_LEN
+            )));
         }
-        self.stream
-            .write_all(SerializedMessage::from_string(msg).as_bytes())?;
-        self.stream.flush()
+        write_msg(&self.stream, &SerializedMessage::from_string(msg))
+    }
+
+    pub fn send_auth(&mut self, kind: AuthKind, user: &str, password: &str) -> io::Result<()> {
+        write_msg(
+            &self.stream,
+            &SerializedMessage::from_auth_request(kind, user, password),
+        )
     }
 }
 
+fn write_msg(stream: &SharedWriter, msg: &SerializedMessage) -> io::Result<()> {
+    let mut stream = stream.lock().expect("Writer lock is poisoned");
+    stream.write_all(msg.as_bytes())?;
+    stream.flush()
+}
+
 pub struct Reader {
     msg_receiver: Receiver<io::Result<ParsedMsg>>,
+    latency: Latency,
 }
 
 impl Reader {
     #[must_use]
     pub fn try_read_msg(&self) -> Option<io::Result<ParsedMsg>> {
-        match self.msg_receiver.recv_timeout(Duration::from_millis(0)) {
-            Ok(msg) => Some(msg),
-            Err(_) => None,
+        self.msg_receiver
+            .recv_timeout(Duration::from_millis(0))
+            .ok()
+    }
+
+    /// The round trip time of the latest ping answered by the server.
+    #[must_use]
+    pub fn latency(&self) -> Option<Duration> {
+        *self.latency.lock().expect("Latency lock is poisoned")
+    }
+}
+
+/// The reader thread, which also pings the server and answers its pings.
+struct MsgReader {
+    writer: SharedWriter,
+    heartbeat: Heartbeat,
+    // The tokens of the pings are the microseconds since then
+    started: Instant,
+    latency: Latency,
+}
+
+impl MsgReader {
+    fn read_msgs(
+        &self,
+        mut stream: Box<dyn Read + Send>,
+        msg_sender: &Sender<io::Result<ParsedMsg>>,
+    ) {
+        let mut decoder = FrameDecoder::new(MAX_FRAME_LEN);
+        let mut chunk = [0; 4096];
+        let mut missed = 0;
+        'read: loop {
+            // Nothing is lost when waiting times out, the decoder keeps the partial messages
+            let read = match stream.read(&mut chunk) {
+                Ok(0) => {
+                    let _ = msg_sender.send(Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "the server closed the connection",
+                    )));
+                    break;
+                }
+                Ok(read) => read,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if missed >= self.heartbeat.missed {
+                        let _ = msg_sender.send(Err(io::Error::new(
+                            ErrorKind::TimedOut,
+                            "the server stopped answering",
+                        )));
+                        break;
+                    }
+                    missed += 1;
+                    let token = self.started.elapsed().as_micros() as u64;
+                    if let Err(e) = write_msg(&self.writer, &SerializedMessage::from_ping(token)) {
+                        let _ = msg_sender.send(Err(e));
+                        break;
+                    }
+                    continue;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    let _ = msg_sender.send(Err(e));
+                    break;
+                }
+            };
+            missed = 0;
+            decoder.push(&chunk[..read]);
+            while let Some(frame) = decoder.next_frame() {
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        warn!(error = ?e, "skipping invalid message from the server");
+                        continue;
+                    }
+                };
+                match ParsedMsg::from_bytes(frame) {
+                    Some(ParsedMsg::Ping(token)) => {
+                        if let Err(e) =
+                            write_msg(&self.writer, &SerializedMessage::from_pong(token))
+                        {
+                            let _ = msg_sender.send(Err(e));
+                            break 'read;
+                        }
+                    }
+                    Some(ParsedMsg::Pong(token)) => {
+                        let latency = self
+                            .started
+                            .elapsed()
+                            .checked_sub(Duration::from_micros(token));
+                        *self.latency.lock().expect("Latency lock is poisoned") = latency;
+                    }
+                    Some(msg) => {
+                        if msg_sender.send(Ok(msg)).is_err() {
+                            break 'read;
+                        }
+                    }
+                    None => {
+                        let _ = msg_sender.send(Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            "invalid message from the server",
+                        )));
+                        break 'read;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod connection_tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn read_frame(stream: &mut TcpStream) -> ParsedMsg {
+        let mut len = [0; SerializedMessage::size_of_len()];
+        stream.read_exact(&mut len).unwrap();
+        let mut buf = len.to_vec();
+        buf.resize(u32::from_be_bytes(len) as usize, 0);
+        stream
+            .read_exact(&mut buf[SerializedMessage::size_of_len()..])
+            .unwrap();
+        ParsedMsg::from_bytes(&buf).unwrap()
+    }
+
+    fn next_msg(reader: &Reader) -> io::Result<ParsedMsg> {
+        reader
+            .msg_receiver
+            .recv_timeout(Duration::from_secs(5))
+            .expect("No message")
+    }
+
+    #[test]
+    fn framing_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (_writer, reader) = Connection::new("127.0.0.1", port, None, Heartbeat::default())
+            .unwrap()
+            .split();
+        let (mut server, _) = listener.accept().unwrap();
+
+        // Split across writes
+        let msg = SerializedMessage::from_room_text(Some(1), "lobby", "alice: Hi");
+        let (head, tail) = msg.as_bytes().split_at(3);
+        server.write_all(head).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        server.write_all(tail).unwrap();
+        assert_eq!(
+            next_msg(&reader).unwrap(),
+            ParsedMsg::RoomText {
+                seq: Some(1),
+                room: "lobby".to_string(),
+                text: "alice: Hi".to_string()
+            }
+        );
+
+        // A size shorter than the header is skipped, not a panic
+        server.write_all(&[0, 0, 0, 1, 0]).unwrap();
+        server
+            .write_all(SerializedMessage::from_string("hello").as_bytes())
+            .unwrap();
+        assert_eq!(
+            next_msg(&reader).unwrap(),
+            ParsedMsg::Text("hello".to_string())
+        );
+
+        drop(server);
+        let e = next_msg(&reader).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn heartbeat_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let heartbeat = Heartbeat {
+            interval: Duration::from_millis(50),
+            missed: 2,
+        };
+        let (_writer, reader) = Connection::new("127.0.0.1", port, None, heartbeat)
+            .unwrap()
+            .split();
+        let (mut server, _) = listener.accept().unwrap();
+
+        // Pinged while the server is silent
+        let ParsedMsg::Ping(token) = read_frame(&mut server) else {
+            panic!("Not pinged");
+        };
+        assert_eq!(reader.latency(), None);
+        server
+            .write_all(SerializedMessage::from_pong(token).as_bytes())
+            .unwrap();
+        // The pings of the server are answered
+        server
+            .write_all(SerializedMessage::from_ping(7).as_bytes())
+            .unwrap();
+        server
+            .write_all(SerializedMessage::from_string("hello").as_bytes())
+            .unwrap();
+        assert_eq!(
+            next_msg(&reader).unwrap(),
+            ParsedMsg::Text("hello".to_string())
+        );
+        assert!(reader.latency().is_some());
+        let mut answered = false;
+        while !answered {
+            answered = read_frame(&mut server) == ParsedMsg::Pong(7);
         }
+
+        // Given up once the pings go unanswered
+        let e = next_msg(&reader).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
     }
 }
@@ -1,4 +1,6 @@
 mod connection;
+mod sequence;
+mod tls;
 mod ui;
 
 fn main() {
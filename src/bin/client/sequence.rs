@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+/// What to do with a chat message replayed from the history, given the ones seen before.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Delivery {
+    Show,
+    /// Shown before a reconnection, or before leaving the room
+    Duplicate,
+    /// Shown, but some messages of the room before it fell out of the history
+    AfterGap,
+}
+
+/// The latest sequence number seen from the server in every room, kept across reconnections.
+#[derive(Debug, Default, Clone)]
+pub struct Sequence {
+    last: HashMap<String, u64>,
+}
+
+impl Sequence {
+    /// `prev_seq` is the message before it in the room, as told by the server.
+    pub fn replayed(&mut self, room: &str, seq: u64, prev_seq: Option<u64>) -> Delivery {
+        // The first message of the room comes before what was seen: the server restarted
+        // without a chat log, so the numbering started over in every room
+        if prev_seq.is_none() && self.last.get(room).is_some_and(|&last| seq < last) {
+            self.last.clear();
+        }
+        let delivery = match self.last.get(room) {
+            Some(&last) if seq <= last => return Delivery::Duplicate,
+            Some(&last) if prev_seq.is_some_and(|prev| prev > last) => Delivery::AfterGap,
+            _ => Delivery::Show,
+        };
+        let _ = self.last.insert(room.to_string(), seq);
+        delivery
+    }
+
+    /// A live message is always new. It goes back only when the server restarted
+    /// without a chat log, so the numbering started over in every room.
+    pub fn live(&mut self, room: &str, seq: u64) {
+        if self.last.get(room).is_some_and(|&last| seq <= last) {
+            self.last.clear();
+        }
+        let _ = self.last.insert(room.to_string(), seq);
+    }
+}
+
+#[cfg(test)]
+mod sequence_tests {
+    use super::*;
+
+    #[test]
+    fn replay_test() {
+        let mut sequence = Sequence::default();
+        // Everything is new on the first connection
+        assert_eq!(sequence.replayed("lobby", 5, None), Delivery::Show);
+        assert_eq!(sequence.replayed("lobby", 6, Some(5)), Delivery::Show);
+        sequence.live("lobby", 9);
+
+        // Reconnected, the history overlaps what was seen
+        assert_eq!(sequence.replayed("lobby", 8, Some(6)), Delivery::Duplicate);
+        assert_eq!(sequence.replayed("lobby", 9, Some(8)), Delivery::Duplicate);
+        // The messages in between were sent to other rooms
+        assert_eq!(sequence.replayed("lobby", 12, Some(9)), Delivery::Show);
+
+        // Reconnected after the history moved past what was seen
+        assert_eq!(sequence.replayed("lobby", 20, Some(15)), Delivery::AfterGap);
+        assert_eq!(sequence.replayed("lobby", 22, Some(20)), Delivery::Show);
+
+        // A room joined for the first time, then again
+        assert_eq!(sequence.replayed("rust", 3, Some(1)), Delivery::Show);
+        assert_eq!(sequence.replayed("rust", 3, Some(1)), Delivery::Duplicate);
+        assert_eq!(sequence.replayed("rust", 21, Some(3)), Delivery::Show);
+
+        // The server started over
+        sequence.live("lobby", 1);
+        assert_eq!(sequence.replayed("lobby", 2, None), Delivery::Show);
+        assert_eq!(sequence.replayed("rust", 2, None), Delivery::Show);
+    }
+
+    #[test]
+    fn restart_test() {
+        let mut sequence = Sequence::default();
+        assert_eq!(sequence.replayed("lobby", 40, None), Delivery::Show);
+        sequence.live("lobby", 41);
+        sequence.live("rust", 42);
+
+        // Reconnected to a server that started over, its history comes first
+        assert_eq!(sequence.replayed("lobby", 1, None), Delivery::Show);
+        assert_eq!(sequence.replayed("lobby", 3, Some(1)), Delivery::Show);
+        assert_eq!(sequence.replayed("rust", 2, None), Delivery::Show);
+        // Unlike the first message of a room replayed again
+        assert_eq!(sequence.replayed("rust", 2, None), Delivery::Duplicate);
+    }
+}
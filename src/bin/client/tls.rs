@@ -0,0 +1,284 @@
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    SignatureScheme,
+};
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+const RECORDS_BUF_LEN: usize = 16 * 1024;
+
+/// How the certificate of the server is trusted.
+#[derive(Debug, Clone)]
+pub enum Trust {
+    /// PEM file with the certificate authorities to validate the server against
+    Ca(PathBuf),
+    /// PEM file with the exact certificate the server must present
+    Pinned(PathBuf),
+}
+
+pub fn client_config(trust: &Trust) -> io::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder();
+    let config = match trust {
+        Trust::Ca(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| invalid_data(path, e.to_string()))?;
+            }
+            builder.with_root_certificates(roots)
+        }
+        Trust::Pinned(path) => {
+            let cert = load_certs(path)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| invalid_data(path, "no certificate found".to_string()))?;
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCert::new(cert)))
+        }
+    };
+    Ok(Arc::new(config.with_no_client_auth()))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect)
+        .map_err(|e| invalid_data(path, e.to_string()))
+}
+
+fn invalid_data(path: &Path, e: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    )
+}
+
+/// Accepts only the given certificate, whatever the name of the server is.
+#[derive(Debug)]
+struct PinnedCert {
+    cert: CertificateDer<'static>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl PinnedCert {
+    fn new(cert: CertificateDer<'static>) -> Self {
+        Self {
+            cert,
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if *end_entity == self.cert {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Completes the handshake on `stream` and splits it, so that reading and
+/// writing can happen on different threads.
+pub fn connect(
+    mut stream: TcpStream,
+    server: &str,
+    config: Arc<ClientConfig>,
+) -> io::Result<(TlsReader, TlsWriter)> {
+    let server_name = ServerName::try_from(server.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut conn = ClientConnection::new(config, server_name).map_err(io::Error::other)?;
+    while conn.is_handshaking() {
+        let _ = conn.complete_io(&mut stream)?;
+    }
+    let conn = Arc::new(Mutex::new(conn));
+    Ok((
+        TlsReader {
+            conn: Arc::clone(&conn),
+            stream: stream.try_clone()?,
+            records: vec![0; RECORDS_BUF_LEN],
+        },
+        TlsWriter { conn, stream },
+    ))
+}
+
+fn write_records(conn: &mut ClientConnection, stream: &mut TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        let _ = conn.write_tls(stream)?;
+    }
+    Ok(())
+}
+
+pub struct TlsReader {
+    conn: Arc<Mutex<ClientConnection>>,
+    stream: TcpStream,
+    records: Vec<u8>,
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self
+                .conn
+                .lock()
+                .expect("TLS lock is poisoned")
+                .reader()
+                .read(buf)
+            {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                res => return res,
+            }
+            // Wait for the records without holding the lock, so that the writer can go on
+            let n = self.stream.read(&mut self.records)?;
+            let mut conn = self.conn.lock().expect("TLS lock is poisoned");
+            let mut records = &self.records[..n];
+            loop {
+                let _ = conn.read_tls(&mut records)?;
+                let _ = conn
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if records.is_empty() {
+                    break;
+                }
+            }
+            write_records(&mut conn, &mut self.stream)?;
+        }
+    }
+}
+
+pub struct TlsWriter {
+    conn: Arc<Mutex<ClientConnection>>,
+    stream: TcpStream,
+}
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().expect("TLS lock is poisoned");
+        let n = conn.writer().write(buf)?;
+        write_records(&mut conn, &mut self.stream)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.conn.lock().expect("TLS lock is poisoned");
+        conn.writer().flush()?;
+        write_records(&mut conn, &mut self.stream)?;
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tls_tests {
+    use super::*;
+    use rustls::{pki_types::PrivateKeyDer, ServerConfig, ServerConnection, StreamOwned};
+    use std::{net::TcpListener, thread};
+
+    struct TestCert {
+        dir: PathBuf,
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+    }
+
+    fn self_signed(name: &str) -> TestCert {
+        let cert = rcgen::generate_simple_self_signed(["127.0.0.1".to_string()])
+            .expect("Cannot generate certificate");
+        let dir = std::env::temp_dir().join(format!("async_chat_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        TestCert {
+            dir,
+            cert: cert.cert.der().clone(),
+            key: PrivateKeyDer::try_from(cert.key_pair.serialize_der()).unwrap(),
+        }
+    }
+
+    /// Echoes back a single line over tls.
+    fn echo_server(cert: &TestCert) -> u16 {
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.clone()], cert.key.clone_key())
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let conn = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut stream = StreamOwned::new(conn, stream);
+            let mut buf = [0; 5];
+            if stream.read_exact(&mut buf).is_ok() {
+                let _ = stream.write_all(&buf);
+                let _ = stream.flush();
+            }
+        });
+        port
+    }
+
+    fn echo(trust: Trust, cert: &TestCert) -> io::Result<[u8; 5]> {
+        let port = echo_server(cert);
+        let stream = TcpStream::connect(("127.0.0.1", port))?;
+        let (mut reader, mut writer) = connect(stream, "127.0.0.1", client_config(&trust)?)?;
+        writer.write_all(b"hello")?;
+        writer.flush()?;
+        let mut buf = [0; 5];
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn ca_test() {
+        let cert = self_signed("client_ca");
+        let buf = echo(Trust::Ca(cert.dir.join("cert.pem")), &cert).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn pinned_test() {
+        let cert = self_signed("client_pinned");
+        let buf = echo(Trust::Pinned(cert.dir.join("cert.pem")), &cert).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Another certificate is refused even if it is for the same name
+        let other = self_signed("client_other");
+        assert!(echo(Trust::Pinned(cert.dir.join("cert.pem")), &other).is_err());
+    }
+}
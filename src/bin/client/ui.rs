@@ -3,46 +3,129 @@ use std::env::{self};
 use std::error::Error;
 use std::io::ErrorKind;
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use async_chat::message::ParsedMsg;
+use async_chat::logging::LogConfig;
+use async_chat::message::{AuthKind, AuthStatus, InfoKind, ParsedMsg};
 use cursive::event::{Event, EventResult};
+use cursive::theme::{BaseColor, Color, Effect};
+use cursive::utils::markup::StyledString;
 use cursive::view::ViewWrapper;
 use cursive::views::Dialog;
 use cursive::{
     event::Key,
     theme::Theme,
     view::{Nameable, Resizable, ScrollStrategy, Scrollable},
-    views::{DummyView, LinearLayout, TextArea, TextView},
+    views::{DummyView, EditView, LinearLayout, TextArea, TextView},
 };
 use cursive::{Cursive, CursiveRunnable, CursiveRunner, View};
 
-use crate::connection::{Connection, Reader, Writer};
+use crate::connection::{Connection, Heartbeat, Reader, Writer};
+use crate::sequence::{Delivery, Sequence};
+use crate::tls::{self, Trust};
+use rustls::ClientConfig;
+use tracing::{info, info_span, warn};
 
 const CHAT_NAME: &str = "chat_view";
 const INPUT_NAME: &str = "input_view";
+const STATUS_NAME: &str = "status_view";
 const DIALOG_NAME: &str = "conn_err_dialog";
+const LOGIN_NAME: &str = "login_dialog";
+const WAITING_NAME: &str = "waiting_dialog";
+const LOGIN_STATUS_NAME: &str = "login_status_view";
+const USER_NAME: &str = "user_view";
+const PASSWORD_NAME: &str = "password_view";
+const CREDENTIALS_WIDTH: usize = 32;
 const MAX_DURATION_DISCONNECTED: Duration = Duration::from_secs(5);
 const MAX_CHAT_LEN_CHARS: usize = 1_024 * 50;
 const INFO_PREFIX: &str = "INFO";
+const HISTORY_BEGIN: &str = "----- history -----\n\n";
+const HISTORY_END: &str = "----- end of history -----\n\n";
+const USAGE: &str = "Provide server ip and port to connect, optionally followed by --tls-ca <pem> or --tls-pin <pem>, --heartbeat-secs <secs>, and --log-file <path> [--log-filter <filter>] [--log-format text|json]";
 
 type Runner = CursiveRunner<CursiveRunnable>;
 
+/// The command line: `<server-ip> <port>` followed by the flags.
+#[derive(Debug)]
+struct Args {
+    ip: String,
+    port: u16,
+    trust: Option<Trust>,
+    heartbeat: Heartbeat,
+    // The screen belongs to the ui, so the client logs only to a file
+    log: Option<LogConfig>,
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let [ip, port, flags @ ..] = args else {
+        return Err(USAGE.to_string());
+    };
+    let port = port.parse().map_err(|_| format!("Invalid port {}", port))?;
+    let mut trust = None;
+    let mut heartbeat = Heartbeat::default();
+    let mut log = LogConfig::default();
+    for flag in flags.chunks(2) {
+        match flag {
+            [flag, path] if flag == "--tls-ca" => trust = Some(Trust::Ca(path.into())),
+            [flag, path] if flag == "--tls-pin" => trust = Some(Trust::Pinned(path.into())),
+            [flag, secs] if flag == "--heartbeat-secs" => {
+                heartbeat.interval = match secs.parse() {
+                    Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                    _ => return Err(format!("Invalid heartbeat interval {}", secs)),
+                };
+            }
+            [flag, path] if flag == "--log-file" => log.file = Some(path.into()),
+            [flag, filter] if flag == "--log-filter" => log.filter.clone_from(filter),
+            [flag, format] if flag == "--log-format" => log.format = format.parse()?,
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    Ok(Args {
+        ip: ip.clone(),
+        port,
+        trust,
+        heartbeat,
+        log: log.file.is_some().then_some(log),
+    })
+}
+
 pub fn run() {
-    let mut args = env::args().skip(1);
-    if args.len() != 2 {
-        eprintln!("Provide server ip and port to connect");
-        return;
+    let args = match parse_args(&env::args().skip(1).collect::<Vec<_>>()) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    if let Some(log) = &args.log {
+        if let Err(e) = log.init() {
+            eprintln!("{}", e);
+            return;
+        }
     }
-    let ip = args.next().unwrap();
-    let port = args.next().as_ref().and_then(|p| p.parse().ok()).unwrap();
+    let Args {
+        ip,
+        port,
+        trust,
+        heartbeat,
+        ..
+    } = args;
+    let _span = info_span!("client", server = %format!("{}:{}", ip, port)).entered();
+    let tls = match trust.as_ref().map(tls::client_config).transpose() {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("Cannot load TLS certificates: {}", e);
+            return;
+        }
+    };
 
     let mut siv = cursive::default();
     siv.set_theme(Theme::terminal_default());
     let mut siv = siv.into_runner();
     siv.add_global_callback(Key::Esc, Cursive::quit);
 
-    let mut app = App::new(&mut siv, ip, port);
+    let mut app = App::new(&mut siv, ip, port, tls, heartbeat);
 
     siv.refresh();
     while siv.is_running() {
@@ -51,48 +134,80 @@ pub fn run() {
     }
 }
 
+#[derive(Debug, Clone)]
+struct AuthRequest {
+    kind: AuthKind,
+    user: String,
+    password: String,
+}
+
 struct App {
     state: State,
     ip: String,
     port: u16,
+    tls: Option<Arc<ClientConfig>>,
+    heartbeat: Heartbeat,
     retry_requested: Rc<RefCell<bool>>,
     retries: usize,
     time_since_disconnection: Instant,
+    // Longer when the server asked to wait before trying again
+    retry_after: Duration,
+    // The connection waiting for the server to accept the credentials
+    pending: Option<(Writer, Reader)>,
+    auth_requested: Rc<RefCell<Option<AuthRequest>>>,
+    // Kept to log in again after a reconnection
+    credentials: Option<AuthRequest>,
+    chat_text: Option<String>,
+    input_text: Option<String>,
+    // Kept to tell the history already shown after a reconnection
+    sequence: Sequence,
 }
 
 impl App {
-    fn new(siv: &mut Runner, ip: String, port: u16) -> Self {
-        match Connection::new(&ip, port) {
+    fn new(
+        siv: &mut Runner,
+        ip: String,
+        port: u16,
+        tls: Option<Arc<ClientConfig>>,
+        heartbeat: Heartbeat,
+    ) -> Self {
+        let mut app = Self {
+            state: State::NotConnected,
+            ip,
+            port,
+            tls,
+            heartbeat,
+            retry_requested: Rc::new(RefCell::new(false)),
+            retries: 1,
+            time_since_disconnection: Instant::now(),
+            retry_after: MAX_DURATION_DISCONNECTED,
+            pending: None,
+            auth_requested: Rc::new(RefCell::new(None)),
+            credentials: None,
+            chat_text: None,
+            input_text: None,
+            sequence: Sequence::default(),
+        };
+        match Connection::new(&app.ip, app.port, app.tls.as_ref(), app.heartbeat) {
             Ok(connection) => {
-                let app = Self {
-                    state: State::Connected,
-                    ip,
-                    port,
-                    retry_requested: Rc::new(RefCell::new(false)),
-                    retries: 0,
-                    time_since_disconnection: Instant::now(),
-                };
-                Self::chat_layer(siv, connection, None, None);
-                app
+                info!("connected");
+                app.state = State::Authenticating;
+                app.retries = 0;
+                app.pending = Some(connection.split());
             }
-            Err(_) => {
-                let mut app = Self {
-                    state: State::NotConnected,
-                    ip,
-                    port,
-                    retry_requested: Rc::new(RefCell::new(false)),
-                    retries: 1,
-                    time_since_disconnection: Instant::now(),
-                };
-                app.dialog_layer(siv);
-                app
+            Err(e) => {
+                warn!(error = %e, "cannot connect");
+                app.dialog_layer(siv, unable_to_connect_text(app.retries));
             }
         }
+        app
     }
 
     fn run(&mut self, siv: &mut Runner) {
         match self.state {
+            State::Authenticating => self.authenticate(siv),
             State::Connected => {
+                Self::show_latency(siv);
                 if let Some(action) = siv
                     .call_on_name(CHAT_NAME, |chat: &mut Chat| chat.check_messages())
                     .flatten()
@@ -104,7 +219,7 @@ impl App {
                         MessageAction::LostConnection => {
                             self.state = State::NotConnected;
                             self.time_since_disconnection = Instant::now();
-                            self.dialog_layer(siv);
+                            self.dialog_layer(siv, unable_to_connect_text(self.retries));
                             siv.refresh();
                         }
                     };
@@ -112,35 +227,46 @@ impl App {
             }
             State::NotConnected => {
                 if !(*self.retry_requested).borrow().to_owned()
-                    && self.time_since_disconnection.elapsed() < MAX_DURATION_DISCONNECTED
+                    && self.time_since_disconnection.elapsed() < self.retry_after
                 {
                     return;
                 }
                 *self.retry_requested.borrow_mut() = false;
                 self.time_since_disconnection = Instant::now();
-                match Connection::new(&self.ip, self.port) {
+                self.retry_after = MAX_DURATION_DISCONNECTED;
+                match Connection::new(&self.ip, self.port, self.tls.as_ref(), self.heartbeat) {
                     Ok(connection) => {
-                        self.state = State::Connected;
+                        info!(retries = self.retries, "reconnected");
+                        self.state = State::Authenticating;
                         self.retries = 0;
 
-                        let input_text = siv
+                        if let Some(input_text) = siv
                             .call_on_name(INPUT_NAME, |input: &mut Input| {
                                 input.with_view(|text| text.get_content().to_owned())
                             })
-                            .flatten();
+                            .flatten()
+                        {
+                            self.input_text = Some(input_text);
+                        }
 
-                        let chat_text = siv
+                        if let Some((chat_text, sequence)) = siv
                             .call_on_name(CHAT_NAME, |chat: &mut Chat| {
                                 chat.with_view_mut(|text| text.get_content().source().to_owned())
+                                    .map(|text| (text, chat.sequence.clone()))
                             })
-                            .flatten();
+                            .flatten()
+                        {
+                            self.chat_text = Some(chat_text);
+                            self.sequence = sequence;
+                        }
 
                         siv.pop_layer();
                         siv.pop_layer();
-                        Self::chat_layer(siv, connection, chat_text, input_text);
+                        self.pending = Some(connection.split());
                     }
-                    Err(_) => {
+                    Err(e) => {
                         self.retries = self.retries.wrapping_add(1);
+                        warn!(error = %e, retries = self.retries, "cannot reconnect");
                         let retries = self.retries;
                         siv.call_on_name(DIALOG_NAME, move |view: &mut Dialog| {
                             view.set_content(TextView::new(unable_to_connect_text(retries)));
@@ -152,23 +278,156 @@ impl App {
         };
     }
 
+    fn authenticate(&mut self, siv: &mut Runner) {
+        let Some((writer, reader)) = self.pending.as_mut() else {
+            return;
+        };
+        if let Some(request) = self.auth_requested.borrow_mut().take() {
+            let _ = writer.send_auth(request.kind, &request.user, &request.password);
+            self.credentials = Some(AuthRequest {
+                kind: AuthKind::Login,
+                ..request
+            });
+        }
+        let Some(msg) = reader.try_read_msg() else {
+            return;
+        };
+        if !matches!(msg, Ok(ParsedMsg::Info(..)))
+            && siv.find_name::<Dialog>(WAITING_NAME).is_some()
+        {
+            siv.pop_layer();
+        }
+        match msg {
+            Ok(ParsedMsg::AuthResponse(AuthStatus::Ok, text)) => {
+                let Some((writer, reader)) = self.pending.take() else {
+                    return;
+                };
+                info!("logged in");
+                if siv.find_name::<Dialog>(LOGIN_NAME).is_some() {
+                    siv.pop_layer();
+                }
+                self.state = State::Connected;
+                let mut chat_text = self.chat_text.take().unwrap_or_default();
+                chat_text.push_str(&format!("{}.Auth: {}\n\n", INFO_PREFIX, text));
+                Self::chat_layer(
+                    siv,
+                    writer,
+                    reader,
+                    Some(chat_text),
+                    self.input_text.take(),
+                    self.sequence.clone(),
+                );
+            }
+            Ok(ParsedMsg::AuthResponse(AuthStatus::Required, text)) => match &self.credentials {
+                Some(credentials) => {
+                    let _ = writer.send_auth(
+                        credentials.kind,
+                        &credentials.user,
+                        &credentials.password,
+                    );
+                }
+                None => self.login_layer(siv, &text),
+            },
+            Ok(ParsedMsg::AuthResponse(status, text)) => {
+                info!(?status, "log in refused");
+                self.credentials = None;
+                self.login_layer(siv, &text);
+            }
+            Ok(ParsedMsg::Info(InfoKind::ServerFull, text)) => {
+                info!("waiting for a free slot");
+                siv.add_layer(
+                    Dialog::text(text)
+                        .button("Quit", Cursive::quit)
+                        .with_name(WAITING_NAME),
+                );
+            }
+            Ok(ParsedMsg::Refused {
+                retry_after_secs,
+                text,
+            }) => {
+                info!(retry_after_secs, "connection refused");
+                self.pending = None;
+                self.state = State::NotConnected;
+                self.time_since_disconnection = Instant::now();
+                self.retry_after = Duration::from_secs(retry_after_secs.into());
+                self.dialog_layer(
+                    siv,
+                    format!("{}. Trying again in {}s", text, retry_after_secs),
+                );
+            }
+            Ok(_) => (),
+            Err(e) => {
+                warn!(error = %e, "lost connection while logging in");
+                self.pending = None;
+                self.state = State::NotConnected;
+                self.time_since_disconnection = Instant::now();
+                self.dialog_layer(siv, unable_to_connect_text(self.retries));
+            }
+        }
+        siv.refresh();
+    }
+
+    fn login_layer(&self, siv: &mut Runner, status: &str) {
+        let status = status.to_owned();
+        if siv
+            .call_on_name(LOGIN_STATUS_NAME, |view: &mut TextView| {
+                view.set_content(status.clone());
+            })
+            .is_some()
+        {
+            return;
+        }
+        let login_requested = Rc::clone(&self.auth_requested);
+        let register_requested = Rc::clone(&self.auth_requested);
+        siv.add_layer(
+            Dialog::around(
+                LinearLayout::vertical()
+                    .child(TextView::new(status).with_name(LOGIN_STATUS_NAME))
+                    .child(DummyView)
+                    .child(TextView::new("User"))
+                    .child(
+                        EditView::new()
+                            .with_name(USER_NAME)
+                            .fixed_width(CREDENTIALS_WIDTH),
+                    )
+                    .child(TextView::new("Password"))
+                    .child(
+                        EditView::new()
+                            .secret()
+                            .with_name(PASSWORD_NAME)
+                            .fixed_width(CREDENTIALS_WIDTH),
+                    ),
+            )
+            .title("Login")
+            .button("Login", move |siv| {
+                request_auth(siv, &login_requested, AuthKind::Login);
+            })
+            .button("Register", move |siv| {
+                request_auth(siv, &register_requested, AuthKind::Register);
+            })
+            .button("Quit", Cursive::quit)
+            .with_name(LOGIN_NAME),
+        );
+    }
+
     fn chat_layer(
         siv: &mut Cursive,
-        connection: Connection,
+        writer: Writer,
+        reader: Reader,
         chat_text: Option<String>,
         input_text: Option<String>,
+        sequence: Sequence,
     ) {
-        let (writer, reader) = connection.split();
         let screen = LinearLayout::vertical()
             .child(
-                Chat::new(reader, chat_text)
+                Chat::new(reader, chat_text, sequence)
                     .with_name(CHAT_NAME)
                     .full_width()
                     .full_height()
                     .scrollable()
                     .scroll_strategy(ScrollStrategy::StickToBottom),
             )
-            .child(DummyView)
+            .child(TextView::new("").style(Effect::Dim).with_name(STATUS_NAME))
             .child(
                 Input::new(writer, input_text)
                     .with_name(INPUT_NAME)
@@ -179,26 +438,70 @@ impl App {
         siv.add_fullscreen_layer(screen);
     }
 
-    fn dialog_layer(&mut self, siv: &mut Runner) {
+    /// Updates the status line with the latency measured by the heartbeats.
+    fn show_latency(siv: &mut Runner) {
+        let Some(latency) = siv
+            .call_on_name(CHAT_NAME, |chat: &mut Chat| chat.reader.latency())
+            .flatten()
+        else {
+            return;
+        };
+        let status = format!("latency: {} ms", latency.as_millis());
+        let changed = siv
+            .call_on_name(STATUS_NAME, |view: &mut TextView| {
+                let changed = view.get_content().source() != status;
+                if changed {
+                    view.set_content(status);
+                }
+                changed
+            })
+            .unwrap_or(false);
+        if changed {
+            siv.refresh();
+        }
+    }
+
+    fn dialog_layer(&mut self, siv: &mut Runner, text: String) {
         let retry_requested = Rc::clone(&self.retry_requested);
         siv.add_layer(
-            Dialog::text(unable_to_connect_text(self.retries))
+            Dialog::text(text)
                 .button("Try again", move |_| {
                     *retry_requested.borrow_mut() = true;
                 })
-                .button("Quit", |s| Cursive::quit(s))
+                .button("Quit", Cursive::quit)
                 .with_name(DIALOG_NAME),
         );
     }
 }
 
+fn request_auth(siv: &mut Cursive, requested: &RefCell<Option<AuthRequest>>, kind: AuthKind) {
+    let content = |siv: &mut Cursive, name| {
+        siv.call_on_name(name, |view: &mut EditView| view.get_content().to_string())
+            .unwrap_or_default()
+    };
+    let user = content(siv, USER_NAME);
+    let password = content(siv, PASSWORD_NAME);
+    *requested.borrow_mut() = Some(AuthRequest {
+        kind,
+        user,
+        password,
+    });
+}
+
 fn unable_to_connect_text(retries: usize) -> String {
     format!("Unable to connect to server. Retry no. {}", retries)
 }
 
+/// Formats the seconds since the unix epoch as `HH:MM` UTC.
+fn utc_time(timestamp: u64) -> String {
+    let minutes = timestamp / 60;
+    format!("{:02}:{:02}", (minutes / 60) % 24, minutes % 60)
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum State {
     NotConnected,
+    Authenticating,
     Connected,
 }
 
@@ -211,14 +514,18 @@ enum MessageAction {
 struct Chat {
     reader: Reader,
     text_view: TextView,
+    in_history: bool,
+    sequence: Sequence,
 }
 
 impl Chat {
     #[must_use]
-    fn new(reader: Reader, text: Option<String>) -> Self {
+    fn new(reader: Reader, text: Option<String>, sequence: Sequence) -> Self {
         Self {
             reader,
             text_view: TextView::new(text.unwrap_or("".to_string())),
+            in_history: false,
+            sequence,
         }
     }
 
@@ -234,16 +541,50 @@ impl Chat {
     #[must_use]
     fn check_messages(&mut self) -> Option<MessageAction> {
         if let Some(msg) = self.reader.try_read_msg() {
+            let delivery = match &msg {
+                Ok(ParsedMsg::History {
+                    seq,
+                    prev_seq,
+                    room,
+                    ..
+                }) => self.sequence.replayed(room, *seq, *prev_seq),
+                _ => Delivery::Show,
+            };
+            if delivery == Delivery::Duplicate {
+                return None;
+            }
+            let is_history = matches!(msg, Ok(ParsedMsg::History { .. }));
+            if self.in_history != is_history {
+                self.in_history = is_history;
+                self.text_view.append(if is_history {
+                    HISTORY_BEGIN
+                } else {
+                    HISTORY_END
+                });
+            }
             match msg {
-                Ok(ParsedMsg::Command(_) | ParsedMsg::Info(_)) => {
+                Ok(
+                    ParsedMsg::Command(_)
+                    | ParsedMsg::BadCommand(_)
+                    | ParsedMsg::AuthRequest { .. },
+                ) => {
                     panic!("Invalid message type from server {:#?}", msg)
                 }
+                Ok(ParsedMsg::Info(info_kind, text)) => {
+                    self.text_view
+                        .append(format!("{}.{:?}: {}\n\n", INFO_PREFIX, info_kind, text));
+                    self.check_text_len();
+                    Some(MessageAction::Refresh)
+                }
+                Ok(ParsedMsg::AuthResponse(status, text)) => {
+                    self.text_view
+                        .append(format!("{}.{:?}: {}\n\n", INFO_PREFIX, status, text));
+                    self.check_text_len();
+                    Some(MessageAction::Refresh)
+                }
                 Ok(ParsedMsg::UserCount(n)) => {
-                    self.text_view.append(format!(
-                        "{}.User-Count: {}\n\n",
-                        INFO_PREFIX,
-                        n.to_string()
-                    ));
+                    self.text_view
+                        .append(format!("{}.User-Count: {}\n\n", INFO_PREFIX, n));
                     self.check_text_len();
                     Some(MessageAction::Refresh)
                 }
@@ -254,13 +595,71 @@ impl Chat {
                     self.check_text_len();
                     Some(MessageAction::Refresh)
                 }
+                Ok(ParsedMsg::RoomText { seq, room, text }) => {
+                    if let Some(seq) = seq {
+                        self.sequence.live(&room, seq);
+                    }
+                    self.text_view.append(format!("[{}] {}\n\n", room, text));
+                    self.check_text_len();
+                    Some(MessageAction::Refresh)
+                }
+                Ok(ParsedMsg::History {
+                    timestamp,
+                    room,
+                    sender,
+                    text,
+                    ..
+                }) => {
+                    if delivery == Delivery::AfterGap {
+                        self.text_view.append(format!(
+                            "{}.History: some messages of [{}] sent while away are no longer in the history\n\n",
+                            INFO_PREFIX, room
+                        ));
+                    }
+                    self.text_view.append(StyledString::styled(
+                        format!(
+                            "[{}] {} {}: {}\n\n",
+                            room,
+                            utc_time(timestamp),
+                            sender,
+                            text
+                        ),
+                        Effect::Dim,
+                    ));
+                    self.check_text_len();
+                    Some(MessageAction::Refresh)
+                }
+                Ok(ParsedMsg::Whisper { from, to, text }) => {
+                    self.text_view.append(StyledString::styled(
+                        format!("(whisper) {} -> {}: {}\n\n", from, to, text),
+                        Color::Dark(BaseColor::Magenta),
+                    ));
+                    self.check_text_len();
+                    Some(MessageAction::Refresh)
+                }
+                Ok(ParsedMsg::RoomList(rooms)) => {
+                    self.text_view.append(format!(
+                        "{}.Rooms:\n{}\n\n",
+                        INFO_PREFIX,
+                        rooms.join("\n")
+                    ));
+                    self.check_text_len();
+                    Some(MessageAction::Refresh)
+                }
                 Ok(ParsedMsg::Text(text)) => {
                     self.text_view.append(text);
                     self.text_view.append("\n\n");
                     self.check_text_len();
                     Some(MessageAction::Refresh)
                 }
-                Err(_) => Some(MessageAction::LostConnection),
+                // Sent only before logging in
+                Ok(ParsedMsg::Refused { .. }) => Some(MessageAction::LostConnection),
+                // Answered by the connection
+                Ok(ParsedMsg::Ping(_) | ParsedMsg::Pong(_)) => None,
+                Err(e) => {
+                    warn!(error = %e, "lost connection");
+                    Some(MessageAction::LostConnection)
+                }
             }
         } else {
             None
@@ -313,6 +712,7 @@ impl ViewWrapper for Input {
             Event::CtrlChar('s') => {
                 let content = self.text_area.get_content();
                 if let Err(e) = self.writer.try_send_msg(content) {
+                    warn!(error = %e, "cannot send message");
                     if e.kind() == ErrorKind::Other {
                         let err_string =
                             e.source().map(|e| e.to_string()).unwrap_or("".to_string());
@@ -341,3 +741,49 @@ impl ViewWrapper for Input {
         Some(f(&self.text_area))
     }
 }
+
+#[cfg(test)]
+mod ui_tests {
+    use super::*;
+    use async_chat::logging::LogFormat;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn args_test() {
+        let args = parse(&["127.0.0.1", "60000"]).unwrap();
+        assert_eq!((args.ip.as_str(), args.port), ("127.0.0.1", 60_000));
+        assert!(args.trust.is_none() && args.log.is_none());
+
+        let args = parse(&[
+            "localhost",
+            "7000",
+            "--log-format",
+            "json",
+            "--tls-pin",
+            "cert.pem",
+            "--log-file",
+            "client.log",
+        ])
+        .unwrap();
+        assert!(matches!(args.trust, Some(Trust::Pinned(_))));
+        let log = args.log.unwrap();
+        assert_eq!(log.format, LogFormat::Json);
+        assert_eq!(log.filter, "info");
+
+        // Without a file there is nowhere to log to
+        assert!(parse(&["localhost", "7000", "--log-filter", "debug"])
+            .unwrap()
+            .log
+            .is_none());
+        assert!(parse(&["localhost"]).is_err());
+        assert!(parse(&["localhost", "port"]).is_err());
+        assert!(parse(&["localhost", "7000", "--log-format", "xml"]).is_err());
+        assert!(parse(&["localhost", "7000", "--tls-ca"]).is_err());
+        let args = parse(&["localhost", "7000", "--heartbeat-secs", "5"]).unwrap();
+        assert_eq!(args.heartbeat.interval, Duration::from_secs(5));
+        assert!(parse(&["localhost", "7000", "--heartbeat-secs", "0"]).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// An address range like `10.0.0.0/8`. A plain address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    #[must_use]
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask_v4(ip.to_bits(), self.prefix) == net.to_bits()
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask_v6(ip.to_bits(), self.prefix) == net.to_bits()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid address '{}'", addr))?
+            .to_canonical();
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid prefix length '{}'", prefix))?,
+            None => max_prefix,
+        };
+        Ok(Self {
+            addr: subnet(addr, prefix),
+            prefix,
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+fn mask_v4(bits: u32, prefix: u8) -> u32 {
+    bits & u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0)
+}
+
+fn mask_v6(bits: u128, prefix: u8) -> u128 {
+    bits & u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0)
+}
+
+/// The first address of the subnet of `ip` with the given prefix length.
+fn subnet(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => IpAddr::V4(mask_v4(ip.to_bits(), prefix.min(32)).into()),
+        IpAddr::V6(ip) => IpAddr::V6(mask_v6(ip.to_bits(), prefix.min(128)).into()),
+    }
+}
+
+/// Which addresses may connect. Each line of the file is either
+/// `allow <cidr>` or `deny <cidr>`, and `#` starts a comment. A denied address
+/// is always refused; when there is some `allow` rule, only the addresses
+/// matching one of them are let in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut list = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (rule, cidr) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format!("line {}: expected '<allow|deny> <cidr>'", i + 1))?;
+            let cidr = cidr
+                .trim()
+                .parse()
+                .map_err(|e| format!("line {}: {}", i + 1, e))?;
+            match rule {
+                "allow" => list.allow.push(cidr),
+                "deny" => list.deny.push(cidr),
+                _ => return Err(format!("line {}: unknown rule '{}'", i + 1, rule)),
+            }
+        }
+        Ok(list)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    #[must_use]
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessConfig {
+    /// File with the allow/deny rules. Everybody is allowed without it
+    pub list: Option<PathBuf>,
+    /// Simultaneous connections from the same subnet
+    pub max_conns_per_subnet: usize,
+    /// The prefix lengths grouping the addresses in subnets. 32 and 128 count single addresses
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        Self {
+            list: None,
+            max_conns_per_subnet: 10,
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    Denied,
+    Banned,
+    TooManyConnections,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Denied => write!(f, "address denied"),
+            Self::Banned => write!(f, "address banned"),
+            Self::TooManyConnections => write!(f, "too many connections from the same subnet"),
+        }
+    }
+}
+
+impl GateState {
+    fn subnet_of(&self, ip: IpAddr) -> IpAddr {
+        let ip = ip.to_canonical();
+        let prefix = if ip.is_ipv4() {
+            self.config.ipv4_prefix
+        } else {
+            self.config.ipv6_prefix
+        };
+        subnet(ip, prefix)
+    }
+}
+
+struct GateState {
+    config: AccessConfig,
+    list: AccessList,
+    conns: HashMap<IpAddr, usize>,
+    /// Banned addresses and the end of their ban
+    bans: HashMap<IpAddr, Instant>,
+}
+
+/// Decides which incoming connections are let in, before they reach the server.
+#[derive(Clone)]
+pub struct Gate {
+    state: Arc<Mutex<GateState>>,
+}
+
+impl Gate {
+    pub fn new(config: AccessConfig) -> io::Result<Self> {
+        let list = match &config.list {
+            Some(path) => AccessList::load(path)?,
+            None => AccessList::default(),
+        };
+        Ok(Self {
+            state: Arc::new(Mutex::new(GateState {
+                config,
+                list,
+                conns: HashMap::new(),
+                bans: HashMap::new(),
+            })),
+        })
+    }
+
+    /// Reads the access list file again. The current rules are kept on error.
+    pub fn reload(&self) -> io::Result<()> {
+        let path = self
+            .state
+            .lock()
+            .expect("Gate lock is poisoned")
+            .config
+            .list
+            .clone();
+        let list = match path {
+            Some(path) => AccessList::load(&path)?,
+            None => return Ok(()),
+        };
+        self.state.lock().expect("Gate lock is poisoned").list = list;
+        Ok(())
+    }
+
+    /// Replaces the settings, reading the access list again. The current ones are kept on error.
+    /// The connections already counted stay with their subnet.
+    pub fn configure(&self, config: AccessConfig) -> io::Result<()> {
+        let list = match &config.list {
+            Some(path) => AccessList::load(path)?,
+            None => AccessList::default(),
+        };
+        let mut state = self.state.lock().expect("Gate lock is poisoned");
+        state.config = config;
+        state.list = list;
+        Ok(())
+    }
+
+    /// Refuses `ip` for `duration`, on top of the access list.
+    pub fn ban(&self, ip: IpAddr, duration: Duration) {
+        let mut state = self.state.lock().expect("Gate lock is poisoned");
+        let _ = state
+            .bans
+            .insert(ip.to_canonical(), Instant::now() + duration);
+    }
+
+    /// Lets `ip` in, or tells why not. The connection is counted until the ticket is dropped.
+    pub fn admit(&self, ip: IpAddr) -> Result<Ticket, Refusal> {
+        let mut state = self.state.lock().expect("Gate lock is poisoned");
+        let subnet = state.subnet_of(ip);
+        if !state.list.is_allowed(ip) {
+            return Err(Refusal::Denied);
+        }
+        let now = Instant::now();
+        state.bans.retain(|_, until| *until > now);
+        if state.bans.contains_key(&ip.to_canonical()) {
+            return Err(Refusal::Banned);
+        }
+        let max_conns = state.config.max_conns_per_subnet;
+        let conns = state.conns.entry(subnet).or_default();
+        if *conns >= max_conns {
+            return Err(Refusal::TooManyConnections);
+        }
+        *conns += 1;
+        Ok(Ticket {
+            state: Arc::clone(&self.state),
+            subnet,
+        })
+    }
+}
+
+/// An admitted connection.
+pub struct Ticket {
+    state: Arc<Mutex<GateState>>,
+    subnet: IpAddr,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().expect("Gate lock is poisoned");
+        if let Some(conns) = state.conns.get_mut(&self.subnet) {
+            *conns -= 1;
+            if *conns == 0 {
+                let _ = state.conns.remove(&self.subnet);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod access_tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_test() {
+        let net = "10.1.2.3/8".parse::<Cidr>().unwrap();
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert!(net.contains(ip("10.200.0.1")));
+        assert!(net.contains(ip("::ffff:10.0.0.1")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        assert!(!net.contains(ip("::1")));
+
+        let host = "192.168.0.7".parse::<Cidr>().unwrap();
+        assert!(host.contains(ip("192.168.0.7")));
+        assert!(!host.contains(ip("192.168.0.8")));
+
+        let net = "fd00::/8".parse::<Cidr>().unwrap();
+        assert!(net.contains(ip("fd12::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("1.2.3.4")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn access_list_test() {
+        let list =
+            AccessList::parse("# office\nallow 10.0.0.0/8\ndeny 10.0.0.66 # intern\n").unwrap();
+        assert!(list.is_allowed(ip("10.1.1.1")));
+        assert!(!list.is_allowed(ip("10.0.0.66")));
+        assert!(!list.is_allowed(ip("8.8.8.8")));
+
+        let list = AccessList::parse("deny 8.8.8.8").unwrap();
+        assert!(list.is_allowed(ip("8.8.4.4")));
+        assert!(!list.is_allowed(ip("8.8.8.8")));
+
+        assert_eq!(
+            AccessList::parse("allow 10.0.0.1\nblock 8.8.8.8"),
+            Err("line 2: unknown rule 'block'".to_string())
+        );
+    }
+
+    #[test]
+    fn gate_test() {
+        let path = std::env::temp_dir().join(format!("async_chat_access_{}", std::process::id()));
+        std::fs::write(&path, "deny 10.0.0.1\n").unwrap();
+        let gate = Gate::new(AccessConfig {
+            list: Some(path.clone()),
+            max_conns_per_subnet: 2,
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+        })
+        .unwrap();
+
+        assert_eq!(gate.admit(ip("10.0.0.1")).err(), Some(Refusal::Denied));
+        let first = gate.admit(ip("10.0.0.2")).unwrap();
+        let _second = gate.admit(ip("10.0.0.3")).unwrap();
+        assert_eq!(
+            gate.admit(ip("10.0.0.4")).err(),
+            Some(Refusal::TooManyConnections)
+        );
+        assert!(gate.admit(ip("10.0.1.1")).is_ok());
+        drop(first);
+        assert!(gate.admit(ip("10.0.0.4")).is_ok());
+
+        gate.ban(ip("10.0.1.2"), Duration::from_millis(50));
+        assert_eq!(gate.admit(ip("10.0.1.2")).err(), Some(Refusal::Banned));
+        assert!(gate.admit(ip("10.0.1.3")).is_ok());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(gate.admit(ip("10.0.1.2")).is_ok());
+
+        std::fs::write(&path, "deny 10.0.0.0/24\n").unwrap();
+        gate.reload().unwrap();
+        assert_eq!(gate.admit(ip("10.0.0.5")).err(), Some(Refusal::Denied));
+
+        // A broken file keeps the previous rules
+        std::fs::write(&path, "deny nothing\n").unwrap();
+        assert!(gate.reload().is_err());
+        assert_eq!(gate.admit(ip("10.0.0.5")).err(), Some(Refusal::Denied));
+
+        gate.configure(AccessConfig {
+            max_conns_per_subnet: 1,
+            ..AccessConfig::default()
+        })
+        .unwrap();
+        let _first = gate.admit(ip("10.0.0.5")).unwrap();
+        assert_eq!(
+            gate.admit(ip("10.0.0.5")).err(),
+            Some(Refusal::TooManyConnections)
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
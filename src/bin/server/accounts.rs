@@ -0,0 +1,183 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use async_chat::message::{AuthStatus, MAX_NICK_LEN};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+};
+use tracing::warn;
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+pub const MAX_PASSWORD_LEN: usize = 128;
+
+/// Local store of the user accounts. Every line of the file is
+/// `<user>:<argon2 PHC string>`, the latter containing the salt and the hash parameters.
+/// The user names are case insensitive, like the nicks.
+pub struct UserStore {
+    path: PathBuf,
+    users: HashMap<String, String>,
+    // Verified against for the unknown users, so that they take as long as the others
+    dummy_hash: String,
+}
+
+#[cfg(not(test))]
+fn hasher() -> Argon2<'static> {
+    Argon2::default()
+}
+
+// The default parameters are way too slow for unoptimized test builds
+#[cfg(test)]
+fn hasher() -> Argon2<'static> {
+    let params = argon2::Params::new(8, 1, 1, None).expect("Invalid argon2 parameters");
+    Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+}
+
+impl UserStore {
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let mut users = HashMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    match line.split_once(':') {
+                        Some((user, hash)) if PasswordHash::new(hash).is_ok() => {
+                            let _ = users.insert(account_name(user), hash.to_string());
+                        }
+                        _ => warn!(path = %path.display(), "ignoring malformed line in user store"),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        let dummy_hash = hasher()
+            .hash_password(b"", &SaltString::generate(&mut OsRng))
+            .map_err(|e| io::Error::other(e.to_string()))?
+            .to_string();
+        Ok(Self {
+            path,
+            users,
+            dummy_hash,
+        })
+    }
+
+    #[must_use]
+    pub fn contains(&self, user: &str) -> bool {
+        self.users.contains_key(&account_name(user))
+    }
+
+    /// The names of all the accounts, as given by [`account_name`].
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.users.keys().map(String::as_str)
+    }
+
+    #[must_use]
+    pub fn verify(&self, user: &str, password: &str) -> AuthStatus {
+        let (known, hash) = match self.users.get(&account_name(user)) {
+            Some(hash) => (true, hash),
+            None => (false, &self.dummy_hash),
+        };
+        let verified = PasswordHash::new(hash)
+            .map(|hash| hasher().verify_password(password.as_bytes(), &hash).is_ok())
+            .unwrap_or(false);
+        if known && verified {
+            AuthStatus::Ok
+        } else {
+            AuthStatus::InvalidCredentials
+        }
+    }
+
+    pub fn register(&mut self, user: &str, password: &str) -> io::Result<AuthStatus> {
+        if !is_valid_user(user) {
+            return Ok(AuthStatus::InvalidUser);
+        }
+        if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&password.len()) {
+            return Ok(AuthStatus::InvalidPassword);
+        }
+        if self.contains(user) {
+            return Ok(AuthStatus::UserExists);
+        }
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = hasher()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| io::Error::other(e.to_string()))?
+            .to_string();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let user = account_name(user);
+        file.write_all(format!("{}:{}\n", user, hash).as_bytes())?;
+        file.sync_data()?;
+        let _ = self.users.insert(user, hash);
+        Ok(AuthStatus::Ok)
+    }
+}
+
+/// The name an account is known by, whatever the case it is typed in.
+#[must_use]
+pub fn account_name(user: &str) -> String {
+    user.to_ascii_lowercase()
+}
+
+#[must_use]
+pub fn is_valid_user(user: &str) -> bool {
+    crate::is_valid_name(user, MAX_NICK_LEN)
+}
+
+#[cfg(test)]
+mod accounts_tests {
+    use super::*;
+
+    #[test]
+    fn register_and_verify() {
+        let path = std::env::temp_dir().join(format!("async_chat_users_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut store = UserStore::open(path.clone()).unwrap();
+        assert_eq!(
+            store.register("alice", "secret-pw").unwrap(),
+            AuthStatus::Ok
+        );
+        assert_eq!(
+            store.register("alice", "secret-pw").unwrap(),
+            AuthStatus::UserExists
+        );
+        assert_eq!(
+            store.register("Alice", "secret-pw").unwrap(),
+            AuthStatus::UserExists
+        );
+        assert_eq!(
+            store.register("a:b", "secret-pw").unwrap(),
+            AuthStatus::InvalidUser
+        );
+        assert_eq!(
+            store.register("bob", "short").unwrap(),
+            AuthStatus::InvalidPassword
+        );
+
+        // The hashes are persisted and salted
+        let store = UserStore::open(path.clone()).unwrap();
+        assert_eq!(store.verify("alice", "secret-pw"), AuthStatus::Ok);
+        assert_eq!(store.verify("ALICE", "secret-pw"), AuthStatus::Ok);
+        assert!(store.contains("Alice"));
+        assert_eq!(store.names().collect::<Vec<_>>(), ["alice"]);
+        assert_eq!(
+            store.verify("alice", "wrong-pw"),
+            AuthStatus::InvalidCredentials
+        );
+        assert_eq!(
+            store.verify("bob", "secret-pw"),
+            AuthStatus::InvalidCredentials
+        );
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("secret-pw"));
+        // Nothing is stored for the unknown users
+        assert_eq!(content.lines().count(), 1);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::{
+    commands::Role,
+    config::Config,
+    moderation::{format_duration, with_reason},
+    AuthState, Connections, Entry,
+};
+use async_chat::{
+    admin::{ConnectionInfo, Reply, Request, Response, RoomInfo, Stats},
+    message::{InfoKind, SerializedMessage},
+};
+use std::{
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    spawn,
+    sync::{mpsc::Sender, oneshot},
+};
+use tracing::{error, info, warn};
+
+/// A request of the admin socket, answered by the connections task.
+pub struct AdminRequest {
+    pub request: Request,
+    pub reply: oneshot::Sender<Response>,
+}
+
+/// Binds the admin socket, replacing a stale one. Only the owner of the server can use it.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+pub async fn serve(listener: UnixListener, requests: Sender<AdminRequest>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let requests = requests.clone();
+                spawn(async move {
+                    if let Err(e) = handle_client(stream, requests).await {
+                        warn!(error = %e, "admin connection failed");
+                    }
+                });
+            }
+            Err(e) => warn!(error = %e, "cannot accept admin connection"),
+        }
+    }
+}
+
+async fn handle_client(stream: UnixStream, requests: Sender<AdminRequest>) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                let (reply, replied) = oneshot::channel();
+                let shutting_down = || Response::Error("Server is shutting down".to_string());
+                if requests
+                    .send(AdminRequest { request, reply })
+                    .await
+                    .is_err()
+                {
+                    shutting_down()
+                } else {
+                    replied.await.unwrap_or_else(|_| shutting_down())
+                }
+            }
+            Err(e) => Response::Error(format!("Invalid request: {}", e)),
+        };
+        let mut json = serde_json::to_string(&response).map_err(io::Error::other)?;
+        json.push('\n');
+        writer.write_all(json.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn connection_info(sockaddr: SocketAddr, entry: &Entry) -> ConnectionInfo {
+    ConnectionInfo {
+        addr: sockaddr,
+        nick: entry.nick.clone(),
+        room: entry.room.clone(),
+        account: entry.account.clone(),
+        moderator: entry.role > Role::User,
+        authenticated: entry.auth == AuthState::Authenticated,
+        muted: entry.is_muted(),
+    }
+}
+
+impl Connections {
+    pub(crate) fn handle_admin(&mut self, request: AdminRequest) {
+        let AdminRequest { request, reply } = request;
+        let response = match request {
+            Request::Connections => {
+                let mut connections = self
+                    .entries
+                    .iter()
+                    .map(|(sockaddr, entry)| connection_info(*sockaddr, entry))
+                    .collect::<Vec<_>>();
+                connections.sort_by_key(|info| info.addr);
+                Response::Ok(Reply::Connections(connections))
+            }
+            Request::Rooms => Response::Ok(Reply::Rooms(
+                self.rooms
+                    .names()
+                    .into_iter()
+                    .map(|name| RoomInfo {
+                        name: name.to_string(),
+                        members: self.rooms.members(name).count(),
+                    })
+                    .collect(),
+            )),
+            Request::Kick { nick, reason } => match self.find_by_nick(&nick) {
+                Some((target, _)) => {
+                    self.admin_kick(target, &reason);
+                    Response::Ok(Reply::Done)
+                }
+                None => Response::Error(format!("User '{}' not found", nick)),
+            },
+            Request::Ban {
+                target,
+                secs,
+                reason,
+            } => {
+                if secs == 0 {
+                    Response::Error("The ban must last at least a second".to_string())
+                } else if self.admin_ban(&target, Duration::from_secs(secs), &reason) {
+                    Response::Ok(Reply::Done)
+                } else {
+                    Response::Error(format!("User '{}' not found", target))
+                }
+            }
+            Request::Announce { text } => {
+                self.announce(&text);
+                Response::Ok(Reply::Done)
+            }
+            Request::Limits => Response::Ok(Reply::Limits(self.config.limits())),
+            Request::SetLimits(update) => match self.config.with_limits(update) {
+                Ok(config) => {
+                    self.set_config(config);
+                    Response::Ok(Reply::Limits(self.config.limits()))
+                }
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Request::Stats => Response::Ok(Reply::Stats(Stats {
+                uptime_secs: self.started.elapsed().as_secs(),
+                connections: self.entries.len(),
+                rooms: self.rooms.names().len(),
+                total_connections: self.total_connections,
+                total_messages: self.total_messages,
+            })),
+            Request::Reload => match self.reload() {
+                Ok(restart_needed) => Response::Ok(Reply::Reloaded { restart_needed }),
+                Err(e) => Response::Error(e),
+            },
+        };
+        // The admin may have gone already
+        let _ = reply.send(response);
+    }
+
+    /// Loads the configuration again with the flags of the server.
+    /// Returns the changed settings that need a restart.
+    fn reload(&mut self) -> Result<Vec<String>, String> {
+        let Some(args) = &self.config_source.args else {
+            return Err("The server was not started from the command line".to_string());
+        };
+        let new = Config::load(args).map_err(|e| e.to_string())?;
+        let (config, restart_needed) = self.config.reloaded(new);
+        self.set_config(config);
+        info!(?restart_needed, "configuration reloaded");
+        Ok(restart_needed.into_iter().map(str::to_string).collect())
+    }
+
+    /// Applies a new configuration to the whole server.
+    pub(crate) fn set_config(&mut self, config: Config) {
+        if let Err(e) = self.gate.configure(config.access.clone()) {
+            error!(error = %e, "cannot load access list");
+        }
+        self.capacity
+            .configure(config.max_connections, config.wait_queue_len);
+        self.config = Arc::new(config);
+        let _ = self
+            .config_source
+            .sender
+            .send_replace(Arc::clone(&self.config));
+    }
+
+    fn announce(&self, text: &str) {
+        let msg = SerializedMessage::from_info(InfoKind::Announcement, text);
+        for entry in self
+            .entries
+            .values()
+            .filter(|entry| entry.auth == AuthState::Authenticated)
+        {
+            entry.outbox.send(msg.clone());
+        }
+        info!(text, "announced");
+    }
+
+    fn admin_kick(&self, target: SocketAddr, reason: &str) {
+        if let Some(entry) = self.entries.get(&target) {
+            info!(parent: &entry.span, reason, "kicked by the admin");
+        }
+        let text = with_reason("You were kicked by the admin".to_string(), reason);
+        self.disconnect(target, InfoKind::Kicked, text);
+    }
+
+    /// Returns false if `target` is neither an address nor a connected nick.
+    fn admin_ban(&mut self, target: &str, duration: Duration, reason: &str) -> bool {
+        let text = with_reason(
+            format!(
+                "You are banned for {} by the admin",
+                format_duration(duration)
+            ),
+            reason,
+        );
+        if let Ok(ip) = target.parse::<IpAddr>() {
+            info!(%ip, duration = %format_duration(duration), reason, "address banned by the admin");
+            self.ban_address(ip, duration, &text, |_| true);
+            return true;
+        }
+        let Some((target, entry)) = self.find_by_nick(target) else {
+            return false;
+        };
+        info!(parent: &entry.span, duration = %format_duration(duration), reason, "banned by the admin");
+        self.ban_user(target, duration, reason, text);
+        true
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::oneshot;
+
+struct CapacityState {
+    max_connections: usize,
+    wait_queue_len: usize,
+    /// The connections holding a slot
+    taken: usize,
+    /// The connections waiting for a slot, told in the order they came
+    waiting: VecDeque<oneshot::Sender<()>>,
+}
+
+impl CapacityState {
+    /// Hands the free slots to the waiting connections that are still there.
+    fn admit_waiting(&mut self) {
+        while self.taken < self.max_connections {
+            let Some(waiting) = self.waiting.pop_front() else {
+                return;
+            };
+            if waiting.send(()).is_ok() {
+                self.taken += 1;
+            }
+        }
+    }
+}
+
+/// The number of connections the server accepts, and the queue of those waiting for a slot.
+#[derive(Clone)]
+pub struct Capacity {
+    state: Arc<Mutex<CapacityState>>,
+}
+
+/// How a new connection gets in.
+pub enum Entrance {
+    Admitted(Slot),
+    /// The server is full, the connection gets the next free slot
+    Queued(Waiting),
+    /// The server and its queue are full
+    Refused,
+}
+
+impl Capacity {
+    #[must_use]
+    pub fn new(max_connections: usize, wait_queue_len: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(CapacityState {
+                max_connections,
+                wait_queue_len,
+                taken: 0,
+                waiting: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Applies new limits. Fewer slots take effect as the connections leave, the connections
+    /// already waiting keep their place.
+    pub fn configure(&self, max_connections: usize, wait_queue_len: usize) {
+        let mut state = self.state.lock().expect("Capacity lock is poisoned");
+        state.max_connections = max_connections;
+        state.wait_queue_len = wait_queue_len;
+        state.admit_waiting();
+    }
+
+    #[must_use]
+    pub fn enter(&self) -> Entrance {
+        let mut state = self.state.lock().expect("Capacity lock is poisoned");
+        // The ones gone while waiting leave room in the queue
+        state.waiting.retain(|waiting| !waiting.is_closed());
+        if state.waiting.is_empty() && state.taken < state.max_connections {
+            state.taken += 1;
+            return Entrance::Admitted(Slot {
+                state: Arc::clone(&self.state),
+            });
+        }
+        if state.waiting.len() >= state.wait_queue_len {
+            return Entrance::Refused;
+        }
+        let (sender, admitted) = oneshot::channel();
+        state.waiting.push_back(sender);
+        Entrance::Queued(Waiting {
+            state: Arc::clone(&self.state),
+            admitted: Some(admitted),
+        })
+    }
+
+    /// Turns away the waiting connections, when the server shuts down.
+    pub fn close(&self) {
+        let mut state = self.state.lock().expect("Capacity lock is poisoned");
+        state.wait_queue_len = 0;
+        state.waiting.clear();
+    }
+}
+
+/// The place of an admitted connection, freed when dropped.
+pub struct Slot {
+    state: Arc<Mutex<CapacityState>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        release(&self.state);
+    }
+}
+
+fn release(state: &Mutex<CapacityState>) {
+    let mut state = state.lock().expect("Capacity lock is poisoned");
+    state.taken -= 1;
+    state.admit_waiting();
+}
+
+/// A connection in the queue. Dropping it gives its place up.
+pub struct Waiting {
+    state: Arc<Mutex<CapacityState>>,
+    // None once the slot was handed over
+    admitted: Option<oneshot::Receiver<()>>,
+}
+
+impl Waiting {
+    /// Waits for a free slot. None if the queue was closed meanwhile.
+    pub async fn slot(&mut self) -> Option<Slot> {
+        let admitted = self.admitted.as_mut()?;
+        let admitted = admitted.await;
+        self.admitted = None;
+        admitted.ok()?;
+        Some(Slot {
+            state: Arc::clone(&self.state),
+        })
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        let Some(mut admitted) = self.admitted.take() else {
+            return;
+        };
+        admitted.close();
+        // The slot was handed over just before giving up
+        if admitted.try_recv().is_ok() {
+            release(&self.state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod admission_tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn admitted(entrance: Entrance) -> Slot {
+        let Entrance::Admitted(slot) = entrance else {
+            panic!("Not admitted");
+        };
+        slot
+    }
+
+    fn queued(entrance: Entrance) -> Waiting {
+        let Entrance::Queued(waiting) = entrance else {
+            panic!("Not queued");
+        };
+        waiting
+    }
+
+    #[tokio::test]
+    async fn queue_test() {
+        let capacity = Capacity::new(2, 1);
+        let first = admitted(capacity.enter());
+        let _second = admitted(capacity.enter());
+        let mut waiting = queued(capacity.enter());
+        assert!(matches!(capacity.enter(), Entrance::Refused));
+
+        assert!(timeout(Duration::from_millis(20), waiting.slot())
+            .await
+            .is_err());
+        // The slot goes to the queue, not to a newcomer
+        drop(first);
+        assert!(matches!(capacity.enter(), Entrance::Queued(_)));
+        let third = waiting.slot().await.unwrap();
+        drop(third);
+        let _fourth = admitted(capacity.enter());
+    }
+
+    #[tokio::test]
+    async fn gone_while_waiting_test() {
+        let capacity = Capacity::new(1, 2);
+        let first = admitted(capacity.enter());
+        let gone = queued(capacity.enter());
+        let mut waiting = queued(capacity.enter());
+        drop(gone);
+        drop(first);
+        let second = waiting.slot().await.unwrap();
+
+        // Handed a slot, but gone before taking it
+        let gone = queued(capacity.enter());
+        drop(second);
+        drop(gone);
+        let _third = admitted(capacity.enter());
+    }
+
+    #[tokio::test]
+    async fn configure_test() {
+        let capacity = Capacity::new(1, 1);
+        let first = admitted(capacity.enter());
+        let mut waiting = queued(capacity.enter());
+        capacity.configure(2, 1);
+        let _second = waiting.slot().await.unwrap();
+
+        // Taken away as the connections leave
+        capacity.configure(1, 0);
+        assert!(matches!(capacity.enter(), Entrance::Refused));
+        drop(first);
+        assert!(matches!(capacity.enter(), Entrance::Refused));
+
+        capacity.configure(1, 1);
+        let mut waiting = queued(capacity.enter());
+        capacity.close();
+        assert!(waiting.slot().await.is_none());
+        assert!(matches!(capacity.enter(), Entrance::Refused));
+    }
+}
@@ -0,0 +1,413 @@
+use crate::history::{History, HistoryEntry};
+use async_chat::message::{ParsedMsg, SerializedMessage};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tracing::{error, warn};
+
+const CRC_LEN: usize = std::mem::size_of::<u32>();
+// Records bigger than this are considered corrupted
+const MAX_RECORD_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Sync after every appended message
+    Always,
+    /// Sync at most once per interval
+    Every(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatLogConfig {
+    pub path: PathBuf,
+    pub fsync: FsyncPolicy,
+    /// Rotate the log when it grows beyond this size in bytes
+    pub max_size: u64,
+    /// Rotate the log when its first message is older than this
+    pub max_age: Duration,
+    /// Number of rotated logs to keep
+    pub max_files: usize,
+}
+
+impl Default for ChatLogConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("chat.log"),
+            fsync: FsyncPolicy::Every(Duration::from_secs(1)),
+            max_size: 10 * 1024 * 1024,
+            max_age: Duration::from_secs(24 * 60 * 60),
+            max_files: 10,
+        }
+    }
+}
+
+/// Append-only log of the broadcast messages.
+///
+/// Every record is a history frame followed by its crc32, so a record torn by a
+/// crash is detected and cut away when the log is opened again.
+pub struct ChatLog {
+    config: ChatLogConfig,
+    file: File,
+    size: u64,
+    /// Timestamp of the first record in the file
+    started_at: Option<u64>,
+    dirty: bool,
+}
+
+impl ChatLog {
+    pub fn open(config: ChatLogConfig) -> io::Result<Self> {
+        let (entries, valid_len) = match File::open(&config.path) {
+            Ok(file) => read_records(file)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Vec::new(), 0),
+            Err(e) => return Err(e),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        if file.metadata()?.len() != valid_len {
+            warn!(
+                path = %config.path.display(),
+                valid_len,
+                "truncating corrupted tail of chat log"
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        Ok(Self {
+            started_at: entries.first().map(|e| e.timestamp),
+            config,
+            file,
+            size: valid_len,
+            dirty: false,
+        })
+    }
+
+    /// Loads the last `capacity` messages from the current and the rotated logs, and at least
+    /// the latest one for the numbering to go on.
+    pub fn recover(&self, capacity: usize) -> io::Result<History> {
+        let mut files = rotated_logs(&self.config.path)?;
+        files.push(self.config.path.clone());
+        let mut entries = Vec::new();
+        for path in files.iter().rev() {
+            if entries.len() >= capacity.max(1) {
+                break;
+            }
+            let (mut older, _) = read_records(File::open(path)?)?;
+            older.append(&mut entries);
+            entries = older;
+        }
+        let mut history = History::new(capacity);
+        entries.into_iter().for_each(|entry| history.push(entry));
+        Ok(history)
+    }
+
+    pub fn append(&mut self, entry: &HistoryEntry) -> io::Result<()> {
+        let frame = entry.serialize();
+        let record_len = (frame.as_bytes().len() + CRC_LEN) as u64;
+        if self.needs_rotation(record_len, entry.timestamp) {
+            self.rotate(entry.timestamp)?;
+        }
+        let mut record = Vec::from(frame);
+        record.extend_from_slice(&crc32(&record).to_be_bytes());
+        // A single write, so the record is either complete or a torn tail
+        self.file.write_all(&record)?;
+        self.size += record_len;
+        self.started_at.get_or_insert(entry.timestamp);
+        self.dirty = true;
+        if self.config.fsync == FsyncPolicy::Always {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.file.sync_data()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn needs_rotation(&self, record_len: u64, now: u64) -> bool {
+        let too_big = self.size > 0 && self.size + record_len > self.config.max_size;
+        let too_old = self.started_at.is_some_and(|started_at| {
+            now.saturating_sub(started_at) >= self.config.max_age.as_secs()
+        });
+        too_big || too_old
+    }
+
+    fn rotate(&mut self, now: u64) -> io::Result<()> {
+        self.file.sync_all()?;
+        let mut suffix = now;
+        let rotated = loop {
+            let rotated = rotated_path(&self.config.path, suffix);
+            if !rotated.exists() {
+                break rotated;
+            }
+            suffix += 1;
+        };
+        fs::rename(&self.config.path, rotated)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)?;
+        self.size = 0;
+        self.started_at = None;
+        self.dirty = false;
+
+        let rotated = rotated_logs(&self.config.path)?;
+        let excess = rotated.len().saturating_sub(self.config.max_files);
+        for path in &rotated[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the log on its own thread, fed by the returned sender.
+    /// The thread syncs and exits once every sender is dropped.
+    pub fn spawn(mut self) -> (Sender<HistoryEntry>, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel::<HistoryEntry>();
+        let sync_interval = match self.config.fsync {
+            FsyncPolicy::Every(interval) => interval,
+            FsyncPolicy::Always => Duration::MAX,
+        };
+        let handle = thread::spawn(move || {
+            let mut last_sync = Instant::now();
+            loop {
+                let disconnected = match receiver.recv_timeout(sync_interval) {
+                    Ok(entry) => {
+                        if let Err(e) = self.append(&entry) {
+                            error!(error = %e, "cannot append to chat log");
+                        }
+                        false
+                    }
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Err(RecvTimeoutError::Disconnected) => true,
+                };
+                if disconnected || last_sync.elapsed() >= sync_interval {
+                    if let Err(e) = self.sync() {
+                        error!(error = %e, "cannot sync chat log");
+                    }
+                    last_sync = Instant::now();
+                }
+                if disconnected {
+                    break;
+                }
+            }
+        });
+        (sender, handle)
+    }
+}
+
+fn rotated_path(path: &Path, suffix: u64) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", suffix));
+    path.with_file_name(name)
+}
+
+/// The rotated logs of `path`, oldest first.
+fn rotated_logs(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        "{}.",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+    let mut logs = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let suffix = name.to_str()?.strip_prefix(&prefix)?.parse::<u64>().ok()?;
+            Some((suffix, entry.path()))
+        })
+        .collect::<Vec<_>>();
+    logs.sort_unstable();
+    Ok(logs.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Reads the valid records of a log, returning them with the length of the
+/// valid prefix of the file.
+fn read_records(mut file: File) -> io::Result<(Vec<HistoryEntry>, u64)> {
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while let Some(entry) = read_record(&bytes[pos..]) {
+        pos += entry.1;
+        entries.push(entry.0);
+    }
+    Ok((entries, pos as u64))
+}
+
+fn read_record(bytes: &[u8]) -> Option<(HistoryEntry, usize)> {
+    let size = u32::from_be_bytes(
+        bytes
+            .get(..SerializedMessage::size_of_len())?
+            .try_into()
+            .ok()?,
+    ) as usize;
+    if size <= SerializedMessage::size_of_header() || size > MAX_RECORD_LEN {
+        return None;
+    }
+    let frame = bytes.get(..size)?;
+    let crc = u32::from_be_bytes(bytes.get(size..size + CRC_LEN)?.try_into().ok()?);
+    if crc != crc32(frame) {
+        return None;
+    }
+    let ParsedMsg::History {
+        seq,
+        prev_seq,
+        timestamp,
+        room,
+        sender,
+        text,
+    } = ParsedMsg::from_bytes(frame)?
+    else {
+        return None;
+    };
+    let entry = HistoryEntry {
+        seq,
+        prev_seq,
+        timestamp,
+        room,
+        sender,
+        text,
+    };
+    Some((entry, size + CRC_LEN))
+}
+
+/// CRC-32 (IEEE 802.3)
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &b| {
+        (0..8).fold(crc ^ b as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[cfg(test)]
+mod chatlog_tests {
+    use super::*;
+
+    fn test_config(name: &str) -> ChatLogConfig {
+        let dir = std::env::temp_dir().join(format!("async_chat_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        ChatLogConfig {
+            path: dir.join("chat.log"),
+            fsync: FsyncPolicy::Always,
+            ..ChatLogConfig::default()
+        }
+    }
+
+    fn entry(timestamp: u64, text: &str) -> HistoryEntry {
+        HistoryEntry {
+            seq: timestamp + 1,
+            prev_seq: (timestamp > 0).then_some(timestamp),
+            timestamp,
+            room: "lobby".to_string(),
+            sender: "alice".to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    fn texts(history: &History) -> Vec<&str> {
+        history.room("lobby").map(|e| e.text.as_str()).collect()
+    }
+
+    #[test]
+    fn crc32_test() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn append_and_recover() {
+        let config = test_config("recover");
+        let mut log = ChatLog::open(config.clone()).unwrap();
+        for (i, text) in ["a", "b", "c"].into_iter().enumerate() {
+            log.append(&entry(i as u64, text)).unwrap();
+        }
+        drop(log);
+
+        let log = ChatLog::open(config.clone()).unwrap();
+        assert_eq!(texts(&log.recover(10).unwrap()), ["a", "b", "c"]);
+        assert_eq!(texts(&log.recover(2).unwrap()), ["b", "c"]);
+        // The numbering goes on after a restart
+        assert_eq!(log.recover(2).unwrap().last_seq(), 3);
+        let _ = fs::remove_dir_all(config.path.parent().unwrap());
+    }
+
+    #[test]
+    fn recover_without_history() {
+        let config = test_config("no_history");
+        let mut log = ChatLog::open(config.clone()).unwrap();
+        for (i, text) in ["a", "b"].into_iter().enumerate() {
+            log.append(&entry(i as u64, text)).unwrap();
+        }
+        drop(log);
+
+        let history = ChatLog::open(config.clone()).unwrap().recover(0).unwrap();
+        assert!(texts(&history).is_empty());
+        assert_eq!(history.last_seq(), 2);
+        assert_eq!(history.last_seq_in("lobby"), Some(2));
+        let _ = fs::remove_dir_all(config.path.parent().unwrap());
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let config = test_config("torn");
+        let mut log = ChatLog::open(config.clone()).unwrap();
+        log.append(&entry(0, "complete")).unwrap();
+        drop(log);
+        let valid_len = fs::metadata(&config.path).unwrap().len();
+
+        let torn = entry(1, "torn").serialize();
+        let mut file = OpenOptions::new().append(true).open(&config.path).unwrap();
+        file.write_all(&torn.as_bytes()[..5]).unwrap();
+        drop(file);
+
+        let mut log = ChatLog::open(config.clone()).unwrap();
+        assert_eq!(fs::metadata(&config.path).unwrap().len(), valid_len);
+        log.append(&entry(2, "after")).unwrap();
+        assert_eq!(texts(&log.recover(10).unwrap()), ["complete", "after"]);
+        let _ = fs::remove_dir_all(config.path.parent().unwrap());
+    }
+
+    #[test]
+    fn rotation() {
+        let config = ChatLogConfig {
+            max_size: 64,
+            max_age: Duration::from_secs(100),
+            max_files: 2,
+            ..test_config("rotation")
+        };
+        let mut log = ChatLog::open(config.clone()).unwrap();
+        // Each record is bigger than half of max_size: one record per file
+        for (i, text) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            log.append(&entry(i as u64, &text.repeat(20))).unwrap();
+        }
+        assert_eq!(rotated_logs(&config.path).unwrap().len(), 2);
+        let recovered = log.recover(10).unwrap();
+        assert_eq!(recovered.room("lobby").count(), 3);
+
+        // Rotation by age
+        let mut log = ChatLog::open(ChatLogConfig {
+            max_size: u64::MAX,
+            ..config.clone()
+        })
+        .unwrap();
+        log.append(&entry(1_000, "old")).unwrap();
+        log.append(&entry(1_100, "new")).unwrap();
+        assert_eq!(log.size, fs::metadata(&config.path).unwrap().len());
+        assert_eq!(texts(&log.recover(1).unwrap()), ["new"]);
+        let _ = fs::remove_dir_all(config.path.parent().unwrap());
+    }
+}
@@ -0,0 +1,185 @@
+use crate::Connections;
+use async_chat::command::{Cmd, CMD_PREFIX};
+use std::net::SocketAddr;
+
+/// The roles of the users, ordered by the permissions they grant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Role {
+    #[default]
+    User,
+    Moderator,
+}
+
+type Handler = fn(&mut Connections, SocketAddr, Cmd);
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub permission: Role,
+    pub handler: Handler,
+}
+
+impl CommandSpec {
+    #[must_use]
+    pub fn help_line(&self) -> String {
+        format!("{}{} -> {}", CMD_PREFIX, self.usage, self.description)
+    }
+}
+
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "help",
+        usage: "help [command]",
+        description: "Get the list of commands, or the help of a single command",
+        permission: Role::User,
+        handler: |conns, sockaddr, cmd| {
+            if let Cmd::Help(name) = cmd {
+                conns.send_help_to_user(sockaddr, name);
+            }
+        },
+    },
+    CommandSpec {
+        name: "count",
+        usage: "count",
+        description: "Current number of connected users",
+        permission: Role::User,
+        handler: |conns, sockaddr, _| conns.send_count_to_user(sockaddr),
+    },
+    CommandSpec {
+        name: "nick",
+        usage: "nick <name>",
+        description: "Change your nickname",
+        permission: Role::User,
+        handler: |conns, sockaddr, cmd| {
+            if let Cmd::Nick(nick) = cmd {
+                conns.change_nick(sockaddr, nick);
+            }
+        },
+    },
+    CommandSpec {
+        name: "join",
+        usage: "join <room>",
+        description: "Join (or create) a room",
+        permission: Role::User,
+        handler: |conns, sockaddr, cmd| {
+            if let Cmd::Join(room) = cmd {
+                conns.join_room(sockaddr, room);
+            }
+        },
+    },
+    CommandSpec {
+        name: "leave",
+        usage: "leave",
+        description: "Leave the current room and go back to the lobby",
+        permission: Role::User,
+        handler: |conns, sockaddr, _| conns.leave_room(sockaddr),
+    },
+    CommandSpec {
+        name: "rooms",
+        usage: "rooms",
+        description: "List the available rooms",
+        permission: Role::User,
+        handler: |conns, sockaddr, _| conns.send_room_list_to_user(sockaddr),
+    },
+    CommandSpec {
+        name: "msg",
+        usage: "msg <nick> <text>",
+        description: "Send a private message to a user",
+        permission: Role::User,
+        handler: |conns, sockaddr, cmd| {
+            if let Cmd::DirectMsg { nick, text } = cmd {
+                conns.send_direct_msg(sockaddr, nick, text);
+            }
+        },
+    },
+    CommandSpec {
+        name: "kick",
+        usage: "kick <nick> [reason]",
+        description: "Disconnect a user",
+        permission: Role::Moderator,
+        handler: |conns, sockaddr, cmd| {
+            if let Cmd::Kick { nick, reason } = cmd {
+                conns.kick(sockaddr, nick, reason);
+            }
+        },
+    },
+    CommandSpec {
+        name: "ban",
+        usage: "ban <nick|ip> <duration> [reason]",
+        description: "Disconnect a user and keep it out (its account, or its address for guests). Durations are like 30s, 10m, 2h or 7d",
+        permission: Role::Moderator,
+        handler: |conns, sockaddr, cmd| {
+            if let Cmd::Ban {
+                target,
+                duration,
+                reason,
+            } = cmd
+            {
+                conns.ban(sockaddr, target, duration, reason);
+            }
+        },
+    },
+    CommandSpec {
+        name: "mute",
+        usage: "mute <nick> <duration> [reason]",
+        description: "Drop the messages of a user to the rooms",
+        permission: Role::Moderator,
+        handler: |conns, sockaddr, cmd| {
+            if let Cmd::Mute {
+                nick,
+                duration,
+                reason,
+            } = cmd
+            {
+                conns.mute(sockaddr, nick, duration, reason);
+            }
+        },
+    },
+];
+
+#[must_use]
+pub fn find(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+/// The help of all the commands available to `role`.
+#[must_use]
+pub fn help(role: Role) -> String {
+    COMMANDS
+        .iter()
+        .filter(|spec| spec.permission <= role)
+        .enumerate()
+        .map(|(i, spec)| format!("{}. {}", i + 1, spec.help_line()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod commands_tests {
+    use super::*;
+
+    #[test]
+    fn registry_matches_parser() {
+        for spec in COMMANDS {
+            let usage = spec.usage.replace("<duration>", "10m");
+            let Some(Ok(cmd)) = Cmd::parse(&format!("{}{}", CMD_PREFIX, usage)) else {
+                panic!("Usage of '{}' does not parse", spec.name);
+            };
+            assert_eq!(cmd.name(), spec.name);
+        }
+    }
+
+    #[test]
+    fn help_test() {
+        let help = help(Role::User);
+        let user_commands = COMMANDS
+            .iter()
+            .filter(|spec| spec.permission == Role::User)
+            .count();
+        assert_eq!(help.lines().count(), user_commands);
+        assert!(help.starts_with("1. /help [command] -> "));
+        assert!(!help.contains("/kick"));
+        assert_eq!(super::help(Role::Moderator).lines().count(), COMMANDS.len());
+    }
+}
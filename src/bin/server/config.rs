@@ -0,0 +1,795 @@
+use crate::{
+    access::AccessConfig,
+    chatlog::{ChatLogConfig, FsyncPolicy},
+    outbox::{OutboxConfig, SlowClientPolicy},
+    ratelimit::RateLimitConfig,
+    tls::TlsConfig,
+};
+use async_chat::{
+    admin::{Limits, LimitsUpdate},
+    logging::{self, LogConfig, LogFormat},
+    message::{SerializedMessage, MAX_MSG_LEN},
+};
+use clap::Parser;
+use serde::Deserialize;
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Read when no configuration file is given on the command line, if it exists.
+pub const DEFAULT_CONFIG_PATH: &str = "server.toml";
+pub const DEFAULT_PORT: u16 = 60_000;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 100;
+pub const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(10);
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(1_000);
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+pub const DEFAULT_MISSED_HEARTBEATS: u32 = 3;
+pub const DEFAULT_CHANNEL_QUEUE_LEN: usize = 256;
+pub const DEFAULT_HISTORY_LEN: usize = 50;
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(5_000);
+// The chat log cannot store bigger messages
+const MAX_MSG_LEN_LIMIT: usize = 32 * 1024;
+
+/// Command line flags. They take precedence over the configuration file.
+#[derive(Debug, Default, Parser)]
+#[command(about = "Async chat server")]
+pub struct Args {
+    /// TOML configuration file [default: server.toml, if it exists]
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    #[arg(long)]
+    pub listen_ip: Option<IpAddr>,
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+    /// Connections kept waiting for a free slot once the server is full [default: 0]
+    #[arg(long)]
+    pub wait_queue_len: Option<usize>,
+    /// Time the refused connections are told to wait before trying again
+    #[arg(long)]
+    pub retry_after_secs: Option<u32>,
+    /// Time to receive a whole message, once its first byte arrived
+    #[arg(long)]
+    pub read_timeout_ms: Option<u64>,
+    /// Time a client may go without sending a message, besides the heartbeats [default: 0, no limit]
+    #[arg(long)]
+    pub idle_timeout_secs: Option<u64>,
+    /// Time a client may stay silent before it is pinged
+    #[arg(long)]
+    pub heartbeat_interval_ms: Option<u64>,
+    /// Pings a client may leave unanswered before it is disconnected
+    #[arg(long)]
+    pub missed_heartbeats: Option<u32>,
+    #[arg(long)]
+    pub channel_queue_len: Option<usize>,
+    /// Messages queued for a client that reads slower than it is written to
+    #[arg(long)]
+    pub outbox_len: Option<usize>,
+    /// drop_oldest or disconnect, once the queue of a client is full [default: disconnect]
+    #[arg(long)]
+    pub slow_client_policy: Option<SlowClientPolicy>,
+    /// Longest message accepted from the clients, in bytes
+    #[arg(long)]
+    pub max_msg_len: Option<usize>,
+    /// Messages replayed to new connections
+    #[arg(long)]
+    pub history_len: Option<usize>,
+    /// Time given to the clients to be notified on SIGINT/SIGTERM, before exiting anyway
+    #[arg(long)]
+    pub shutdown_timeout_ms: Option<u64>,
+    /// Enables the accounts, stored in this file
+    #[arg(long)]
+    pub users: Option<PathBuf>,
+    /// Enables the chat log, stored in this file
+    #[arg(long)]
+    pub chat_log: Option<PathBuf>,
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// File with the allow/deny rules of the incoming addresses
+    #[arg(long)]
+    pub access_list: Option<PathBuf>,
+    #[arg(long)]
+    pub max_conns_per_subnet: Option<usize>,
+    /// Unix socket of the admin interface
+    #[arg(long)]
+    pub admin_socket: Option<PathBuf>,
+    /// Serves the Prometheus metrics on this port of the loopback interface
+    #[arg(long)]
+    pub metrics_port: Option<u16>,
+    /// Account allowed to kick, ban and mute the other users. Can be repeated
+    #[arg(long = "moderator")]
+    pub moderators: Vec<String>,
+    /// Which events are logged, like `info` or `warn,server=debug` [default: info]
+    #[arg(long)]
+    pub log_filter: Option<String>,
+    /// text or json [default: text]
+    #[arg(long)]
+    pub log_format: Option<LogFormat>,
+    /// Appends the log to this file instead of printing it
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    listen_ip: Option<IpAddr>,
+    port: Option<u16>,
+    max_connections: Option<usize>,
+    wait_queue_len: Option<usize>,
+    retry_after_secs: Option<u32>,
+    read_timeout_ms: Option<u64>,
+    idle_timeout_secs: Option<u64>,
+    heartbeat_interval_ms: Option<u64>,
+    missed_heartbeats: Option<u32>,
+    channel_queue_len: Option<usize>,
+    outbox_len: Option<usize>,
+    slow_client_policy: Option<SlowClientPolicy>,
+    max_msg_len: Option<usize>,
+    history_len: Option<usize>,
+    shutdown_timeout_ms: Option<u64>,
+    users: Option<PathBuf>,
+    moderators: Option<Vec<String>>,
+    admin_socket: Option<PathBuf>,
+    metrics_port: Option<u16>,
+    chat_log: Option<FileChatLog>,
+    tls: Option<TlsConfig>,
+    rate_limit: Option<FileRateLimit>,
+    access: Option<FileAccess>,
+    log: Option<FileLog>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileChatLog {
+    path: Option<PathBuf>,
+    /// 0 syncs after every message
+    fsync_interval_ms: Option<u64>,
+    max_size: Option<u64>,
+    max_age_secs: Option<u64>,
+    max_files: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileRateLimit {
+    msgs_per_sec: Option<f64>,
+    msg_burst: Option<f64>,
+    bytes_per_sec: Option<f64>,
+    byte_burst: Option<f64>,
+    strikes_before_mute: Option<u32>,
+    strike_window_secs: Option<u64>,
+    mute_secs: Option<u64>,
+    mutes_before_disconnect: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileAccess {
+    list: Option<PathBuf>,
+    max_conns_per_subnet: Option<usize>,
+    ipv4_prefix: Option<u8>,
+    ipv6_prefix: Option<u8>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileLog {
+    filter: Option<String>,
+    format: Option<LogFormat>,
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "Cannot read {}: {}", path.display(), e),
+            Self::Parse(path, e) => write!(f, "Invalid configuration in {}: {}", path.display(), e),
+            Self::Invalid(reason) => write!(f, "Invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The settings of the server. Without a file, the chat log, the accounts, the admin socket,
+/// the metrics and tls are disabled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub listen_ip: IpAddr,
+    pub port: u16,
+    pub max_connections: usize,
+    /// Connections waiting for a free slot, the others are refused
+    pub wait_queue_len: usize,
+    pub retry_after: Duration,
+    /// Time to receive a whole message, from its first byte
+    pub read_timeout: Duration,
+    /// Time a client may go without sending a message, the heartbeats aside
+    pub idle_timeout: Option<Duration>,
+    /// A silent client is pinged every interval, and dropped after missing that many pings
+    pub heartbeat_interval: Duration,
+    pub missed_heartbeats: u32,
+    pub channel_queue_len: usize,
+    /// The queue of each client, applied to the new connections
+    pub outbox: OutboxConfig,
+    pub max_msg_len: usize,
+    pub history_len: usize,
+    pub shutdown_timeout: Duration,
+    pub users: Option<PathBuf>,
+    /// Accounts with the moderator role
+    pub moderators: Vec<String>,
+    /// Enables the admin interface on this unix socket
+    pub admin_socket: Option<PathBuf>,
+    /// Enables the Prometheus metrics on this port of the loopback interface
+    pub metrics_port: Option<u16>,
+    pub chat_log: Option<ChatLogConfig>,
+    pub tls: Option<TlsConfig>,
+    pub rate_limit: RateLimitConfig,
+    pub access: AccessConfig,
+    pub log: LogConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: DEFAULT_PORT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            wait_queue_len: 0,
+            retry_after: DEFAULT_RETRY_AFTER,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            idle_timeout: None,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            missed_heartbeats: DEFAULT_MISSED_HEARTBEATS,
+            channel_queue_len: DEFAULT_CHANNEL_QUEUE_LEN,
+            outbox: OutboxConfig::default(),
+            max_msg_len: MAX_MSG_LEN,
+            history_len: DEFAULT_HISTORY_LEN,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            users: None,
+            moderators: Vec::new(),
+            admin_socket: None,
+            metrics_port: None,
+            chat_log: None,
+            tls: None,
+            rate_limit: RateLimitConfig::default(),
+            access: AccessConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+macro_rules! set {
+    ($target:expr, $value:expr) => {
+        if let Some(value) = $value {
+            $target = value;
+        }
+    };
+    ($target:expr, $value:expr, $map:expr) => {
+        if let Some(value) = $value {
+            $target = $map(value);
+        }
+    };
+}
+
+impl Config {
+    /// Defaults, overridden by the configuration file, overridden by the flags.
+    pub fn load(args: &Args) -> Result<Self, ConfigError> {
+        let file = match &args.config {
+            Some(path) => read_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                read_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => FileConfig::default(),
+        };
+        let mut config = Self::default();
+        config.merge_file(file);
+        config.merge_args(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn merge_file(&mut self, file: FileConfig) {
+        set!(self.listen_ip, file.listen_ip);
+        set!(self.port, file.port);
+        set!(self.max_connections, file.max_connections);
+        set!(self.wait_queue_len, file.wait_queue_len);
+        set!(self.retry_after, file.retry_after_secs, |secs| {
+            Duration::from_secs(u64::from(secs))
+        });
+        set!(
+            self.read_timeout,
+            file.read_timeout_ms,
+            Duration::from_millis
+        );
+        set!(self.idle_timeout, file.idle_timeout_secs, |secs| (secs > 0)
+            .then(|| Duration::from_secs(secs)));
+        set!(
+            self.heartbeat_interval,
+            file.heartbeat_interval_ms,
+            Duration::from_millis
+        );
+        set!(self.missed_heartbeats, file.missed_heartbeats);
+        set!(self.channel_queue_len, file.channel_queue_len);
+        set!(self.outbox.len, file.outbox_len);
+        set!(self.outbox.policy, file.slow_client_policy);
+        set!(self.max_msg_len, file.max_msg_len);
+        set!(self.history_len, file.history_len);
+        set!(
+            self.shutdown_timeout,
+            file.shutdown_timeout_ms,
+            Duration::from_millis
+        );
+        self.users = file.users.or(self.users.take());
+        set!(self.moderators, file.moderators);
+        self.admin_socket = file.admin_socket.or(self.admin_socket.take());
+        self.metrics_port = file.metrics_port.or(self.metrics_port);
+        if let Some(file) = file.chat_log {
+            let chat_log = self.chat_log.get_or_insert_with(ChatLogConfig::default);
+            set!(chat_log.path, file.path);
+            set!(chat_log.fsync, file.fsync_interval_ms, |ms| match ms {
+                0 => FsyncPolicy::Always,
+                ms => FsyncPolicy::Every(Duration::from_millis(ms)),
+            });
+            set!(chat_log.max_size, file.max_size);
+            set!(chat_log.max_age, file.max_age_secs, Duration::from_secs);
+            set!(chat_log.max_files, file.max_files);
+        }
+        self.tls = file.tls.or(self.tls.take());
+        if let Some(file) = file.rate_limit {
+            let limits = &mut self.rate_limit;
+            set!(limits.msgs_per_sec, file.msgs_per_sec);
+            set!(limits.msg_burst, file.msg_burst);
+            set!(limits.bytes_per_sec, file.bytes_per_sec);
+            set!(limits.byte_burst, file.byte_burst);
+            set!(limits.strikes_before_mute, file.strikes_before_mute);
+            set!(
+                limits.strike_window,
+                file.strike_window_secs,
+                Duration::from_secs
+            );
+            set!(limits.mute_duration, file.mute_secs, Duration::from_secs);
+            set!(limits.mutes_before_disconnect, file.mutes_before_disconnect);
+        }
+        if let Some(file) = file.access {
+            let access = &mut self.access;
+            access.list = file.list.or(access.list.take());
+            set!(access.max_conns_per_subnet, file.max_conns_per_subnet);
+            set!(access.ipv4_prefix, file.ipv4_prefix);
+            set!(access.ipv6_prefix, file.ipv6_prefix);
+        }
+        if let Some(file) = file.log {
+            set!(self.log.filter, file.filter);
+            set!(self.log.format, file.format);
+            self.log.file = file.file.or(self.log.file.take());
+        }
+    }
+
+    fn merge_args(&mut self, args: &Args) {
+        set!(self.listen_ip, args.listen_ip);
+        set!(self.port, args.port);
+        set!(self.max_connections, args.max_connections);
+        set!(self.wait_queue_len, args.wait_queue_len);
+        set!(self.retry_after, args.retry_after_secs, |secs| {
+            Duration::from_secs(u64::from(secs))
+        });
+        set!(
+            self.read_timeout,
+            args.read_timeout_ms,
+            Duration::from_millis
+        );
+        set!(self.idle_timeout, args.idle_timeout_secs, |secs| (secs > 0)
+            .then(|| Duration::from_secs(secs)));
+        set!(
+            self.heartbeat_interval,
+            args.heartbeat_interval_ms,
+            Duration::from_millis
+        );
+        set!(self.missed_heartbeats, args.missed_heartbeats);
+        set!(self.channel_queue_len, args.channel_queue_len);
+        set!(self.outbox.len, args.outbox_len);
+        set!(self.outbox.policy, args.slow_client_policy);
+        set!(self.max_msg_len, args.max_msg_len);
+        set!(self.history_len, args.history_len);
+        set!(
+            self.shutdown_timeout,
+            args.shutdown_timeout_ms,
+            Duration::from_millis
+        );
+        if let Some(users) = &args.users {
+            self.users = Some(users.clone());
+        }
+        if let Some(path) = &args.admin_socket {
+            self.admin_socket = Some(path.clone());
+        }
+        if args.metrics_port.is_some() {
+            self.metrics_port = args.metrics_port;
+        }
+        if !args.moderators.is_empty() {
+            self.moderators.clone_from(&args.moderators);
+        }
+        if let Some(path) = &args.chat_log {
+            self.chat_log
+                .get_or_insert_with(ChatLogConfig::default)
+                .path
+                .clone_from(path);
+        }
+        if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+            self.tls = Some(TlsConfig {
+                cert: cert.clone(),
+                key: key.clone(),
+            });
+        }
+        if let Some(list) = &args.access_list {
+            self.access.list = Some(list.clone());
+        }
+        set!(self.access.max_conns_per_subnet, args.max_conns_per_subnet);
+        if let Some(filter) = &args.log_filter {
+            self.log.filter.clone_from(filter);
+        }
+        set!(self.log.format, args.log_format);
+        if let Some(path) = &args.log_file {
+            self.log.file = Some(path.clone());
+        }
+    }
+
+    /// `new` as far as it can be applied while the server runs: the settings that need a
+    /// restart are kept from `self`. Also returns the names of those that changed.
+    #[must_use]
+    pub fn reloaded(&self, mut new: Self) -> (Self, Vec<&'static str>) {
+        let mut restart_needed = Vec::new();
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(
+                    if new.$field != self.$field {
+                        restart_needed.push(stringify!($field));
+                        new.$field = self.$field.clone();
+                    }
+                )*
+            };
+        }
+        keep!(
+            listen_ip,
+            port,
+            channel_queue_len,
+            history_len,
+            shutdown_timeout,
+            users,
+            admin_socket,
+            metrics_port,
+            chat_log,
+            tls,
+            log
+        );
+        (new, restart_needed)
+    }
+
+    #[must_use]
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_connections: self.max_connections,
+            max_conns_per_subnet: self.access.max_conns_per_subnet,
+            max_msg_len: self.max_msg_len,
+            read_timeout_ms: self.read_timeout.as_millis() as u64,
+            msgs_per_sec: self.rate_limit.msgs_per_sec,
+            msg_burst: self.rate_limit.msg_burst,
+            bytes_per_sec: self.rate_limit.bytes_per_sec,
+            byte_burst: self.rate_limit.byte_burst,
+        }
+    }
+
+    /// A copy with the limits of `update`, if they are valid.
+    pub fn with_limits(&self, update: LimitsUpdate) -> Result<Self, ConfigError> {
+        let mut config = self.clone();
+        set!(config.max_connections, update.max_connections);
+        set!(
+            config.access.max_conns_per_subnet,
+            update.max_conns_per_subnet
+        );
+        set!(config.max_msg_len, update.max_msg_len);
+        set!(
+            config.read_timeout,
+            update.read_timeout_ms,
+            Duration::from_millis
+        );
+        let limits = &mut config.rate_limit;
+        set!(limits.msgs_per_sec, update.msgs_per_sec);
+        set!(limits.msg_burst, update.msg_burst);
+        set!(limits.bytes_per_sec, update.bytes_per_sec);
+        set!(limits.byte_burst, update.byte_burst);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let check = |ok: bool, reason: String| {
+            if ok {
+                Ok(())
+            } else {
+                Err(ConfigError::Invalid(reason))
+            }
+        };
+        check(
+            self.max_connections > 0,
+            "max_connections must be positive".to_string(),
+        )?;
+        check(
+            !self.read_timeout.is_zero(),
+            "read_timeout_ms must be positive".to_string(),
+        )?;
+        check(
+            !self.heartbeat_interval.is_zero(),
+            "heartbeat_interval_ms must be positive".to_string(),
+        )?;
+        check(
+            !self.shutdown_timeout.is_zero(),
+            "shutdown_timeout_ms must be positive".to_string(),
+        )?;
+        check(
+            self.channel_queue_len > 0,
+            "channel_queue_len must be positive".to_string(),
+        )?;
+        check(
+            self.outbox.len > 0,
+            "outbox_len must be positive".to_string(),
+        )?;
+        check(
+            (SerializedMessage::size_of_header() + 1..=MAX_MSG_LEN_LIMIT)
+                .contains(&self.max_msg_len),
+            format!(
+                "max_msg_len must be between {} and {}",
+                SerializedMessage::size_of_header() + 1,
+                MAX_MSG_LEN_LIMIT
+            ),
+        )?;
+        check(
+            self.moderators.is_empty() || self.users.is_some(),
+            "moderators need the accounts, set users".to_string(),
+        )?;
+        if let Some(chat_log) = &self.chat_log {
+            check(
+                chat_log.max_size > 0 && chat_log.max_files > 0,
+                "chat_log.max_size and chat_log.max_files must be positive".to_string(),
+            )?;
+        }
+        let limits = &self.rate_limit;
+        check(
+            limits.msgs_per_sec > 0.0 && limits.bytes_per_sec > 0.0,
+            "rate_limit.msgs_per_sec and rate_limit.bytes_per_sec must be positive".to_string(),
+        )?;
+        check(
+            limits.msg_burst >= 1.0,
+            "rate_limit.msg_burst must be at least 1".to_string(),
+        )?;
+        check(
+            limits.byte_burst >= self.max_msg_len as f64,
+            format!(
+                "rate_limit.byte_burst must be at least max_msg_len ({})",
+                self.max_msg_len
+            ),
+        )?;
+        check(
+            limits.strikes_before_mute > 0,
+            "rate_limit.strikes_before_mute must be positive".to_string(),
+        )?;
+        let access = &self.access;
+        check(
+            access.max_conns_per_subnet > 0,
+            "access.max_conns_per_subnet must be positive".to_string(),
+        )?;
+        check(
+            access.ipv4_prefix <= 32 && access.ipv6_prefix <= 128,
+            "access.ipv4_prefix must be at most 32 and access.ipv6_prefix at most 128".to_string(),
+        )?;
+        logging::check_filter(&self.log.filter).map_err(|e| ConfigError::Invalid(e.to_string()))
+    }
+}
+
+fn read_file(path: &Path) -> Result<FileConfig, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+    toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        config.merge_file(toml::from_str(text).map_err(|e| ConfigError::Parse(PathBuf::new(), e))?);
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn file_test() {
+        let config = parse(
+            r#"
+            port = 7000
+            read_timeout_ms = 250
+            heartbeat_interval_ms = 5000
+            idle_timeout_secs = 600
+            shutdown_timeout_ms = 2000
+            users = "users.db"
+            moderators = ["alice"]
+            metrics_port = 9100
+            slow_client_policy = "drop_oldest"
+            wait_queue_len = 20
+
+            [chat_log]
+            fsync_interval_ms = 0
+
+            [tls]
+            cert = "cert.pem"
+            key = "key.pem"
+
+            [rate_limit]
+            mute_secs = 5
+
+            [access]
+            ipv4_prefix = 24
+
+            [log]
+            format = "json"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.read_timeout, Duration::from_millis(250));
+        assert_eq!(config.heartbeat_interval, Duration::from_secs(5));
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(600)));
+        assert_eq!(parse("idle_timeout_secs = 0").unwrap().idle_timeout, None);
+        assert_eq!(config.missed_heartbeats, DEFAULT_MISSED_HEARTBEATS);
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(2));
+        assert_eq!(config.max_connections, DEFAULT_MAX_CONNECTIONS);
+        assert_eq!(config.wait_queue_len, 20);
+        assert_eq!(config.retry_after, DEFAULT_RETRY_AFTER);
+        assert_eq!(config.users, Some(PathBuf::from("users.db")));
+        assert_eq!(config.moderators, ["alice"]);
+        assert_eq!(config.metrics_port, Some(9100));
+        assert_eq!(config.outbox.policy, SlowClientPolicy::DropOldest);
+        assert_eq!(config.outbox.len, OutboxConfig::default().len);
+        let chat_log = config.chat_log.unwrap();
+        assert_eq!(chat_log.fsync, FsyncPolicy::Always);
+        assert_eq!(chat_log.path, ChatLogConfig::default().path);
+        assert_eq!(config.tls.unwrap().key, PathBuf::from("key.pem"));
+        assert_eq!(config.rate_limit.mute_duration, Duration::from_secs(5));
+        assert_eq!(config.access.ipv4_prefix, 24);
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.log.filter, "info");
+
+        assert_eq!(parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn precedence_test() {
+        let path = std::env::temp_dir().join(format!("async_chat_config_{}", std::process::id()));
+        std::fs::write(&path, "port = 7000\nmax_connections = 5\n").unwrap();
+        let args = Args::try_parse_from([
+            "server",
+            "--config",
+            path.to_str().unwrap(),
+            "--port",
+            "8000",
+            "--chat-log",
+            "other.log",
+            "--log-format",
+            "json",
+            "--outbox-len",
+            "16",
+            "--retry-after-secs",
+            "30",
+        ])
+        .unwrap();
+        let config = Config::load(&args).unwrap();
+        assert_eq!(config.port, 8000);
+        assert_eq!(config.max_connections, 5);
+        assert_eq!(config.chat_log.unwrap().path, PathBuf::from("other.log"));
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert_eq!(config.outbox.len, 16);
+        assert_eq!(config.retry_after, Duration::from_secs(30));
+        let _ = std::fs::remove_file(&path);
+
+        // A missing file given explicitly is an error
+        let args = Args::try_parse_from(["server", "-c", "/nonexistent/server.toml"]).unwrap();
+        assert!(matches!(Config::load(&args), Err(ConfigError::Io(..))));
+        // Cert and key go together
+        assert!(Args::try_parse_from(["server", "--tls-cert", "cert.pem"]).is_err());
+    }
+
+    #[test]
+    fn reload_test() {
+        let current = parse("port = 7000\nmax_connections = 5").unwrap();
+        let new = parse("port = 8000\nmax_connections = 6\nmoderators = []").unwrap();
+        let (config, restart_needed) = current.reloaded(new);
+        assert_eq!(restart_needed, ["port"]);
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.max_connections, 6);
+    }
+
+    #[test]
+    fn limits_test() {
+        let config = Config::default()
+            .with_limits(LimitsUpdate {
+                max_connections: Some(5),
+                read_timeout_ms: Some(300),
+                ..LimitsUpdate::default()
+            })
+            .unwrap();
+        let limits = config.limits();
+        assert_eq!(limits.max_connections, 5);
+        assert_eq!(limits.read_timeout_ms, 300);
+        assert_eq!(limits.max_msg_len, MAX_MSG_LEN);
+
+        assert!(matches!(
+            config.with_limits(LimitsUpdate {
+                msgs_per_sec: Some(0.0),
+                ..LimitsUpdate::default()
+            }),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn validation_test() {
+        assert!(matches!(parse("prot = 7000"), Err(ConfigError::Parse(..))));
+        assert!(matches!(parse("port = \"x\""), Err(ConfigError::Parse(..))));
+        assert!(matches!(
+            parse("max_connections = 0"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse("max_msg_len = 1000000"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse("max_msg_len = 10000\n[rate_limit]\nbyte_burst = 5000.0"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse("moderators = [\"alice\"]"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse("heartbeat_interval_ms = 0"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse("outbox_len = 0"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse("slow_client_policy = \"block\""),
+            Err(ConfigError::Parse(..))
+        ));
+        assert!(matches!(
+            parse("[access]\nipv4_prefix = 33"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse("[log]\nfilter = \"server=loud\""),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            parse("[log]\nformat = \"xml\""),
+            Err(ConfigError::Parse(..))
+        ));
+        assert!(matches!(
+            parse("[tls]\ncert = \"cert.pem\""),
+            Err(ConfigError::Parse(..))
+        ));
+    }
+}
@@ -0,0 +1,140 @@
+use async_chat::message::SerializedMessage;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// Place in the order of the broadcast messages, from 1
+    pub seq: u64,
+    /// The message before it in the same room, None for the first one
+    pub prev_seq: Option<u64>,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub room: String,
+    pub sender: String,
+    pub text: String,
+}
+
+impl HistoryEntry {
+    #[must_use]
+    pub fn now(seq: u64, prev_seq: Option<u64>, room: &str, sender: &str, text: &str) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self {
+            seq,
+            prev_seq,
+            timestamp,
+            room: room.to_string(),
+            sender: sender.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[must_use]
+    pub fn serialize(&self) -> SerializedMessage {
+        SerializedMessage::from_history(
+            self.seq,
+            self.prev_seq,
+            self.timestamp,
+            &self.room,
+            &self.sender,
+            &self.text,
+        )
+    }
+}
+
+/// Bounded buffer of the most recent broadcast messages.
+pub struct History {
+    entries: VecDeque<HistoryEntry>,
+    capacity: usize,
+    // The latest message of every room, including the ones already dropped
+    last_in_room: HashMap<String, u64>,
+    // Likewise of all the rooms, so that the numbering goes on without a history
+    last_seq: u64,
+}
+
+impl History {
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            last_in_room: HashMap::new(),
+            last_seq: 0,
+        }
+    }
+
+    pub fn push(&mut self, entry: HistoryEntry) {
+        let _ = self.last_in_room.insert(entry.room.clone(), entry.seq);
+        self.last_seq = self.last_seq.max(entry.seq);
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            let _ = self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// The messages of a room, the only ones its members may see.
+    pub fn room<'a>(&'a self, room: &'a str) -> impl Iterator<Item = &'a HistoryEntry> {
+        self.entries.iter().filter(move |entry| entry.room == room)
+    }
+
+    /// The sequence number of the latest message of a room.
+    #[must_use]
+    pub fn last_seq_in(&self, room: &str) -> Option<u64> {
+        self.last_in_room.get(room).copied()
+    }
+
+    /// The sequence number of the latest message, 0 if there is none.
+    #[must_use]
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+}
+
+#[cfg(test)]
+mod history_tests {
+    use super::*;
+
+    #[test]
+    fn bounded_test() {
+        let mut history = History::new(2);
+        for (seq, text) in (1..).zip(["a", "b", "c"]) {
+            history.push(HistoryEntry::now(seq, None, "lobby", "alice", text));
+        }
+        let texts = history
+            .room("lobby")
+            .map(|e| e.text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["b", "c"]);
+        assert_eq!(history.last_seq(), 3);
+
+        let mut history = History::new(0);
+        history.push(HistoryEntry::now(1, None, "lobby", "alice", "a"));
+        assert_eq!(history.room("lobby").count(), 0);
+        assert_eq!(history.last_seq(), 1);
+    }
+
+    #[test]
+    fn room_test() {
+        let mut history = History::new(2);
+        for (seq, room) in (1..).zip(["lobby", "rust", "lobby"]) {
+            let prev_seq = history.last_seq_in(room);
+            history.push(HistoryEntry::now(seq, prev_seq, room, "alice", "a"));
+        }
+        let seqs = |room| history.room(room).map(|e| e.seq).collect::<Vec<_>>();
+        assert_eq!(seqs("lobby"), [3]);
+        assert_eq!(seqs("rust"), [2]);
+        assert!(seqs("go").is_empty());
+        // Still known once dropped from the history
+        assert_eq!(history.room("lobby").next().unwrap().prev_seq, Some(1));
+        assert_eq!(history.last_seq_in("lobby"), Some(3));
+        assert_eq!(history.last_seq_in("go"), None);
+    }
+}
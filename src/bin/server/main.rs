@@ -1,237 +1,812 @@
-use async_chat::message::{Cmd, InfoKind, ParsedMsg, SerializedMessage, MAX_MSG_LEN};
+mod access;
+mod accounts;
+mod admin;
+mod admission;
+mod chatlog;
+mod commands;
+mod config;
+mod history;
+mod metrics;
+mod moderation;
+mod outbox;
+mod ratelimit;
+mod rooms;
+mod tls;
+
+use access::{Gate, Ticket};
+use accounts::UserStore;
+use admin::AdminRequest;
+use admission::{Capacity, Entrance, Slot, Waiting};
+use async_chat::command::{Cmd, CmdError, CMD_PREFIX};
+use async_chat::message::{
+    AuthKind, AuthStatus, FrameDecoder, FrameError, InfoKind, MsgType, ParsedMsg,
+    SerializedMessage, DEFAULT_ROOM, MAX_NICK_LEN, MAX_ROOM_NAME_LEN,
+};
+use chatlog::ChatLog;
+use clap::Parser;
+use commands::Role;
+use config::{Args, Config};
+use history::{History, HistoryEntry};
+use metrics::Metrics;
+use moderation::Sanctions;
+use outbox::Outbox;
+use ratelimit::{RateLimiter, Verdict};
+use rooms::Rooms;
 use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, Weak},
-    time::Duration,
+    collections::{HashMap, HashSet},
+    future::Future,
+    io::Cursor,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener,
-    },
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    signal::unix::{signal, SignalKind},
     spawn,
     sync::{
         mpsc::{self, Receiver, Sender},
-        Mutex,
+        oneshot, watch,
     },
+    task::{spawn_blocking, JoinHandle},
+    time::timeout_at,
 };
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
 const RESERVED_MSG_LEN: usize = 512;
-const MAX_CHANNEL_QUEUE_LEN: usize = 256;
 const MAX_SIMULATANEOUS_INCOMING_CONNECTIONS: usize = 32;
 const SERVER_INFO_HEADER: &str = "SERVER.INFO: ";
-const MAX_CONNECTIONS: usize = 100;
-const SERVER_PORT: u16 = 60_000;
-const SERVER_LISTEN_IP: &str = "0.0.0.0";
-const READ_TIMEOUT_MS: Duration = Duration::from_millis(1_000);
+const GUEST_NICK_PREFIX: &str = "guest-";
+
+// Either a plain tcp stream or a tls one
+type StreamReader = Box<dyn AsyncRead + Send + Unpin>;
+type StreamWriter = Box<dyn AsyncWrite + Send + Unpin>;
+// The settings can change while the server runs, see Connections::set_config
+type ConfigWatch = watch::Receiver<Arc<Config>>;
 
-const HELP_STRING: &str = //
-    r"1. /help -> Get this message
-    2. /count -> Current number of connectet users";
+/// Publishes the settings to the rest of the server. The flags are kept to load them again.
+struct ConfigSource {
+    sender: watch::Sender<Arc<Config>>,
+    args: Option<Args>,
+}
 
 enum Connection {
     Push {
         sockaddr: SocketAddr,
-        stream_writer: OwnedWriteHalf,
+        outbox: Outbox,
+        writer: JoinHandle<()>,
+        hangup: oneshot::Sender<()>,
     },
     Pop(SocketAddr),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AuthState {
+    Anonymous,
+    /// Waiting for the password to be checked
+    Pending,
+    Authenticated,
+}
+
+struct AuthOutcome {
+    sockaddr: SocketAddr,
+    kind: AuthKind,
+    user: String,
+    status: AuthStatus,
+}
+
 struct Entry {
-    writer_stream: Arc<Mutex<OwnedWriteHalf>>,
+    outbox: Outbox,
+    // Writes what is queued in the outbox
+    writer: JoinHandle<()>,
+    sockaddr: SocketAddr,
+    nick: String,
+    room: String,
+    role: Role,
+    auth: AuthState,
+    account: Option<String>,
+    /// Set by a moderator, the messages to the rooms are dropped until then
+    muted_until: Option<Instant>,
+    // Dropped with the entry, which stops reading from the connection
+    _hangup: oneshot::Sender<()>,
+    // Parent of the events about this connection
+    span: Span,
+}
+
+/// The span of the events about a connection, once it has a nick.
+fn conn_span(sockaddr: SocketAddr, nick: &str) -> Span {
+    info_span!("conn", addr = %sockaddr, nick)
 }
 
 impl Entry {
-    fn new(stream: OwnedWriteHalf) -> Self {
+    fn new(
+        outbox: Outbox,
+        writer: JoinHandle<()>,
+        sockaddr: SocketAddr,
+        nick: String,
+        auth: AuthState,
+        hangup: oneshot::Sender<()>,
+    ) -> Self {
         Self {
-            writer_stream: Arc::new(Mutex::new(stream)),
+            outbox,
+            writer,
+            sockaddr,
+            span: conn_span(sockaddr, &nick),
+            nick,
+            room: DEFAULT_ROOM.to_string(),
+            role: Role::default(),
+            auth,
+            account: None,
+            muted_until: None,
+            _hangup: hangup,
         }
     }
 
-    async fn close(&mut self) {
-        let mut stream = self.writer_stream.lock().await;
-        if let Err(e) = stream.shutdown().await {
-            println!("Cannot shutdown stream: {}", e);
-        }
+    fn set_nick(&mut self, nick: String) {
+        self.span = conn_span(self.sockaddr, &nick);
+        self.nick = nick;
     }
 
-    fn get_weak_stream(&self) -> WeakEntry {
-        WeakEntry {
-            stream: Arc::downgrade(&self.writer_stream),
-        }
+    fn is_muted(&self) -> bool {
+        self.muted_until.is_some_and(|until| Instant::now() < until)
     }
 
-    async fn write_all<F>(&self, f: F)
-    where
-        F: FnOnce() -> SerializedMessage,
-    {
-        write_all(&self.writer_stream, f).await;
+    /// Queues `msg` as the last message of the connection, which is closed once it is written.
+    fn close_with(&self, msg: SerializedMessage) {
+        self.outbox.send(msg);
+        self.outbox.close();
     }
 }
 
-struct WeakEntry {
-    stream: Weak<Mutex<OwnedWriteHalf>>,
+struct Connections {
+    entries: HashMap<SocketAddr, Entry>,
+    rooms: Rooms,
+    history: History,
+    chat_log: Option<std::sync::mpsc::Sender<HistoryEntry>>,
+    // Guarded by a std Mutex since it is used only in blocking tasks
+    accounts: Option<Arc<std::sync::Mutex<UserStore>>>,
+    // Of the registered accounts, so that a nick is checked without waiting for the store
+    account_names: HashSet<String>,
+    auth_sender: Sender<AuthOutcome>,
+    // To drop connections from within
+    conn_sender: Sender<Connection>,
+    gate: Gate,
+    // Shared with the accept loop
+    capacity: Capacity,
+    sanctions: Sanctions,
+    guest_counter: usize,
+    config: Arc<Config>,
+    config_source: ConfigSource,
+    started: Instant,
+    total_connections: u64,
+    total_messages: u64,
+    /// Of the latest broadcast message. It goes on from the chat log, if any
+    seq: u64,
+    // Shared with the connection tasks and the metrics endpoint
+    metrics: Arc<Metrics>,
 }
 
-impl WeakEntry {
-    async fn write_all<F>(&self, f: F)
-    where
-        F: FnOnce() -> SerializedMessage,
-    {
-        if let Some(stream) = self.stream.upgrade() {
-            write_all(&stream, f).await;
-        }
-    }
+#[must_use]
+fn is_valid_name(name: &str, max_len: usize) -> bool {
+    !name.is_empty()
+        && name.len() <= max_len
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
 }
 
-async fn write_all<F>(stream: &Mutex<OwnedWriteHalf>, f: F)
-where
-    F: FnOnce() -> SerializedMessage,
-{
-    let mut lock_stream = stream.lock().await;
-    if let Ok(()) = lock_stream.writable().await {
-        lock_stream
-            .write_all(f().as_bytes())
-            .await
-            .expect("Cannot write to stream");
+impl Connections {
+    fn new(
+        history: History,
+        chat_log: Option<std::sync::mpsc::Sender<HistoryEntry>>,
+        accounts: Option<UserStore>,
+        auth_sender: Sender<AuthOutcome>,
+        conn_sender: Sender<Connection>,
+        gate: Gate,
+        config_source: ConfigSource,
+    ) -> Self {
+        let config = Arc::clone(&config_source.sender.borrow());
+        Self {
+            entries: HashMap::new(),
+            rooms: Rooms::default(),
+            seq: history.last_seq(),
+            history,
+            chat_log,
+            account_names: accounts
+                .iter()
+                .flat_map(|store| store.names().map(str::to_string))
+                .collect(),
+            accounts: accounts.map(|store| Arc::new(std::sync::Mutex::new(store))),
+            auth_sender,
+            conn_sender,
+            gate,
+            capacity: Capacity::new(config.max_connections, config.wait_queue_len),
+            sanctions: Sanctions::default(),
+            guest_counter: 0,
+            config,
+            config_source,
+            started: Instant::now(),
+            total_connections: 0,
+            total_messages: 0,
+            metrics: Arc::default(),
+        }
     }
-}
-
-#[derive(Default)]
-struct Connections {
-    // TODO: Encapsulate Arc<Mutex<OwnedWriteHalf>> in own struct
-    entries: HashMap<SocketAddr, Entry>,
-}
 
-impl Connections {
     async fn handle_conn(&mut self, conn: Connection) {
         match conn {
             Connection::Push {
                 sockaddr,
-                stream_writer,
+                outbox,
+                writer,
+                hangup,
             } => {
-                println!("added connection: {}", sockaddr);
-                let _ = self.entries.insert(sockaddr, Entry::new(stream_writer));
-                if self.entries.len() >= MAX_CONNECTIONS {
-                    self.send_info_msg(sockaddr, InfoKind::ServerFull);
+                self.total_connections += 1;
+                self.metrics.connections_total.inc();
+                let nick = self.next_guest_nick();
+                let auth = if self.accounts.is_some() {
+                    AuthState::Anonymous
+                } else {
+                    AuthState::Authenticated
+                };
+                let entry = Entry::new(outbox, writer, sockaddr, nick, auth, hangup);
+                info!(parent: &entry.span, "added connection");
+                let _ = self.entries.insert(sockaddr, entry);
+                self.metrics.connections.set(self.entries.len() as i64);
+                if auth == AuthState::Authenticated {
+                    self.admit(sockaddr);
+                } else {
+                    self.send_to_user(
+                        sockaddr,
+                        SerializedMessage::from_auth_response(
+                            AuthStatus::Required,
+                            "Log in or register to chat",
+                        ),
+                    );
                 }
             }
             Connection::Pop(sockaddr) => {
-                println!("removed connection: {}", sockaddr);
-                let stream = self.entries.remove(&sockaddr);
-                if let Some(mut stream) = stream {
-                    stream.close().await;
+                // Only the users let in a room are seen leaving
+                let joined = self
+                    .entries
+                    .get(&sockaddr)
+                    .is_some_and(|entry| self.rooms.contains(&entry.room, sockaddr));
+                if let Some(entry) = self.remove_entry(sockaddr) {
+                    info!(parent: &entry.span, "removed connection");
+                    if joined {
+                        self.send_to_room(
+                            &entry.room,
+                            &format!("{}{} left the chat", SERVER_INFO_HEADER, entry.nick),
+                        );
+                    }
+                    // A notice may still be queued, like the reason of a kick
+                    entry.outbox.close();
                 }
             }
         };
     }
 
-    fn send_count_to_user(&self, sockaddr: SocketAddr) {
-        if let Some(entry) = self.entries.get(&sockaddr).map(Entry::get_weak_stream) {
-            let user_count = self.entries.len() as u32;
-            spawn(async move {
-                entry
-                    .write_all(|| SerializedMessage::from_user_count(user_count))
-                    .await;
-            });
+    fn remove_entry(&mut self, sockaddr: SocketAddr) -> Option<Entry> {
+        let entry = self.entries.remove(&sockaddr)?;
+        self.metrics.connections.set(self.entries.len() as i64);
+        self.rooms.leave(&entry.room, sockaddr);
+        Some(entry)
+    }
+
+    /// Lets an authenticated connection in the default room and welcomes it with the history
+    /// of the room.
+    fn admit(&mut self, sockaddr: SocketAddr) {
+        let Some(entry) = self.entries.get(&sockaddr) else {
+            return;
+        };
+        self.rooms.join(&entry.room, sockaddr);
+        let greeting = SerializedMessage::from_auth_response(
+            AuthStatus::Ok,
+            &format!("Welcome, {}", entry.nick),
+        );
+        let history = self.history.room(&entry.room).map(HistoryEntry::serialize);
+        for msg in std::iter::once(greeting).chain(history) {
+            entry.outbox.send(msg);
         }
     }
 
-    fn send_help_to_user(&self, sockaddr: SocketAddr) {
-        if let Some(entry) = self.entries.get(&sockaddr).map(Entry::get_weak_stream) {
-            spawn(async move {
-                entry
-                    .write_all(|| SerializedMessage::from_help_string(HELP_STRING))
-                    .await;
+    fn authenticate(
+        &mut self,
+        sockaddr: SocketAddr,
+        kind: AuthKind,
+        user: String,
+        password: String,
+    ) {
+        let (Some(entry), Some(accounts)) = (self.entries.get_mut(&sockaddr), &self.accounts)
+        else {
+            return;
+        };
+        if entry.auth != AuthState::Anonymous {
+            let status = AuthStatus::AlreadyLoggedIn;
+            self.send_to_user(
+                sockaddr,
+                SerializedMessage::from_auth_response(status, auth_status_text(status)),
+            );
+            return;
+        }
+        entry.auth = AuthState::Pending;
+        // The sessions, sanctions and moderators know an account by a single name
+        let user = accounts::account_name(&user);
+        let accounts = Arc::clone(accounts);
+        let auth_sender = self.auth_sender.clone();
+        // Password hashing is slow on purpose, keep it away from the connections task
+        spawn_blocking(move || {
+            let mut accounts = accounts.lock().expect("User store lock is poisoned");
+            let status = match kind {
+                AuthKind::Login => accounts.verify(&user, &password),
+                AuthKind::Register => accounts.register(&user, &password).unwrap_or_else(|e| {
+                    error!(%sockaddr, %user, error = %e, "cannot register user");
+                    AuthStatus::InvalidCredentials
+                }),
+            };
+            drop(accounts);
+            let _ = auth_sender.blocking_send(AuthOutcome {
+                sockaddr,
+                kind,
+                user,
+                status,
             });
+        });
+    }
+
+    fn handle_auth_outcome(&mut self, outcome: AuthOutcome) {
+        let AuthOutcome {
+            sockaddr,
+            kind,
+            user,
+            mut status,
+        } = outcome;
+        if kind == AuthKind::Register && status == AuthStatus::Ok {
+            let _ = self.account_names.insert(user.clone());
+        }
+        let logged_in = self
+            .entries
+            .values()
+            .any(|entry| entry.account.as_deref() == Some(user.as_str()));
+        if status == AuthStatus::Ok && logged_in {
+            status = AuthStatus::AlreadyLoggedIn;
+        }
+        let nick_taken = self.is_nick_taken(&user);
+        let Some(entry) = self.entries.get_mut(&sockaddr) else {
+            return;
+        };
+        if status != AuthStatus::Ok {
+            entry.auth = AuthState::Anonymous;
+            self.send_to_user(
+                sockaddr,
+                SerializedMessage::from_auth_response(status, auth_status_text(status)),
+            );
+            return;
+        }
+        entry.auth = AuthState::Authenticated;
+        if !nick_taken {
+            entry.set_nick(user.clone());
+        }
+        info!(parent: &entry.span, account = %user, "logged in");
+        entry.account = Some(user.clone());
+        if self.check_sanctions(sockaddr, &user) {
+            self.admit(sockaddr);
         }
     }
 
-    fn broadcast_msg(&self, txt: String, sockaddr: SocketAddr) {
-        for (key, entry) in self.entries.iter().map(|(k, v)| (k, v.get_weak_stream())) {
-            let txt = txt.clone();
-            let key = key.clone();
-            spawn(async move {
-                entry
-                    .write_all(|| {
-                        let prefix = if key == sockaddr {
-                            "You".to_string()
-                        } else {
-                            sockaddr.to_string()
-                        };
-                        SerializedMessage::from_string(&format!("{}: {}", prefix, txt))
-                    })
-                    .await;
-            });
+    /// Whether `nick` belongs to the account of somebody other than `sockaddr`.
+    fn is_account_of_other(&self, sockaddr: SocketAddr, nick: &str) -> bool {
+        let own = self
+            .entries
+            .get(&sockaddr)
+            .and_then(|entry| entry.account.as_deref())
+            .is_some_and(|account| account.eq_ignore_ascii_case(nick));
+        !own && self.account_names.contains(&accounts::account_name(nick))
+    }
+
+    fn next_guest_nick(&mut self) -> String {
+        loop {
+            self.guest_counter = self.guest_counter.wrapping_add(1);
+            let nick = format!("{}{}", GUEST_NICK_PREFIX, self.guest_counter);
+            if !self.is_nick_taken(&nick) {
+                return nick;
+            }
+        }
+    }
+
+    fn is_nick_taken(&self, nick: &str) -> bool {
+        self.entries
+            .values()
+            .any(|entry| entry.nick.eq_ignore_ascii_case(nick))
+    }
+
+    fn change_nick(&mut self, sockaddr: SocketAddr, nick: String) {
+        let Some(current) = self.entries.get(&sockaddr).map(|e| e.nick.clone()) else {
+            return;
+        };
+        if !is_valid_name(&nick, MAX_NICK_LEN) {
+            let msg = format!(
+                "Invalid nickname '{}'. Use 1 to {} letters, digits, '_' or '-'",
+                nick, MAX_NICK_LEN
+            );
+            self.send_to_user(
+                sockaddr,
+                SerializedMessage::from_info(InfoKind::NickInvalid, &msg),
+            );
+        } else if (current != nick && self.is_nick_taken(&nick))
+            || self.is_account_of_other(sockaddr, &nick)
+        {
+            let msg = format!("Nickname '{}' is already taken", nick);
+            self.send_to_user(
+                sockaddr,
+                SerializedMessage::from_info(InfoKind::NickTaken, &msg),
+            );
+        } else if let Some(entry) = self.entries.get_mut(&sockaddr) {
+            entry.set_nick(nick);
+            info!(parent: &entry.span, old = %current, "changed nick");
+            let msg = format!(
+                "{}{} is now known as {}",
+                SERVER_INFO_HEADER, current, entry.nick
+            );
+            let msg = SerializedMessage::from_string(&msg);
+            for entry in self.entries.values() {
+                entry.outbox.send(msg.clone());
+            }
+        }
+    }
+
+    fn join_room(&mut self, sockaddr: SocketAddr, room: String) {
+        if !is_valid_name(&room, MAX_ROOM_NAME_LEN) {
+            let msg = format!(
+                "Invalid room name '{}'. Use 1 to {} letters, digits, '_' or '-'",
+                room, MAX_ROOM_NAME_LEN
+            );
+            self.send_to_user(
+                sockaddr,
+                SerializedMessage::from_info(InfoKind::RoomInvalid, &msg),
+            );
+            return;
+        }
+        let Some(entry) = self.entries.get_mut(&sockaddr) else {
+            return;
+        };
+        if entry.room == room {
+            return;
+        }
+        let old_room = std::mem::replace(&mut entry.room, room);
+        let nick = entry.nick.clone();
+        self.rooms.leave(&old_room, sockaddr);
+        self.send_to_room(
+            &old_room,
+            &format!("{}{} left the room", SERVER_INFO_HEADER, nick),
+        );
+        let room = self.entries[&sockaddr].room.clone();
+        self.rooms.join(&room, sockaddr);
+        for entry in self.history.room(&room) {
+            self.send_to_user(sockaddr, entry.serialize());
+        }
+        self.send_to_room(
+            &room,
+            &format!("{}{} joined the room", SERVER_INFO_HEADER, nick),
+        );
+    }
+
+    fn leave_room(&mut self, sockaddr: SocketAddr) {
+        let Some(entry) = self.entries.get(&sockaddr) else {
+            return;
+        };
+        if entry.room == DEFAULT_ROOM {
+            let msg = format!("You are already in the {}", DEFAULT_ROOM);
+            self.send_to_user(
+                sockaddr,
+                SerializedMessage::from_info(InfoKind::RoomInvalid, &msg),
+            );
+        } else {
+            self.join_room(sockaddr, DEFAULT_ROOM.to_string());
+        }
+    }
+
+    fn find_by_nick(&self, nick: &str) -> Option<(SocketAddr, &Entry)> {
+        self.entries
+            .iter()
+            .find(|(_, entry)| entry.nick.eq_ignore_ascii_case(nick))
+            .map(|(sockaddr, entry)| (*sockaddr, entry))
+    }
+
+    fn send_direct_msg(&self, sockaddr: SocketAddr, nick: String, txt: String) {
+        let Some(sender) = self.entries.get(&sockaddr) else {
+            return;
+        };
+        if let Some(until) = sender.muted_until.filter(|_| sender.is_muted()) {
+            let msg = format!(
+                "You are muted for another {}",
+                moderation::format_duration(until - Instant::now())
+            );
+            self.send_to_user(
+                sockaddr,
+                SerializedMessage::from_info(InfoKind::Muted, &msg),
+            );
+            return;
+        }
+        let Some((target_sockaddr, target)) = self.find_by_nick(&nick) else {
+            let msg = format!("User '{}' not found", nick);
+            self.send_to_user(
+                sockaddr,
+                SerializedMessage::from_info(InfoKind::UserNotFound, &msg),
+            );
+            return;
+        };
+        let msg = SerializedMessage::from_whisper(&sender.nick, &target.nick, &txt);
+        if target_sockaddr != sockaddr {
+            let msg = msg.clone();
+            self.send_to_user(target_sockaddr, msg);
+        }
+        self.send_to_user(sockaddr, msg);
+    }
+
+    fn send_room_list_to_user(&self, sockaddr: SocketAddr) {
+        let rooms = self
+            .rooms
+            .names()
+            .into_iter()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        self.send_to_user(sockaddr, SerializedMessage::from_room_list(&rooms));
+    }
+
+    fn send_to_room(&self, room: &str, txt: &str) {
+        let msg = SerializedMessage::from_room_text(None, room, txt);
+        for entry in self
+            .rooms
+            .members(room)
+            .filter_map(|sockaddr| self.entries.get(sockaddr))
+        {
+            entry.outbox.send(msg.clone());
+        }
+    }
+
+    fn send_to_user(&self, sockaddr: SocketAddr, msg: SerializedMessage) {
+        if let Some(entry) = self.entries.get(&sockaddr) {
+            entry.outbox.send(msg);
+        }
+    }
+
+    fn send_cmd_error(&self, sockaddr: SocketAddr, err: CmdError) {
+        let info_kind = match err {
+            CmdError::UnknownCommand(_) => InfoKind::UnknownCommand,
+            CmdError::MissingArgument { .. }
+            | CmdError::TooManyArguments(_)
+            | CmdError::InvalidArgument { .. }
+            | CmdError::UnterminatedQuote => InfoKind::InvalidCommand,
+        };
+        self.send_to_user(
+            sockaddr,
+            SerializedMessage::from_info(info_kind, &err.to_string()),
+        );
+    }
+
+    fn send_count_to_user(&self, sockaddr: SocketAddr) {
+        let user_count = self.entries.len() as u32;
+        self.send_to_user(sockaddr, SerializedMessage::from_user_count(user_count));
+    }
+
+    fn send_help_to_user(&self, sockaddr: SocketAddr, cmd_name: Option<String>) {
+        let Some(entry) = self.entries.get(&sockaddr) else {
+            return;
+        };
+        let help = match cmd_name {
+            None => commands::help(entry.role),
+            Some(name) => match commands::find(&name) {
+                Some(spec) if spec.permission <= entry.role => spec.help_line(),
+                _ => {
+                    self.send_cmd_error(sockaddr, CmdError::UnknownCommand(name));
+                    return;
+                }
+            },
+        };
+        self.send_to_user(sockaddr, SerializedMessage::from_help_string(&help));
+    }
+
+    fn run_command(&mut self, sockaddr: SocketAddr, cmd: Cmd) {
+        let Some(role) = self.entries.get(&sockaddr).map(|entry| entry.role) else {
+            return;
+        };
+        let Some(spec) = commands::find(cmd.name()) else {
+            self.send_cmd_error(sockaddr, CmdError::UnknownCommand(cmd.name().to_string()));
+            return;
+        };
+        if spec.permission > role {
+            let msg = format!(
+                "You do not have the permission to use {}{}",
+                CMD_PREFIX, spec.name
+            );
+            self.send_to_user(
+                sockaddr,
+                SerializedMessage::from_info(InfoKind::PermissionDenied, &msg),
+            );
+            return;
+        }
+        (spec.handler)(self, sockaddr, cmd);
+    }
+
+    fn broadcast_msg(&mut self, txt: String, sockaddr: SocketAddr) {
+        let Some(sender) = self.entries.get(&sockaddr) else {
+            return;
+        };
+        if sender.is_muted() {
+            return;
+        }
+        self.total_messages += 1;
+        self.seq += 1;
+        let room = &sender.room;
+        let prev_seq = self.history.last_seq_in(room);
+        let history_entry = HistoryEntry::now(self.seq, prev_seq, room, &sender.nick, &txt);
+        if let Some(chat_log) = &self.chat_log {
+            if chat_log.send(history_entry.clone()).is_err() {
+                error!("chat log is closed, message not persisted");
+            }
+        }
+        self.history.push(history_entry);
+        let seq = Some(self.seq);
+        let msg =
+            SerializedMessage::from_room_text(seq, room, &format!("{}: {}", sender.nick, txt));
+        let own_msg = SerializedMessage::from_room_text(seq, room, &format!("You: {}", txt));
+        for (key, entry) in self
+            .rooms
+            .members(room)
+            .filter_map(|k| self.entries.get(k).map(|entry| (*k, entry)))
+        {
+            let msg = if key == sockaddr { &own_msg } else { &msg };
+            entry.outbox.send(msg.clone());
         }
     }
 
-    fn send_info_msg(&mut self, sockaddr: SocketAddr, info_kind: InfoKind) {
+    fn send_info_msg(&mut self, sockaddr: SocketAddr, info_kind: InfoKind, text: String) {
         match info_kind {
             InfoKind::MessageTooLong => {
-                if let Some(entry) = self.entries.get(&sockaddr).map(Entry::get_weak_stream) {
-                    spawn(async move {
-                        entry.write_all(|| {
-                                let msg = format!(
-                                    "{}Your message is too long. Maximum allowed lenght in bytes is {}",
-                                    SERVER_INFO_HEADER, MAX_MSG_LEN
-                                );
-                                SerializedMessage::from_string(&msg)
-                            })
-                            .await;
-                    });
-                }
+                let msg = format!(
+                    "{}Your message is too long. Maximum allowed lenght in bytes is {}",
+                    SERVER_INFO_HEADER, self.config.max_msg_len
+                );
+                self.send_to_user(sockaddr, SerializedMessage::from_string(&msg));
             }
-            InfoKind::ServerFull => {
-                if let Some(entry) = self.entries.remove(&sockaddr) {
-                    spawn(async move {
-                        entry.write_all(|| {
-                            let msg = format!(
-                                "{}Server has reached max number of connections {}. Refusing the connection.",
-                                SERVER_INFO_HEADER,
-                                MAX_CONNECTIONS
-                            );
-                            SerializedMessage::from_string(&msg)
-                        }).await;
-                    });
+            // Sent directly by the command handlers
+            InfoKind::NickTaken
+            | InfoKind::NickInvalid
+            | InfoKind::RoomInvalid
+            | InfoKind::UserNotFound
+            | InfoKind::UnknownCommand
+            | InfoKind::InvalidCommand
+            | InfoKind::PermissionDenied
+            | InfoKind::NotAuthenticated
+            | InfoKind::ShuttingDown
+            | InfoKind::Kicked
+            | InfoKind::Banned
+            | InfoKind::Announcement => (),
+            // Sent by the connection tasks
+            InfoKind::Throttled | InfoKind::Muted => {
+                self.send_to_user(sockaddr, SerializedMessage::from_info(info_kind, &text));
+            }
+            // Sent to the queued connections, before they are registered
+            InfoKind::ServerFull => (),
+            InfoKind::Disconnected => {
+                if let Some(entry) = self.remove_entry(sockaddr) {
+                    // Close only once the client has been told why
+                    entry.close_with(SerializedMessage::from_info(info_kind, &text));
                 }
             }
         }
     }
 
+    /// Tells every client that the server is going away and closes its connection,
+    /// once the messages already queued for it are written.
+    async fn shutdown(&mut self) {
+        // Dropping the sender lets the chat log sync and stop
+        self.chat_log = None;
+        self.capacity.close();
+        let notice =
+            SerializedMessage::from_info(InfoKind::ShuttingDown, "Server is shutting down");
+        let writers = self
+            .entries
+            .drain()
+            .map(|(_, entry)| {
+                entry.close_with(notice.clone());
+                entry.writer
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            let _ = writer.await;
+        }
+    }
+
     fn handle_message(&mut self, conn_msg: ConnMsg) {
         let ConnMsg { msg, sockaddr } = conn_msg;
+        let Some(auth) = self.entries.get(&sockaddr).map(|entry| entry.auth) else {
+            return;
+        };
+        if auth != AuthState::Authenticated
+            && matches!(
+                msg,
+                ParsedMsg::Text(_) | ParsedMsg::Command(_) | ParsedMsg::BadCommand(_)
+            )
+        {
+            self.send_to_user(
+                sockaddr,
+                SerializedMessage::from_info(InfoKind::NotAuthenticated, "Log in first"),
+            );
+            return;
+        }
         match msg {
-            ParsedMsg::UserCount(_) | ParsedMsg::Help(_) => (), // Clients cannot send these
-            ParsedMsg::Command(cmd) => match cmd {
-                Cmd::UserCount => self.send_count_to_user(sockaddr),
-                Cmd::Help => self.send_help_to_user(sockaddr),
-            },
+            // Clients cannot send these
+            ParsedMsg::UserCount(_)
+            | ParsedMsg::Help(_)
+            | ParsedMsg::RoomText { .. }
+            | ParsedMsg::RoomList(_)
+            | ParsedMsg::Whisper { .. }
+            | ParsedMsg::History { .. }
+            | ParsedMsg::AuthResponse(..)
+            | ParsedMsg::Refused { .. }
+            // Handled by the connection tasks
+            | ParsedMsg::Ping(_)
+            | ParsedMsg::Pong(_) => (),
+            ParsedMsg::AuthRequest {
+                kind,
+                user,
+                password,
+            } => self.authenticate(sockaddr, kind, user, password),
+            ParsedMsg::Command(cmd) => self.run_command(sockaddr, cmd),
+            ParsedMsg::BadCommand(err) => self.send_cmd_error(sockaddr, err),
             ParsedMsg::Text(txt) => self.broadcast_msg(txt, sockaddr),
-            ParsedMsg::Info(info_kind) => self.send_info_msg(sockaddr, info_kind),
+            ParsedMsg::Info(info_kind, text) => self.send_info_msg(sockaddr, info_kind, text),
         };
     }
 }
 
 async fn connections_task(
+    mut connections: Connections,
     mut conn_recv: Receiver<Connection>,
     mut msg_recv: Receiver<ConnMsg>,
-) -> ! {
-    let mut connections = Connections::default();
+    mut auth_recv: Receiver<AuthOutcome>,
+    mut admin_recv: Receiver<AdminRequest>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
     loop {
+        // Connections are polled first so that a Push is always handled before the
+        // messages of that connection. A Pop is queued only after all the messages
+        // of its connection, so pending messages are drained before handling it.
         tokio::select! {
+            biased;
+            _ = &mut shutdown => {
+                // The messages already received are still delivered
+                while let Ok(msg) = msg_recv.try_recv() {
+                    connections.handle_message(msg);
+                }
+                connections.shutdown().await;
+                return;
+            },
             conn = conn_recv.recv() => {
                 if let Some(conn) = conn {
+                    if let Connection::Pop(_) = conn {
+                        while let Ok(msg) = msg_recv.try_recv() {
+                            connections.handle_message(msg);
+                        }
+                    }
                     connections.handle_conn(conn).await;
                 }
             },
+            outcome = auth_recv.recv() => {
+                if let Some(outcome) = outcome {
+                    connections.handle_auth_outcome(outcome);
+                }
+            },
             msg = msg_recv.recv() => {
                 if let Some(msg) = msg {
                     connections.handle_message(msg);
                 }
-            }
+            },
+            // Disabled when there is no admin socket
+            Some(request) = admin_recv.recv() => connections.handle_admin(request),
         }
     }
 }
@@ -240,232 +815,660 @@ struct Server {
     listener: TcpListener,
     conn_sender: Sender<Connection>,
     msg_sender: Sender<ConnMsg>,
+    config: ConfigWatch,
+    gate: Gate,
+    capacity: Capacity,
+    metrics: Arc<Metrics>,
 }
 
 impl Server {
     async fn new(
-        ip: &str,
-        port: u16,
+        config: ConfigWatch,
         conn_sender: Sender<Connection>,
         msg_sender: Sender<ConnMsg>,
+        gate: Gate,
+        capacity: Capacity,
+        metrics: Arc<Metrics>,
     ) -> Self {
-        let listener = TcpListener::bind(format!("{}:{}", ip, port))
-            .await
-            .expect("No client");
+        let addr = (config.borrow().listen_ip, config.borrow().port);
+        let listener = TcpListener::bind(addr).await.expect("No client");
         Self {
             listener,
             conn_sender,
             msg_sender,
+            config,
+            gate,
+            capacity,
+            metrics,
         }
     }
 
-    async fn listen_for_conn(&self) -> (OwnedReadHalf, OwnedWriteHalf, SocketAddr) {
-        let (stream, sockaddr) = self
-            .listener
+    async fn listen_for_conn(&self) -> (TcpStream, SocketAddr) {
+        self.listener
             .accept()
             .await
-            .expect("Cannot accept connection");
-        let (reader, writer) = stream.into_split();
-        (reader, writer, sockaddr)
+            .expect("Cannot accept connection")
+    }
+
+    /// Registers the connection if the server has room for it. Otherwise the connection
+    /// waits in the queue, or is refused with the time to wait before trying again.
+    async fn open_conn(
+        self: &Arc<Self>,
+        sockaddr: SocketAddr,
+        reader: StreamReader,
+        writer: StreamWriter,
+        ticket: Ticket,
+    ) {
+        match self.capacity.enter() {
+            Entrance::Admitted(slot) => {
+                self.register(sockaddr, reader, writer, ticket, slot).await;
+            }
+            Entrance::Queued(waiting) => {
+                let server = Arc::clone(self);
+                spawn(
+                    server
+                        .wait_for_slot(sockaddr, reader, writer, ticket, waiting)
+                        .instrument(info_span!("conn", addr = %sockaddr)),
+                );
+            }
+            Entrance::Refused => {
+                self.metrics.refused_connections_total.inc();
+                info!(addr = %sockaddr, "server full, refused connection");
+                let retry_after = self.config.borrow().retry_after;
+                spawn(refuse(writer, retry_after));
+            }
+        }
+    }
+
+    /// Keeps a queued connection until it gets a slot, or hangs up.
+    async fn wait_for_slot(
+        self: Arc<Self>,
+        sockaddr: SocketAddr,
+        mut reader: StreamReader,
+        mut writer: StreamWriter,
+        ticket: Ticket,
+        mut waiting: Waiting,
+    ) {
+        info!("server full, queued connection");
+        let notice = SerializedMessage::from_info(
+            InfoKind::ServerFull,
+            "Server is full, waiting for a free slot",
+        );
+        if let Err(e) = write_msg(&mut writer, &notice).await {
+            debug!(error = %e, "cannot write to stream");
+            return;
+        }
+        // What the client sends meanwhile is kept until it is registered, reading on to see it
+        // hang up. It has no reason to send more than a message before being let in.
+        let max_len = self.config.borrow().max_msg_len;
+        let mut early = Vec::new();
+        let slot = loop {
+            tokio::select! {
+                slot = waiting.slot() => break slot,
+                read = reader.read_buf(&mut early) => match read {
+                    Ok(0) | Err(_) => break None,
+                    Ok(_) if early.len() > max_len => {
+                        debug!(len = early.len(), "too much sent while queued");
+                        break None;
+                    }
+                    Ok(_) => (),
+                },
+            }
+        };
+        let Some(slot) = slot else {
+            debug!("left the queue");
+            return;
+        };
+        let reader = Box::new(Cursor::new(early).chain(reader));
+        self.register(sockaddr, reader, writer, ticket, slot).await;
     }
 
-    async fn push_conn(&self, sockaddr: SocketAddr, stream_writer: OwnedWriteHalf) {
-        self.conn_sender
+    async fn register(
+        &self,
+        sockaddr: SocketAddr,
+        reader: StreamReader,
+        writer: StreamWriter,
+        ticket: Ticket,
+        slot: Slot,
+    ) {
+        // The reader answers the pings of the client through the outbox too
+        let outbox_config = self.config.borrow().outbox;
+        let (outbox, writer) = Outbox::spawn(
+            writer,
+            outbox_config,
+            sockaddr,
+            self.conn_sender.clone(),
+            Arc::clone(&self.metrics),
+        );
+        let (hangup, hung_up) = oneshot::channel();
+        self.push_conn(sockaddr, outbox.clone(), writer, hangup)
+            .await;
+        self.spawn_conn_task(reader, sockaddr, outbox, ticket, slot, hung_up)
+            .await;
+    }
+
+    async fn push_conn(
+        &self,
+        sockaddr: SocketAddr,
+        outbox: Outbox,
+        writer: JoinHandle<()>,
+        hangup: oneshot::Sender<()>,
+    ) {
+        // Fails only once the server is shutting down, the connection is just dropped then
+        let _ = self
+            .conn_sender
             .send(Connection::Push {
                 sockaddr,
-                stream_writer,
+                outbox,
+                writer,
+                hangup,
             })
-            .await
-            .expect("Cannot queue new connection");
+            .await;
     }
 
-    async fn spawn_conn_task(&self, stream_reader: OwnedReadHalf, sockaddr: SocketAddr) {
+    async fn spawn_conn_task(
+        &self,
+        stream_reader: StreamReader,
+        sockaddr: SocketAddr,
+        outbox: Outbox,
+        ticket: Ticket,
+        slot: Slot,
+        hung_up: oneshot::Receiver<()>,
+    ) {
         let msg_sender = self.msg_sender.clone();
         let conn_sender = self.conn_sender.clone();
+        let config = self.config.clone();
+        let metrics = Arc::clone(&self.metrics);
         spawn(async move {
-            if let Err(parse_error) = parse_messages(stream_reader, msg_sender, sockaddr).await {
+            let parsed = tokio::select! {
+                parsed = parse_messages(stream_reader, msg_sender, sockaddr, outbox, config, &metrics) => parsed,
+                // The connection was dropped by the server
+                _ = hung_up => Err(ParseError::ConnClosed(sockaddr)),
+            };
+            if let Err(parse_error) = parsed {
                 match parse_error {
                     ParseError::ConnClosed(conn) => {
-                        conn_sender
-                            .send(Connection::Pop(conn))
-                            .await
-                            .expect("Cannot send pop conncetion request");
+                        // The connections are already gone if the server is shutting down
+                        let _ = conn_sender.send(Connection::Pop(conn)).await;
+                    }
+                    ParseError::InvalidMsg => {
+                        metrics.parse_errors_total.inc();
+                        warn!("invalid message, closing the connection");
+                        let _ = conn_sender.send(Connection::Pop(sockaddr)).await;
+                    }
+                    ParseError::TooSlow(conn) => {
+                        metrics.frame_timeouts_total.inc();
+                        info!("message not received in time, closing the connection");
+                        let _ = conn_sender.send(Connection::Pop(conn)).await;
                     }
-                    ParseError::InvalidMsg => eprintln!("Invalid Msg: {:?}", parse_error),
                 }
             };
-        });
+            // The connection no longer counts for its subnet, nor for the server
+            drop(ticket);
+            drop(slot);
+        }
+        .instrument(info_span!("conn", addr = %sockaddr)));
     }
 }
 
 async fn msg_task(
-    ip: &str,
-    port: u16,
+    config: ConfigWatch,
     conn_sender: Sender<Connection>,
     msg_sender: Sender<ConnMsg>,
+    tls: Option<TlsAcceptor>,
+    gate: Gate,
+    capacity: Capacity,
+    metrics: Arc<Metrics>,
 ) -> ! {
-    let msg_handler = Server::new(ip, port, conn_sender, msg_sender).await;
+    let msg_handler =
+        Arc::new(Server::new(config, conn_sender, msg_sender, gate, capacity, metrics).await);
     loop {
-        let (stream_reader, stream_writer, sockaddr) = msg_handler.listen_for_conn().await;
-        msg_handler.push_conn(sockaddr, stream_writer).await;
-        msg_handler.spawn_conn_task(stream_reader, sockaddr).await;
+        let (stream, sockaddr) = msg_handler.listen_for_conn().await;
+        let ticket = match msg_handler.gate.admit(sockaddr.ip()) {
+            Ok(ticket) => ticket,
+            Err(refusal) => {
+                msg_handler.metrics.refused_connections_total.inc();
+                info!(addr = %sockaddr, %refusal, "refused connection");
+                continue;
+            }
+        };
+        let Some(acceptor) = tls.clone() else {
+            let (reader, writer) = stream.into_split();
+            msg_handler
+                .open_conn(sockaddr, Box::new(reader), Box::new(writer), ticket)
+                .await;
+            continue;
+        };
+        let read_timeout = msg_handler.config.borrow().read_timeout;
+        let msg_handler = Arc::clone(&msg_handler);
+        // A slow handshake must not hold back the other incoming connections
+        spawn(async move {
+            match tokio::time::timeout(read_timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let (reader, writer) = tokio::io::split(stream);
+                    msg_handler
+                        .open_conn(sockaddr, Box::new(reader), Box::new(writer), ticket)
+                        .await;
+                }
+                Ok(Err(e)) => warn!(addr = %sockaddr, error = %e, "TLS handshake failed"),
+                Err(_) => warn!(addr = %sockaddr, "TLS handshake timed out"),
+            }
+        });
+    }
+}
+
+/// Tells a connection that the server is full, then closes it.
+async fn refuse(mut writer: StreamWriter, retry_after: Duration) {
+    let retry_after_secs = u32::try_from(retry_after.as_secs()).unwrap_or(u32::MAX);
+    let refusal = SerializedMessage::from_refusal(retry_after_secs, "Server is full");
+    let written = match write_msg(&mut writer, &refusal).await {
+        Ok(()) => writer.shutdown().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        debug!(error = %e, "cannot write the refusal");
     }
 }
 
+async fn write_msg(writer: &mut StreamWriter, msg: &SerializedMessage) -> std::io::Result<()> {
+    writer.write_all(msg.as_bytes()).await?;
+    writer.flush().await
+}
+
 struct ConnMsg {
     sockaddr: SocketAddr,
     msg: ParsedMsg,
 }
 
-async fn run_server(port: u16) {
+/// Serves until `shutdown` completes, then closes every connection within the shutdown timeout.
+/// `args` are the flags `config` was loaded with, if any, for reloading it.
+async fn run_server(config: Config, args: Option<Args>, shutdown: impl Future<Output = ()>) {
+    let config = Arc::new(config);
+    let tls = config
+        .tls
+        .as_ref()
+        .map(|tls| tls.acceptor().expect("Cannot load TLS certificate"));
+    let gate = Gate::new(config.access.clone()).expect("Cannot load access list");
+    if config.access.list.is_some() {
+        spawn(reload_on_hangup(gate.clone()));
+    }
+    let (history, chat_log) = match &config.chat_log {
+        Some(chat_log) => {
+            let chat_log = ChatLog::open(chat_log.clone()).expect("Cannot open chat log");
+            let history = chat_log
+                .recover(config.history_len)
+                .expect("Cannot recover history from chat log");
+            (history, Some(chat_log.spawn()))
+        }
+        None => (History::new(config.history_len), None),
+    };
+    let (chat_log, chat_log_thread) = chat_log.unzip();
+    let accounts = config
+        .users
+        .clone()
+        .map(|path| UserStore::open(path).expect("Cannot open user store"));
+    let (auth_sender, auth_recv) = mpsc::channel(config.channel_queue_len);
     let (conn_sender, conn_recv) = mpsc::channel(MAX_SIMULATANEOUS_INCOMING_CONNECTIONS);
-    let (msg_sender, msg_recv) = mpsc::channel::<ConnMsg>(MAX_CHANNEL_QUEUE_LEN);
-    spawn(connections_task(conn_recv, msg_recv));
-    msg_task(SERVER_LISTEN_IP, port, conn_sender, msg_sender).await;
+    let (config_sender, config_recv) = watch::channel(Arc::clone(&config));
+    let connections = Connections::new(
+        history,
+        chat_log,
+        accounts,
+        auth_sender,
+        conn_sender.clone(),
+        gate.clone(),
+        ConfigSource {
+            sender: config_sender,
+            args,
+        },
+    );
+    let metrics = Arc::clone(&connections.metrics);
+    let capacity = connections.capacity.clone();
+    let metrics_server = match config.metrics_port {
+        Some(port) => {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+                .await
+                .expect("Cannot bind metrics port");
+            Some(spawn(metrics::serve(listener, Arc::clone(&metrics))))
+        }
+        None => None,
+    };
+    let (admin_sender, admin_recv) = mpsc::channel(config.channel_queue_len);
+    let admin = config.admin_socket.as_ref().map(|path| {
+        let listener = admin::bind(path).expect("Cannot bind admin socket");
+        spawn(admin::serve(listener, admin_sender))
+    });
+    let (msg_sender, msg_recv) = mpsc::channel::<ConnMsg>(config.channel_queue_len);
+    let (shutdown_sender, shutdown_recv) = oneshot::channel();
+    let connections = spawn(connections_task(
+        connections,
+        conn_recv,
+        msg_recv,
+        auth_recv,
+        admin_recv,
+        shutdown_recv,
+    ));
+    tokio::select! {
+        _ = msg_task(config_recv, conn_sender, msg_sender, tls, gate, capacity, metrics) => (),
+        () = shutdown => (),
+    }
+    // The listener went away with msg_task, no more connections are accepted
+    info!("shutting down");
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.abort();
+    }
+    if let (Some(admin), Some(path)) = (admin, &config.admin_socket) {
+        admin.abort();
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!(error = %e, "cannot remove admin socket");
+        }
+    }
+    let deadline = tokio::time::Instant::now() + config.shutdown_timeout;
+    let _ = shutdown_sender.send(());
+    if timeout_at(deadline, connections).await.is_err() {
+        warn!("timed out closing the connections");
+    }
+    if let Some(thread) = chat_log_thread {
+        if timeout_at(deadline, spawn_blocking(move || thread.join()))
+            .await
+            .is_err()
+        {
+            warn!("timed out syncing the chat log");
+        }
+    }
+}
+
+/// Completes on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!(error = %e, "cannot listen for SIGINT");
+            std::future::pending::<()>().await;
+        }
+    };
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                let _ = terminate.recv().await;
+            }
+            Err(e) => {
+                error!(error = %e, "cannot listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        () = interrupt => (),
+        () = terminate => (),
+    }
+}
+
+/// Reloads the access list on SIGHUP.
+async fn reload_on_hangup(gate: Gate) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!(error = %e, "cannot listen for SIGHUP");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match gate.reload() {
+            Ok(()) => info!("access list reloaded"),
+            Err(e) => error!(error = %e, "cannot reload access list"),
+        }
+    }
+}
+
+#[must_use]
+fn auth_status_text(status: AuthStatus) -> &'static str {
+    match status {
+        AuthStatus::Required => "Log in or register to chat",
+        AuthStatus::Ok => "Logged in",
+        AuthStatus::InvalidCredentials => "Invalid user or password",
+        AuthStatus::UserExists => "User already registered",
+        AuthStatus::InvalidUser => "Invalid user name. Use letters, digits, '_' or '-'",
+        AuthStatus::InvalidPassword => "Invalid password. Use 8 to 128 characters",
+        AuthStatus::AlreadyLoggedIn => "Already logged in",
+    }
 }
 
 #[tokio::main]
 async fn main() {
-    run_server(SERVER_PORT).await;
+    let args = Args::parse();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = config.log.init() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    run_server(config, Some(args), shutdown_signal()).await;
 }
 
 #[derive(Debug)]
 enum ParseError {
     ConnClosed(SocketAddr),
     InvalidMsg,
-}
-
-macro_rules! or_close {
-    ($stream:expr, $sockaddr:expr, $method:ident, with_timeout) => {
-        match tokio::time::timeout(READ_TIMEOUT_MS, $stream.$method()).await {
-            Ok(res) => res.map_err(|_| ParseError::ConnClosed($sockaddr)),
-            Err(_) => Err(ParseError::ConnClosed($sockaddr)),
-        }
-    };
-    ($stream:expr, $sockaddr:expr, $method:ident, $arg:expr, with_timeout) => {
-        match tokio::time::timeout(READ_TIMEOUT_MS, $stream.$method($arg)).await {
-            Ok(res) => res.map_err(|_| ParseError::ConnClosed($sockaddr)),
-            Err(_) => Err(ParseError::ConnClosed($sockaddr)),
-        }
-    };
-    ($stream:expr, $sockaddr:expr, $method:ident) => {
-        $stream
-            .$method()
-            .await
-            .map_err(|_| ParseError::ConnClosed($sockaddr))
-    };
-    ($stream:expr, $sockaddr:expr, $method:ident, $arg:expr) => {
-        $stream
-            .$method($arg)
-            .await
-            .map_err(|_| ParseError::ConnClosed($sockaddr))
-    };
+    /// A message was not received within the read timeout
+    TooSlow(SocketAddr),
 }
 
 async fn parse_messages(
-    mut stream: OwnedReadHalf,
+    mut stream: StreamReader,
     sender: Sender<ConnMsg>,
     sockaddr: SocketAddr,
+    outbox: Outbox,
+    mut updates: ConfigWatch,
+    metrics: &Metrics,
 ) -> Result<(), ParseError> {
-    enum State {
-        ReadHeader,
-        ReadPayload,
-        DiscardMessage(usize),
-    }
-    let mut state = State::ReadHeader;
-    let mut buf = Vec::with_capacity(RESERVED_MSG_LEN);
-    let mut size = 0;
+    let mut config = Arc::clone(&updates.borrow_and_update());
+    let mut decoder = FrameDecoder::new(config.max_msg_len);
+    let mut chunk = [0; RESERVED_MSG_LEN];
+    let mut limiter = RateLimiter::new(config.rate_limit, Instant::now());
+    // The tokens of the pings are the microseconds since then
+    let started = Instant::now();
+    let mut missed_heartbeats = 0;
+    // Of the latest message that was not a heartbeat
+    let mut active = started;
+    // The whole message is due then, once its first byte arrived
+    let mut deadline = started;
     loop {
-        or_close!(stream, sockaddr, readable)?;
-        match state {
-            State::ReadHeader => {
-                size = or_close!(stream, sockaddr, read_u32)?;
-                let msg_type = or_close!(stream, sockaddr, read_u8, with_timeout)?;
-                if size > MAX_MSG_LEN as u32 {
-                    sender
-                        .send(ConnMsg {
-                            sockaddr,
-                            msg: ParsedMsg::from_info(InfoKind::MessageTooLong),
-                        })
-                        .await
-                        .expect("Cannot send reply");
-                    state =
-                        State::DiscardMessage(size as usize - SerializedMessage::size_of_header());
-                    buf.resize(256, 0);
-                } else if size <= SerializedMessage::size_of_header() as u32 {
-                    // This message is malformed for some reason
-                    // TODO: log it
-                    buf.clear();
-                    size = 0;
-                    state = State::ReadHeader;
-                } else {
-                    size.to_be_bytes().into_iter().for_each(|b| buf.push(b));
-                    buf.push(msg_type);
-                    buf.resize(size as usize, 0);
-                    state = State::ReadPayload;
-                }
+        let in_frame = decoder.in_frame();
+        let heartbeat_due = Instant::now() + config.heartbeat_interval;
+        let idle_deadline = config.idle_timeout.map(|timeout| active + timeout);
+        let wake_up = if in_frame {
+            deadline
+        } else {
+            idle_deadline.map_or(heartbeat_due, |idle| idle.min(heartbeat_due))
+        };
+        let read = match timeout_at(wake_up.into(), stream.read(&mut chunk)).await {
+            Ok(Ok(0) | Err(_)) => return Err(ParseError::ConnClosed(sockaddr)),
+            Ok(Ok(read)) => read,
+            Err(_) if in_frame => return Err(ParseError::TooSlow(sockaddr)),
+            Err(_) if idle_deadline.is_some_and(|idle| idle <= Instant::now()) => {
+                metrics.idle_timeouts_total.inc();
+                info!("idle for too long, closing the connection");
+                outbox.send(SerializedMessage::from_info(
+                    InfoKind::Disconnected,
+                    "Disconnected for being idle for too long",
+                ));
+                return Err(ParseError::ConnClosed(sockaddr));
             }
-            State::ReadPayload => {
-                let _ = or_close!(
-                    stream,
-                    sockaddr,
-                    read_exact,
-                    &mut buf[SerializedMessage::size_of_header()..],
-                    with_timeout
-                )?;
-                let msg = ParsedMsg::from_bytes(&buf[..size as usize])
-                    .ok_or_else(|| ParseError::InvalidMsg)?;
-                if let ParsedMsg::Info(ref i) = msg {
-                    println!(
-                        "Invalid message of type INFO from client: {:?}. Ignoring.",
-                        i
-                    );
-                } else {
-                    buf.clear();
-                    size = 0;
-                    state = State::ReadHeader;
-                    sender
-                        .send(ConnMsg { sockaddr, msg })
-                        .await
-                        .expect("Cannot send reply");
-                }
+            Err(_) if missed_heartbeats >= config.missed_heartbeats => {
+                metrics.heartbeat_timeouts_total.inc();
+                info!(
+                    missed_heartbeats,
+                    "no answer to the pings, closing the connection"
+                );
+                return Err(ParseError::ConnClosed(sockaddr));
             }
-            State::DiscardMessage(to_discard) => match stream.read_exact(&mut buf).await {
-                Ok(bytes) => {
-                    if bytes == to_discard {
-                        buf.clear();
-                        size = 0;
-                        state = State::ReadHeader;
-                    } else {
-                        state = State::DiscardMessage(to_discard - bytes);
+            Err(_) => {
+                missed_heartbeats += 1;
+                let token = started.elapsed().as_micros() as u64;
+                outbox.send(SerializedMessage::from_ping(token));
+                continue;
+            }
+        };
+        let received = Instant::now();
+        missed_heartbeats = 0;
+        metrics.received_bytes_total.add(read as u64);
+        if updates.has_changed().unwrap_or(false) {
+            config = Arc::clone(&updates.borrow_and_update());
+            limiter.reconfigure(config.rate_limit, Instant::now());
+            decoder.set_max_len(config.max_msg_len);
+        }
+        decoder.push(&chunk[..read]);
+        let mut decoded = false;
+        while let Some(frame) = decoder.next_frame() {
+            decoded = true;
+            let size = match frame {
+                Ok(frame) => frame.len() as u32,
+                Err(FrameError::TooShort(size) | FrameError::TooLong(size)) => size,
+            };
+            // The heartbeats are answered whatever the rate of the other messages
+            let heartbeat = frame.is_ok_and(|frame| {
+                frame
+                    .get(SerializedMessage::size_of_len())
+                    .is_some_and(|&msg_type| {
+                        msg_type == MsgType::Ping as u8 || msg_type == MsgType::Pong as u8
+                    })
+            });
+            let verdict = if heartbeat {
+                Verdict::Allow
+            } else {
+                limiter.check(size as usize, Instant::now())
+            };
+            let drop_msg = verdict != Verdict::Allow;
+            if drop_msg {
+                metrics.throttled_messages_total.inc();
+            }
+            if let Some((kind, text)) = verdict.notice(&config.rate_limit) {
+                sender
+                    .send(ConnMsg {
+                        sockaddr,
+                        msg: ParsedMsg::Info(kind, text),
+                    })
+                    .await
+                    .map_err(|_| ParseError::ConnClosed(sockaddr))?;
+            }
+            if verdict == Verdict::Disconnect {
+                return Err(ParseError::ConnClosed(sockaddr));
+            }
+            if size > SerializedMessage::size_of_header() as u32 {
+                metrics.messages_received_total.inc();
+                metrics.message_size_bytes.observe(f64::from(size));
+            }
+            let frame = match frame {
+                Err(FrameError::TooLong(_)) => {
+                    metrics.oversize_messages_total.inc();
+                    if !drop_msg {
+                        sender
+                            .send(ConnMsg {
+                                sockaddr,
+                                msg: ParsedMsg::from_info(InfoKind::MessageTooLong),
+                            })
+                            .await
+                            .map_err(|_| ParseError::ConnClosed(sockaddr))?;
                     }
+                    continue;
                 }
-                Err(_) => {
-                    return Err(ParseError::ConnClosed(sockaddr));
+                Ok(frame) if size > SerializedMessage::size_of_header() as u32 => frame,
+                // This message is malformed for some reason
+                _ => {
+                    metrics.parse_errors_total.inc();
+                    debug!(size, "ignoring message shorter than its header");
+                    continue;
                 }
-            },
-        }
+            };
+            let msg = ParsedMsg::from_bytes(frame).ok_or(ParseError::InvalidMsg)?;
+            if !matches!(msg, ParsedMsg::Ping(_) | ParsedMsg::Pong(_)) {
+                active = Instant::now();
+            }
+            match msg {
+                ParsedMsg::Info(kind, _) => {
+                    metrics.parse_errors_total.inc();
+                    warn!(?kind, "ignoring message of type INFO from client");
+                }
+                _ if drop_msg => (),
+                ParsedMsg::Ping(token) => outbox.send(SerializedMessage::from_pong(token)),
+                // Any message tells the client is there
+                ParsedMsg::Pong(_) => (),
+                msg => sender
+                    .send(ConnMsg { sockaddr, msg })
+                    .await
+                    .map_err(|_| ParseError::ConnClosed(sockaddr))?,
+            }
+        }
+        // The rest is the start of the next message
+        if decoded || !in_frame {
+            deadline = received + config.read_timeout;
+        }
     }
 }
 
 #[cfg(test)]
 mod server_tests {
-    use std::time::Duration;
-    use tokio::{io::AsyncWriteExt, net::TcpStream, time::sleep};
+    use tokio::{io::AsyncWriteExt, net::TcpStream, task::JoinSet, time::sleep};
 
     const SERVER_IP: &str = "127.0.0.1";
 
     use super::*;
+    use access::AccessConfig;
+    use async_chat::admin::{Reply, Response};
+    use async_chat::message::MAX_MSG_LEN;
+    use chatlog::ChatLogConfig;
+    use ratelimit::RateLimitConfig;
+    use std::future::pending;
+
+    async fn send_msg(client: &mut (impl AsyncWrite + Unpin), txt: &str) {
+        client
+            .write_all(SerializedMessage::from_string(txt).as_bytes())
+            .await
+            .expect("Cannot send message");
+        client.flush().await.expect("Cannot flush stream");
+    }
+
+    async fn read_msg(client: &mut (impl AsyncRead + Unpin)) -> ParsedMsg {
+        let size = client.read_u32().await.expect("Cannot read size");
+        let mut buf = size.to_be_bytes().to_vec();
+        buf.resize(size as usize, 0);
+        client
+            .read_exact(&mut buf[SerializedMessage::size_of_len()..])
+            .await
+            .expect("Cannot read message");
+        ParsedMsg::from_bytes(&buf).expect("Fail to parse message")
+    }
+
+    /// Connects to a server without accounts, which greets with the auth outcome.
+    async fn connect(port: u16) -> TcpStream {
+        let mut client = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        let ParsedMsg::AuthResponse(AuthStatus::Ok, _) = read_msg(&mut client).await else {
+            panic!("Invalid greeting");
+        };
+        client
+    }
 
     #[tokio::test]
     async fn test_simple_msg() {
         let port = 60_001;
-        spawn(run_server(port));
+        spawn(run_server(
+            Config {
+                port,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
         sleep(Duration::from_millis(500)).await;
 
-        let mut client = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
-            .await
-            .expect("Cannot connect to server");
+        let mut client = connect(port).await;
         let msg = SerializedMessage::from_string("Hello I am a client!");
 
         client.writable().await.unwrap();
@@ -484,12 +1487,17 @@ mod server_tests {
     #[tokio::test]
     async fn test_message_too_long() {
         let port = 60_003;
-        spawn(run_server(port));
+        spawn(run_server(
+            Config {
+                port,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
         sleep(Duration::from_millis(500)).await;
 
-        let mut client = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
-            .await
-            .expect("Cannot connect to server");
+        let mut client = connect(port).await;
         let s = (0..MAX_MSG_LEN + 1).map(|_| 'a').collect::<String>();
         let msg = SerializedMessage::from_string(&s);
 
@@ -509,13 +1517,18 @@ mod server_tests {
     #[tokio::test]
     async fn test_multi_conn() {
         let port = 60_002;
-        spawn(run_server(port));
+        spawn(run_server(
+            Config {
+                port,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
         sleep(Duration::from_millis(500)).await;
 
         spawn(async move {
-            let mut client = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
-                .await
-                .expect("Cannot connect to server");
+            let mut client = connect(port).await;
             sleep(Duration::from_millis(1000)).await;
             let msg = SerializedMessage::from_string("Hello I am a client!");
 
@@ -525,9 +1538,7 @@ mod server_tests {
                 .await
                 .expect("Cannot send message");
         });
-        let mut client = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
-            .await
-            .expect("Cannot connect to server");
+        let mut client = connect(port).await;
 
         let mut v = vec![];
         client.readable().await.unwrap();
@@ -541,12 +1552,17 @@ mod server_tests {
     #[tokio::test]
     async fn test_ask_count() {
         let port = 60_004;
-        spawn(run_server(port));
+        spawn(run_server(
+            Config {
+                port,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
         sleep(Duration::from_millis(500)).await;
 
-        let mut client = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
-            .await
-            .expect("Cannot connect to server");
+        let mut client = connect(port).await;
         let msg = SerializedMessage::from_string("/count");
 
         client.writable().await.unwrap();
@@ -564,4 +1580,1255 @@ mod server_tests {
         };
         assert_eq!(1, n);
     }
+
+    #[tokio::test]
+    async fn test_nick() {
+        let port = 60_005;
+        spawn(run_server(
+            Config {
+                port,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut alice = connect(port).await;
+        let mut bob = connect(port).await;
+
+        send_msg(&mut alice, "/nick alice").await;
+        let ParsedMsg::Text(txt) = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        assert!(txt.ends_with("is now known as alice"));
+
+        send_msg(&mut bob, "/nick ALICE").await;
+        let msg = read_msg(&mut bob).await;
+        assert!(matches!(msg, ParsedMsg::Info(InfoKind::NickTaken, _)));
+
+        send_msg(&mut bob, "/nick \"not a nick\"").await;
+        let msg = read_msg(&mut bob).await;
+        assert!(matches!(msg, ParsedMsg::Info(InfoKind::NickInvalid, _)));
+
+        let _ = read_msg(&mut alice).await;
+        send_msg(&mut alice, "Hello").await;
+        let ParsedMsg::RoomText { room, text, .. } = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(room, DEFAULT_ROOM);
+        assert_eq!(text, "alice: Hello");
+    }
+
+    #[tokio::test]
+    async fn test_rooms() {
+        let port = 60_006;
+        spawn(run_server(
+            Config {
+                port,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut alice = connect(port).await;
+        let mut bob = connect(port).await;
+
+        send_msg(&mut alice, "/join rust").await;
+        let ParsedMsg::RoomText { room, .. } = read_msg(&mut alice).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(room, "rust");
+        let ParsedMsg::RoomText { room, .. } = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(room, DEFAULT_ROOM);
+
+        send_msg(&mut bob, "/rooms").await;
+        let msg = read_msg(&mut bob).await;
+        assert_eq!(
+            msg,
+            ParsedMsg::RoomList(vec![DEFAULT_ROOM.to_string(), "rust".to_string()])
+        );
+
+        // Messages in the lobby do not reach the other rooms
+        send_msg(&mut bob, "Hello lobby").await;
+        let _ = read_msg(&mut bob).await;
+        send_msg(&mut alice, "Hello rust").await;
+        let ParsedMsg::RoomText { room, text, .. } = read_msg(&mut alice).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(room, "rust");
+        assert_eq!(text, "You: Hello rust");
+
+        // Back in the lobby, with what was said there meanwhile
+        send_msg(&mut alice, "/leave").await;
+        let ParsedMsg::History { room, text, .. } = read_msg(&mut alice).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(room, DEFAULT_ROOM);
+        assert_eq!(text, "Hello lobby");
+        let ParsedMsg::RoomText { room, .. } = read_msg(&mut alice).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(room, DEFAULT_ROOM);
+        send_msg(&mut alice, "/leave").await;
+        let msg = read_msg(&mut alice).await;
+        assert!(matches!(msg, ParsedMsg::Info(InfoKind::RoomInvalid, _)));
+    }
+
+    #[tokio::test]
+    async fn test_direct_msg() {
+        let port = 60_007;
+        spawn(run_server(
+            Config {
+                port,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut alice = connect(port).await;
+        let mut bob = connect(port).await;
+        let mut carol = connect(port).await;
+
+        send_msg(&mut bob, "/nick bob").await;
+        let _ = read_msg(&mut alice).await;
+        let _ = read_msg(&mut bob).await;
+        let _ = read_msg(&mut carol).await;
+
+        send_msg(&mut alice, "/msg bob psst").await;
+        let msg = read_msg(&mut bob).await;
+        let ParsedMsg::Whisper { from, to, text } = msg else {
+            panic!("Invalid msg");
+        };
+        assert!(from.starts_with(GUEST_NICK_PREFIX));
+        assert_eq!(to, "bob");
+        assert_eq!(text, "psst");
+        assert!(matches!(
+            read_msg(&mut alice).await,
+            ParsedMsg::Whisper { .. }
+        ));
+
+        send_msg(&mut alice, "/msg dave psst").await;
+        let msg = read_msg(&mut alice).await;
+        assert!(matches!(msg, ParsedMsg::Info(InfoKind::UserNotFound, _)));
+
+        // Nothing to deliver
+        for msg in ["/msg bob", "/msg bob   "] {
+            send_msg(&mut alice, msg).await;
+            let msg = read_msg(&mut alice).await;
+            assert!(matches!(msg, ParsedMsg::Info(InfoKind::InvalidCommand, _)));
+        }
+
+        // Carol receives only the public message
+        send_msg(&mut alice, "Hello").await;
+        let ParsedMsg::RoomText { text, .. } = read_msg(&mut carol).await else {
+            panic!("Invalid msg");
+        };
+        assert!(text.ends_with("Hello"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_cmd() {
+        let port = 60_008;
+        spawn(run_server(
+            Config {
+                port,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut client = connect(port).await;
+
+        send_msg(&mut client, "/cuont").await;
+        let msg = read_msg(&mut client).await;
+        assert!(matches!(msg, ParsedMsg::Info(InfoKind::UnknownCommand, _)));
+
+        send_msg(&mut client, "/join").await;
+        let msg = read_msg(&mut client).await;
+        assert!(matches!(msg, ParsedMsg::Info(InfoKind::InvalidCommand, _)));
+    }
+
+    #[tokio::test]
+    async fn test_help() {
+        let port = 60_009;
+        spawn(run_server(
+            Config {
+                port,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut client = connect(port).await;
+
+        send_msg(&mut client, "/help").await;
+        let ParsedMsg::Help(help) = read_msg(&mut client).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(help, commands::help(Role::User));
+
+        send_msg(&mut client, "/help msg").await;
+        let ParsedMsg::Help(help) = read_msg(&mut client).await else {
+            panic!("Invalid msg");
+        };
+        assert!(help.starts_with("/msg <nick> <text> -> "));
+
+        send_msg(&mut client, "/help cuont").await;
+        let msg = read_msg(&mut client).await;
+        assert!(matches!(msg, ParsedMsg::Info(InfoKind::UnknownCommand, _)));
+    }
+
+    #[tokio::test]
+    async fn test_history() {
+        let port = 60_010;
+        spawn(run_server(
+            Config {
+                port,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut alice = connect(port).await;
+        send_msg(&mut alice, "/nick alice").await;
+        let _ = read_msg(&mut alice).await;
+        for (expected_seq, txt) in [(1, "first"), (2, "second")] {
+            send_msg(&mut alice, txt).await;
+            let ParsedMsg::RoomText { seq, .. } = read_msg(&mut alice).await else {
+                panic!("Invalid msg");
+            };
+            assert_eq!(seq, Some(expected_seq));
+        }
+
+        let mut bob = connect(port).await;
+        for (expected_seq, expected) in [(1, "first"), (2, "second")] {
+            let ParsedMsg::History {
+                seq,
+                room,
+                sender,
+                text,
+                ..
+            } = read_msg(&mut bob).await
+            else {
+                panic!("Invalid msg");
+            };
+            assert_eq!(seq, expected_seq);
+            assert_eq!(room, DEFAULT_ROOM);
+            assert_eq!(sender, "alice");
+            assert_eq!(text, expected);
+        }
+        // The live messages go on from the history
+        send_msg(&mut alice, "third").await;
+        let ParsedMsg::RoomText { seq, .. } = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(seq, Some(3));
+        // The notices are not numbered
+        send_msg(&mut bob, "/join rust").await;
+        let ParsedMsg::RoomText { seq, text, .. } = read_msg(&mut alice).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(text, "You: third");
+        assert_eq!(seq, Some(3));
+        let ParsedMsg::RoomText { seq, .. } = read_msg(&mut alice).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(seq, None);
+    }
+
+    #[tokio::test]
+    async fn test_room_history() {
+        let port = 60_026;
+        spawn(run_server(
+            Config {
+                port,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut alice = connect(port).await;
+        send_msg(&mut alice, "/nick alice").await;
+        let _ = read_msg(&mut alice).await;
+        send_msg(&mut alice, "in the lobby").await;
+        let _ = read_msg(&mut alice).await;
+        send_msg(&mut alice, "/join rust").await;
+        let _ = read_msg(&mut alice).await;
+        for txt in ["in rust", "again in rust"] {
+            send_msg(&mut alice, txt).await;
+            let _ = read_msg(&mut alice).await;
+        }
+
+        let history = |msg| {
+            let ParsedMsg::History {
+                seq,
+                prev_seq,
+                room,
+                text,
+                ..
+            } = msg
+            else {
+                panic!("Invalid msg {:?}", msg);
+            };
+            (seq, prev_seq, room, text)
+        };
+        // Only the history of the room
+        let mut bob = connect(port).await;
+        assert_eq!(
+            history(read_msg(&mut bob).await),
+            (
+                1,
+                None,
+                DEFAULT_ROOM.to_string(),
+                "in the lobby".to_string()
+            )
+        );
+        send_msg(&mut bob, "/count").await;
+        assert_eq!(read_msg(&mut bob).await, ParsedMsg::UserCount(2));
+
+        // Then the history of the room joined
+        send_msg(&mut bob, "/join rust").await;
+        assert_eq!(
+            history(read_msg(&mut bob).await),
+            (2, None, "rust".to_string(), "in rust".to_string())
+        );
+        assert_eq!(
+            history(read_msg(&mut bob).await),
+            (3, Some(2), "rust".to_string(), "again in rust".to_string())
+        );
+        let ParsedMsg::RoomText { text, .. } = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        assert!(text.ends_with("joined the room"));
+    }
+
+    async fn send_auth(client: &mut TcpStream, kind: AuthKind, user: &str, password: &str) {
+        client
+            .write_all(SerializedMessage::from_auth_request(kind, user, password).as_bytes())
+            .await
+            .expect("Cannot send message");
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let port = 60_011;
+        let users = std::env::temp_dir().join(format!("async_chat_auth_{}", std::process::id()));
+        let _ = std::fs::remove_file(&users);
+        spawn(run_server(
+            Config {
+                port,
+                users: Some(users.clone()),
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut alice = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        let ParsedMsg::AuthResponse(AuthStatus::Required, _) = read_msg(&mut alice).await else {
+            panic!("Invalid greeting");
+        };
+        send_msg(&mut alice, "hello").await;
+        let ParsedMsg::Info(InfoKind::NotAuthenticated, _) = read_msg(&mut alice).await else {
+            panic!("Invalid msg");
+        };
+        send_auth(&mut alice, AuthKind::Register, "alice", "secret-pw").await;
+        let ParsedMsg::AuthResponse(AuthStatus::Ok, text) = read_msg(&mut alice).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(text, "Welcome, alice");
+
+        let mut bob = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        let _ = read_msg(&mut bob).await;
+        send_auth(&mut bob, AuthKind::Login, "alice", "wrong-pw").await;
+        let ParsedMsg::AuthResponse(AuthStatus::InvalidCredentials, _) = read_msg(&mut bob).await
+        else {
+            panic!("Invalid msg");
+        };
+        // The account names are case insensitive
+        send_auth(&mut bob, AuthKind::Login, "ALICE", "secret-pw").await;
+        let ParsedMsg::AuthResponse(AuthStatus::AlreadyLoggedIn, _) = read_msg(&mut bob).await
+        else {
+            panic!("Invalid msg");
+        };
+        send_auth(&mut bob, AuthKind::Register, "Alice", "secret-pw").await;
+        let ParsedMsg::AuthResponse(AuthStatus::UserExists, _) = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        send_auth(&mut bob, AuthKind::Register, "bob", "secret-pw").await;
+        let ParsedMsg::AuthResponse(AuthStatus::Ok, _) = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+
+        // Registered names cannot be taken by somebody else, even once they are gone
+        drop(alice);
+        let ParsedMsg::RoomText { text, .. } = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        assert!(text.contains("alice left"));
+        for nick in ["alice", "ALICE"] {
+            send_msg(&mut bob, &format!("/nick {}", nick)).await;
+            let msg = read_msg(&mut bob).await;
+            assert!(matches!(msg, ParsedMsg::Info(InfoKind::NickTaken, _)));
+        }
+        let _ = std::fs::remove_file(&users);
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        let port = 60_012;
+        let (tls, cert) = tls::tls_tests::self_signed("server_tls");
+        spawn(run_server(
+            Config {
+                port,
+                tls: Some(tls),
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).expect("Invalid certificate");
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let stream = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        let server_name = rustls::pki_types::ServerName::try_from(SERVER_IP).unwrap();
+        let mut client = connector
+            .connect(server_name, stream)
+            .await
+            .expect("TLS handshake failed");
+        let ParsedMsg::AuthResponse(AuthStatus::Ok, _) = read_msg(&mut client).await else {
+            panic!("Invalid greeting");
+        };
+        send_msg(&mut client, "over tls").await;
+        let ParsedMsg::RoomText { text, .. } = read_msg(&mut client).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(text, "You: over tls");
+
+        // A plaintext client does not get past the handshake
+        let mut client = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        send_msg(&mut client, "plaintext").await;
+        let mut buf = vec![];
+        let _ = client.read_to_end(&mut buf).await;
+        // At most a TLS alert record comes back before the connection is closed
+        const TLS_ALERT: u8 = 21;
+        assert!(buf.first().is_none_or(|b| *b == TLS_ALERT));
+    }
+
+    #[tokio::test]
+    async fn test_flood() {
+        let port = 60_013;
+        let rate_limit = RateLimitConfig {
+            msgs_per_sec: 0.5,
+            msg_burst: 2.0,
+            strikes_before_mute: 3,
+            mute_duration: Duration::from_millis(200),
+            mutes_before_disconnect: 1,
+            ..RateLimitConfig::default()
+        };
+        spawn(run_server(
+            Config {
+                port,
+                rate_limit,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut client = connect(port).await;
+        for _ in 0..2 {
+            send_msg(&mut client, "spam").await;
+            let ParsedMsg::RoomText { .. } = read_msg(&mut client).await else {
+                panic!("Invalid msg");
+            };
+        }
+        send_msg(&mut client, "spam").await;
+        let ParsedMsg::Info(InfoKind::Throttled, _) = read_msg(&mut client).await else {
+            panic!("Invalid msg");
+        };
+        // Warned only once, then muted
+        send_msg(&mut client, "spam").await;
+        send_msg(&mut client, "spam").await;
+        let ParsedMsg::Info(InfoKind::Muted, _) = read_msg(&mut client).await else {
+            panic!("Invalid msg");
+        };
+        // The heartbeats still go through
+        client
+            .write_all(SerializedMessage::from_ping(9).as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(read_msg(&mut client).await, ParsedMsg::Pong(9));
+        send_msg(&mut client, "spam").await;
+
+        // Repeat offenders get disconnected
+        sleep(Duration::from_millis(300)).await;
+        send_msg(&mut client, "spam").await;
+        let ParsedMsg::Info(InfoKind::Throttled, _) = read_msg(&mut client).await else {
+            panic!("Invalid msg");
+        };
+        send_msg(&mut client, "spam").await;
+        send_msg(&mut client, "spam").await;
+        let ParsedMsg::Info(InfoKind::Disconnected, _) = read_msg(&mut client).await else {
+            panic!("Invalid msg");
+        };
+        let mut buf = vec![];
+        assert_eq!(client.read_to_end(&mut buf).await.unwrap_or(0), 0);
+    }
+
+    #[tokio::test]
+    async fn test_conns_per_ip() {
+        let port = 60_014;
+        let access = AccessConfig {
+            max_conns_per_subnet: 1,
+            ..AccessConfig::default()
+        };
+        spawn(run_server(
+            Config {
+                port,
+                access,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let first = connect(port).await;
+        let mut second = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        let mut buf = vec![];
+        assert_eq!(second.read_to_end(&mut buf).await.unwrap_or(0), 0);
+
+        // The slot is given back once the first connection is gone
+        drop(first);
+        sleep(Duration::from_millis(100)).await;
+        let _third = connect(port).await;
+    }
+
+    #[tokio::test]
+    async fn test_server_full() {
+        let port = 60_022;
+        spawn(run_server(
+            Config {
+                port,
+                max_connections: 1,
+                wait_queue_len: 1,
+                retry_after: Duration::from_secs(7),
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let first = connect(port).await;
+        let mut queued = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        let ParsedMsg::Info(InfoKind::ServerFull, _) = read_msg(&mut queued).await else {
+            panic!("Not queued");
+        };
+        let mut refused = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        let ParsedMsg::Refused {
+            retry_after_secs, ..
+        } = read_msg(&mut refused).await
+        else {
+            panic!("Not refused");
+        };
+        assert_eq!(retry_after_secs, 7);
+        assert_closed(&mut refused).await;
+
+        // The queued connection gets the slot of the first one
+        drop(first);
+        let ParsedMsg::AuthResponse(AuthStatus::Ok, _) = read_msg(&mut queued).await else {
+            panic!("Invalid greeting");
+        };
+
+        // A connection that hangs up while waiting leaves room in the queue
+        let gone = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        sleep(Duration::from_millis(100)).await;
+        drop(gone);
+        sleep(Duration::from_millis(100)).await;
+        let mut waiting = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        let ParsedMsg::Info(InfoKind::ServerFull, _) = read_msg(&mut waiting).await else {
+            panic!("Not queued");
+        };
+
+        // Even after sending a message
+        send_msg(&mut waiting, "/count").await;
+        sleep(Duration::from_millis(100)).await;
+        drop(waiting);
+        sleep(Duration::from_millis(100)).await;
+        let mut last = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        let ParsedMsg::Info(InfoKind::ServerFull, _) = read_msg(&mut last).await else {
+            panic!("Not queued");
+        };
+
+        // What is sent while waiting is answered once let in
+        send_msg(&mut last, "/count").await;
+        drop(queued);
+        let ParsedMsg::AuthResponse(AuthStatus::Ok, _) = read_msg(&mut last).await else {
+            panic!("Invalid greeting");
+        };
+        assert_eq!(read_msg(&mut last).await, ParsedMsg::UserCount(1));
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        let port = 60_023;
+        spawn(run_server(
+            Config {
+                port,
+                heartbeat_interval: Duration::from_millis(100),
+                missed_heartbeats: 2,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut client = connect(port).await;
+        let ParsedMsg::Ping(token) = read_msg(&mut client).await else {
+            panic!("Not pinged");
+        };
+        client
+            .write_all(SerializedMessage::from_pong(token).as_bytes())
+            .await
+            .unwrap();
+        client
+            .write_all(SerializedMessage::from_ping(42).as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(read_msg(&mut client).await, ParsedMsg::Pong(42));
+
+        // Dropped once the pings go unanswered
+        let started = Instant::now();
+        let mut pings = 0;
+        while let Ok(size) = client.read_u32().await {
+            let mut buf = size.to_be_bytes().to_vec();
+            buf.resize(size as usize, 0);
+            let _ = client
+                .read_exact(&mut buf[SerializedMessage::size_of_len()..])
+                .await
+                .unwrap();
+            assert!(matches!(
+                ParsedMsg::from_bytes(&buf),
+                Some(ParsedMsg::Ping(_))
+            ));
+            pings += 1;
+        }
+        assert_eq!(pings, 2);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn test_trickle() {
+        let port = 60_024;
+        spawn(run_server(
+            Config {
+                port,
+                read_timeout: Duration::from_millis(300),
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut client = connect(port).await;
+        // Slow, but whole within the read timeout
+        let count = SerializedMessage::from_string("/count");
+        let (head, tail) = count.as_bytes().split_at(3);
+        client.write_all(head).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        client.write_all(tail).await.unwrap();
+        assert_eq!(read_msg(&mut client).await, ParsedMsg::UserCount(1));
+
+        // An oversized message is skipped up to its last byte
+        send_msg(&mut client, &"x".repeat(MAX_MSG_LEN + 100)).await;
+        send_msg(&mut client, "/count").await;
+        let ParsedMsg::Text(text) = read_msg(&mut client).await else {
+            panic!("Invalid msg");
+        };
+        assert!(text.contains("too long"));
+        assert_eq!(read_msg(&mut client).await, ParsedMsg::UserCount(1));
+
+        // The messages of the server sent by a client are skipped
+        client
+            .write_all(SerializedMessage::from_info(InfoKind::Kicked, "bye").as_bytes())
+            .await
+            .unwrap();
+        send_msg(&mut client, "/count").await;
+        assert_eq!(read_msg(&mut client).await, ParsedMsg::UserCount(1));
+
+        // Every byte comes in time, but not the whole message
+        for byte in &count.as_bytes()[..4] {
+            client.write_all(&[*byte]).await.unwrap();
+            sleep(Duration::from_millis(100)).await;
+        }
+        tokio::time::timeout(Duration::from_secs(1), assert_closed(&mut client))
+            .await
+            .expect("Connection not closed");
+    }
+
+    #[tokio::test]
+    async fn test_idle() {
+        let port = 60_025;
+        spawn(run_server(
+            Config {
+                port,
+                idle_timeout: Some(Duration::from_millis(300)),
+                heartbeat_interval: Duration::from_millis(100),
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut client = connect(port).await;
+        let started = Instant::now();
+        // Answering the pings does not make the client active
+        loop {
+            match read_msg(&mut client).await {
+                ParsedMsg::Ping(token) => client
+                    .write_all(SerializedMessage::from_pong(token).as_bytes())
+                    .await
+                    .unwrap(),
+                ParsedMsg::Info(InfoKind::Disconnected, _) => break,
+                msg => panic!("Invalid msg {:?}", msg),
+            }
+        }
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_closed(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn test_invalid_msg() {
+        let port = 60_027;
+        spawn(run_server(
+            Config {
+                port,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut alice = connect(port).await;
+        let mut bob = connect(port).await;
+        // A user count is never sent by a client, and has a 4 bytes payload
+        let mut frame = (SerializedMessage::size_of_header() as u32 + 2)
+            .to_be_bytes()
+            .to_vec();
+        frame.extend([MsgType::UserCount as u8, 0, 0]);
+        bob.write_all(&frame).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), assert_closed(&mut bob))
+            .await
+            .expect("Connection not closed");
+
+        let ParsedMsg::RoomText { text, .. } = read_msg(&mut alice).await else {
+            panic!("Invalid msg");
+        };
+        assert!(text.ends_with("left the chat"));
+        send_msg(&mut alice, "/count").await;
+        assert_eq!(read_msg(&mut alice).await, ParsedMsg::UserCount(1));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let port = 60_015;
+        let path = std::env::temp_dir().join(format!("async_chat_shutdown_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let chat_log = ChatLogConfig {
+            path: path.clone(),
+            ..ChatLogConfig::default()
+        };
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = spawn(run_server(
+            Config {
+                port,
+                chat_log: Some(chat_log.clone()),
+                shutdown_timeout: Duration::from_secs(2),
+                ..Config::default()
+            },
+            None,
+            async {
+                let _ = stopped.await;
+            },
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut alice = connect(port).await;
+        let mut bob = connect(port).await;
+        send_msg(&mut alice, "last words").await;
+        for client in [&mut alice, &mut bob] {
+            let ParsedMsg::RoomText { text, .. } = read_msg(client).await else {
+                panic!("Invalid msg");
+            };
+            assert!(text.ends_with("last words"));
+        }
+
+        stop.send(()).unwrap();
+        for client in [&mut alice, &mut bob] {
+            let ParsedMsg::Info(InfoKind::ShuttingDown, _) = read_msg(client).await else {
+                panic!("Invalid msg");
+            };
+            let mut buf = vec![];
+            assert_eq!(client.read_to_end(&mut buf).await.unwrap_or(0), 0);
+        }
+        tokio::time::timeout(Duration::from_secs(3), server)
+            .await
+            .expect("Server did not stop in time")
+            .unwrap();
+        assert!(TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .is_err());
+
+        let history = ChatLog::open(chat_log).unwrap().recover(10).unwrap();
+        let texts = history
+            .room(DEFAULT_ROOM)
+            .map(|entry| entry.text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["last words"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Connects to a server with accounts and logs in, registering the user first if needed.
+    async fn login(port: u16, kind: AuthKind, user: &str) -> TcpStream {
+        let mut client = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        let ParsedMsg::AuthResponse(AuthStatus::Required, _) = read_msg(&mut client).await else {
+            panic!("Invalid greeting");
+        };
+        send_auth(&mut client, kind, user, "secret-pw").await;
+        client
+    }
+
+    async fn assert_closed(client: &mut TcpStream) {
+        let mut buf = vec![];
+        assert_eq!(client.read_to_end(&mut buf).await.unwrap_or(0), 0);
+    }
+
+    #[tokio::test]
+    async fn test_moderation() {
+        let port = 60_016;
+        let users =
+            std::env::temp_dir().join(format!("async_chat_moderation_{}", std::process::id()));
+        let _ = std::fs::remove_file(&users);
+        spawn(run_server(
+            Config {
+                port,
+                users: Some(users.clone()),
+                moderators: vec!["mod".to_string()],
+                history_len: 0,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut moderator = login(port, AuthKind::Register, "mod").await;
+        let _ = read_msg(&mut moderator).await;
+        let mut bob = login(port, AuthKind::Register, "bob").await;
+        let _ = read_msg(&mut bob).await;
+
+        send_msg(&mut bob, "/kick mod").await;
+        let ParsedMsg::Info(InfoKind::PermissionDenied, _) = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+
+        // The messages of a muted user are dropped
+        send_msg(&mut moderator, "/mute bob 1m spamming").await;
+        let ParsedMsg::Info(InfoKind::Muted, text) = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(text, "You are muted for 1m by mod: spamming");
+        let ParsedMsg::Text(text) = read_msg(&mut moderator).await else {
+            panic!("Invalid msg");
+        };
+        assert!(text.ends_with("bob is muted for 1m"));
+        send_msg(&mut bob, "hello").await;
+        // Neither can a muted user send private messages
+        send_msg(&mut bob, "/msg mod hello").await;
+        let ParsedMsg::Info(InfoKind::Muted, text) = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        assert!(text.starts_with("You are muted for another"));
+        send_msg(&mut moderator, "hi").await;
+        let ParsedMsg::RoomText { text, .. } = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(text, "mod: hi");
+        let ParsedMsg::RoomText { text, .. } = read_msg(&mut moderator).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(text, "You: hi");
+
+        send_msg(&mut moderator, "/kick bob").await;
+        let ParsedMsg::Info(InfoKind::Kicked, _) = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        assert_closed(&mut bob).await;
+        let _ = read_msg(&mut moderator).await;
+        let ParsedMsg::RoomText { text, .. } = read_msg(&mut moderator).await else {
+            panic!("Invalid msg");
+        };
+        assert!(text.ends_with("bob left the chat"));
+
+        // The ban of an account outlives the connection
+        let mut bob = login(port, AuthKind::Login, "bob").await;
+        let _ = read_msg(&mut bob).await;
+        send_msg(&mut moderator, "/ban bob 1h").await;
+        let ParsedMsg::Info(InfoKind::Banned, _) = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        assert_closed(&mut bob).await;
+        let _ = read_msg(&mut moderator).await;
+        let _ = read_msg(&mut moderator).await;
+        let mut bob = login(port, AuthKind::Login, "bob").await;
+        let ParsedMsg::Info(InfoKind::Banned, text) = read_msg(&mut bob).await else {
+            panic!("Invalid msg");
+        };
+        assert!(text.starts_with("You are banned for another "));
+        assert_closed(&mut bob).await;
+
+        // A banned address is refused right away, the moderators are spared
+        send_msg(&mut moderator, &format!("/ban {} 1m", SERVER_IP)).await;
+        let _ = read_msg(&mut moderator).await;
+        let mut guest = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        assert_closed(&mut guest).await;
+        send_msg(&mut moderator, "still here").await;
+        let ParsedMsg::RoomText { text, .. } = read_msg(&mut moderator).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(text, "You: still here");
+        let _ = std::fs::remove_file(&users);
+    }
+
+    async fn admin_request(admin: &mut tokio::net::UnixStream, request: &str) -> Response {
+        admin
+            .write_all(format!("{}\n", request).as_bytes())
+            .await
+            .expect("Cannot send admin request");
+        let mut line = String::new();
+        let mut byte = [0];
+        while admin.read_exact(&mut byte).await.is_ok() && byte[0] != b'\n' {
+            line.push(byte[0] as char);
+        }
+        serde_json::from_str(&line).expect("Invalid admin response")
+    }
+
+    #[tokio::test]
+    async fn test_admin() {
+        let port = 60_017;
+        let path = std::env::temp_dir().join(format!("async_chat_admin_{}", std::process::id()));
+        spawn(run_server(
+            Config {
+                port,
+                admin_socket: Some(path.clone()),
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut client = connect(port).await;
+        let mut admin = tokio::net::UnixStream::connect(&path)
+            .await
+            .expect("Cannot connect to admin socket");
+
+        let Response::Ok(Reply::Stats(stats)) = admin_request(&mut admin, r#""stats""#).await
+        else {
+            panic!("Invalid response");
+        };
+        assert_eq!((stats.connections, stats.total_connections), (1, 1));
+        let Response::Ok(Reply::Connections(connections)) =
+            admin_request(&mut admin, r#""connections""#).await
+        else {
+            panic!("Invalid response");
+        };
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].addr, client.local_addr().unwrap());
+        let nick = connections[0].nick.clone();
+        let Response::Ok(Reply::Rooms(rooms)) = admin_request(&mut admin, r#""rooms""#).await
+        else {
+            panic!("Invalid response");
+        };
+        assert_eq!(rooms[0].name, DEFAULT_ROOM);
+        assert_eq!(rooms[0].members, 1);
+
+        let response =
+            admin_request(&mut admin, r#"{"announce":{"text":"Restart at noon"}}"#).await;
+        assert_eq!(response, Response::Ok(Reply::Done));
+        let ParsedMsg::Info(InfoKind::Announcement, text) = read_msg(&mut client).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(text, "Restart at noon");
+
+        let Response::Ok(Reply::Limits(limits)) = admin_request(
+            &mut admin,
+            r#"{"set_limits":{"max_connections":50,"msgs_per_sec":2.0}}"#,
+        )
+        .await
+        else {
+            panic!("Invalid response");
+        };
+        assert_eq!((limits.max_connections, limits.msgs_per_sec), (50, 2.0));
+        let Response::Error(_) =
+            admin_request(&mut admin, r#"{"set_limits":{"msgs_per_sec":0.0}}"#).await
+        else {
+            panic!("Invalid limits accepted");
+        };
+        let Response::Error(_) = admin_request(&mut admin, "reboot").await else {
+            panic!("Invalid request accepted");
+        };
+
+        let Response::Error(_) = admin_request(&mut admin, r#"{"kick":{"nick":"nobody"}}"#).await
+        else {
+            panic!("Unknown user kicked");
+        };
+        let request = format!(r#"{{"kick":{{"nick":"{}","reason":"maintenance"}}}}"#, nick);
+        assert_eq!(
+            admin_request(&mut admin, &request).await,
+            Response::Ok(Reply::Done)
+        );
+        let ParsedMsg::Info(InfoKind::Kicked, text) = read_msg(&mut client).await else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(text, "You were kicked by the admin: maintenance");
+        assert_closed(&mut client).await;
+
+        let request = format!(r#"{{"ban":{{"target":"{}","secs":60}}}}"#, SERVER_IP);
+        assert_eq!(
+            admin_request(&mut admin, &request).await,
+            Response::Ok(Reply::Done)
+        );
+        let mut banned = TcpStream::connect(format!("{}:{}", SERVER_IP, port))
+            .await
+            .expect("Cannot connect to server");
+        assert_closed(&mut banned).await;
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_admin_reload() {
+        let port = 60_018;
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("async_chat_reload_{}", std::process::id()));
+        let socket = dir.join(format!("async_chat_reload_sock_{}", std::process::id()));
+        std::fs::write(&path, format!("port = {}\nmax_connections = 20\n", port)).unwrap();
+        let args = Args {
+            config: Some(path.clone()),
+            admin_socket: Some(socket.clone()),
+            ..Args::default()
+        };
+        let config = Config::load(&args).unwrap();
+        spawn(run_server(config, Some(args), pending()));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut admin = tokio::net::UnixStream::connect(&socket)
+            .await
+            .expect("Cannot connect to admin socket");
+        std::fs::write(&path, "port = 1\nmax_connections = 30\n").unwrap();
+        assert_eq!(
+            admin_request(&mut admin, r#""reload""#).await,
+            Response::Ok(Reply::Reloaded {
+                restart_needed: vec!["port".to_string()]
+            })
+        );
+        let Response::Ok(Reply::Limits(limits)) = admin_request(&mut admin, r#""limits""#).await
+        else {
+            panic!("Invalid response");
+        };
+        assert_eq!(limits.max_connections, 30);
+        let _client = connect(port).await;
+
+        // A broken file changes nothing
+        std::fs::write(&path, "max_connections = 0\n").unwrap();
+        let Response::Error(_) = admin_request(&mut admin, r#""reload""#).await else {
+            panic!("Invalid configuration accepted");
+        };
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    async fn scrape(port: u16) -> String {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .expect("Cannot connect to metrics port");
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let port = 60_019;
+        let metrics_port = 60_020;
+        spawn(run_server(
+            Config {
+                port,
+                metrics_port: Some(metrics_port),
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut client = connect(port).await;
+        send_msg(&mut client, "hello").await;
+        let _ = read_msg(&mut client).await;
+        let s = (0..MAX_MSG_LEN + 1).map(|_| 'a').collect::<String>();
+        send_msg(&mut client, &s).await;
+        let _ = read_msg(&mut client).await;
+
+        let metrics = scrape(metrics_port).await;
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(metrics.contains("\nchat_connections 1\n"));
+        assert!(metrics.contains("\nchat_connections_total 1\n"));
+        assert!(metrics.contains("\nchat_messages_received_total 2\n"));
+        assert!(metrics.contains("\nchat_oversize_messages_total 1\n"));
+        assert!(metrics.contains("\nchat_parse_errors_total 0\n"));
+        assert!(metrics.contains("\nchat_write_failures_total 0\n"));
+
+        drop(client);
+        sleep(Duration::from_millis(200)).await;
+        assert!(scrape(metrics_port)
+            .await
+            .contains("\nchat_connections 0\n"));
+    }
+
+    /// Times the delivery of a burst of messages to a full room. Run with
+    /// `cargo test --release --bin server bench_fanout -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark"]
+    async fn bench_fanout() {
+        const RECEIVERS: usize = 100;
+        const MSGS: usize = 1_000;
+        let port = 60_021;
+        let rate_limit = RateLimitConfig {
+            msgs_per_sec: 1e9,
+            msg_burst: 1e9,
+            bytes_per_sec: 1e12,
+            byte_burst: 1e12,
+            ..RateLimitConfig::default()
+        };
+        let access = AccessConfig {
+            max_conns_per_subnet: RECEIVERS + 1,
+            ..AccessConfig::default()
+        };
+        spawn(run_server(
+            Config {
+                port,
+                max_connections: RECEIVERS + 2,
+                rate_limit,
+                access,
+                ..Config::default()
+            },
+            None,
+            pending(),
+        ));
+        sleep(Duration::from_millis(500)).await;
+
+        let mut receivers = JoinSet::new();
+        for _ in 0..RECEIVERS {
+            let mut client = connect(port).await;
+            let _ = receivers.spawn(async move {
+                // Messages received after a later one
+                let mut reordered = 0;
+                let mut last = None;
+                for _ in 0..MSGS {
+                    let ParsedMsg::RoomText { text, .. } = read_msg(&mut client).await else {
+                        panic!("Invalid msg");
+                    };
+                    let n = text
+                        .rsplit(' ')
+                        .next()
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .unwrap_or_else(|| panic!("Invalid msg {}", text));
+                    if last.is_some_and(|last| n < last) {
+                        reordered += 1;
+                    }
+                    last = last.max(Some(n));
+                }
+                // Leaving would notify the receivers still reading
+                (reordered, client)
+            });
+        }
+        let mut sender = connect(port).await;
+        let started = Instant::now();
+        for n in 0..MSGS {
+            send_msg(&mut sender, &format!("message {}", n)).await;
+        }
+        let mut reordered = 0;
+        let mut clients = vec![];
+        while let Some(received) = receivers.join_next().await {
+            let (count, client) = received.unwrap();
+            reordered += count;
+            clients.push(client);
+        }
+        let elapsed = started.elapsed();
+        println!(
+            "{} messages to {} receivers in {:?}: {:.0} deliveries/s, {} out of order",
+            MSGS,
+            RECEIVERS,
+            elapsed,
+            (MSGS * RECEIVERS) as f64 / elapsed.as_secs_f64(),
+            reordered
+        );
+    }
 }
@@ -0,0 +1,370 @@
+use std::{
+    fmt::Write as _,
+    io,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    spawn,
+    time::timeout,
+};
+use tracing::warn;
+
+// A scrape request is a few short lines
+const MAX_REQUEST_LEN: u64 = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MESSAGE_SIZE_BUCKETS: &[f64] = &[64.0, 256.0, 1024.0, 4096.0, 16384.0];
+const WRITE_DURATION_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        let _ = self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    #[must_use]
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts the observations falling under each of its upper bounds.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    // One more than the bounds, for the observations above all of them
+    buckets: Vec<AtomicU64>,
+    // The bits of a f64
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    #[must_use]
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        let _ = self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let _ = self
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                Some((f64::from_bits(sum) + value).to_bits())
+            });
+    }
+}
+
+/// What the server measures, exposed in the Prometheus text format.
+#[derive(Debug)]
+pub struct Metrics {
+    pub connections: Gauge,
+    pub connections_total: Counter,
+    pub refused_connections_total: Counter,
+    pub messages_received_total: Counter,
+    pub messages_sent_total: Counter,
+    pub received_bytes_total: Counter,
+    pub sent_bytes_total: Counter,
+    /// Dropped with InfoKind::MessageTooLong
+    pub oversize_messages_total: Counter,
+    pub throttled_messages_total: Counter,
+    pub parse_errors_total: Counter,
+    pub write_failures_total: Counter,
+    /// Dropped from the queue of a slow client, to make room
+    pub dropped_messages_total: Counter,
+    pub evicted_clients_total: Counter,
+    pub heartbeat_timeouts_total: Counter,
+    /// Clients that took longer than the read timeout to send a message
+    pub frame_timeouts_total: Counter,
+    pub idle_timeouts_total: Counter,
+    pub message_size_bytes: Histogram,
+    pub write_duration_seconds: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            connections: Gauge::default(),
+            connections_total: Counter::default(),
+            refused_connections_total: Counter::default(),
+            messages_received_total: Counter::default(),
+            messages_sent_total: Counter::default(),
+            received_bytes_total: Counter::default(),
+            sent_bytes_total: Counter::default(),
+            oversize_messages_total: Counter::default(),
+            throttled_messages_total: Counter::default(),
+            parse_errors_total: Counter::default(),
+            write_failures_total: Counter::default(),
+            dropped_messages_total: Counter::default(),
+            evicted_clients_total: Counter::default(),
+            heartbeat_timeouts_total: Counter::default(),
+            frame_timeouts_total: Counter::default(),
+            idle_timeouts_total: Counter::default(),
+            message_size_bytes: Histogram::new(MESSAGE_SIZE_BUCKETS),
+            write_duration_seconds: Histogram::new(WRITE_DURATION_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    #[must_use]
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "chat_connections_total",
+                "Connections accepted",
+                &self.connections_total,
+            ),
+            (
+                "chat_refused_connections_total",
+                "Connections refused by the access list, a ban or a full server",
+                &self.refused_connections_total,
+            ),
+            (
+                "chat_messages_received_total",
+                "Messages received from the clients",
+                &self.messages_received_total,
+            ),
+            (
+                "chat_messages_sent_total",
+                "Messages written to the clients",
+                &self.messages_sent_total,
+            ),
+            (
+                "chat_received_bytes_total",
+                "Bytes received from the clients",
+                &self.received_bytes_total,
+            ),
+            (
+                "chat_sent_bytes_total",
+                "Bytes written to the clients",
+                &self.sent_bytes_total,
+            ),
+            (
+                "chat_oversize_messages_total",
+                "Messages dropped for being longer than max_msg_len",
+                &self.oversize_messages_total,
+            ),
+            (
+                "chat_throttled_messages_total",
+                "Messages dropped by the rate limit",
+                &self.throttled_messages_total,
+            ),
+            (
+                "chat_parse_errors_total",
+                "Malformed messages received from the clients",
+                &self.parse_errors_total,
+            ),
+            (
+                "chat_write_failures_total",
+                "Clients dropped because they could not be written to",
+                &self.write_failures_total,
+            ),
+            (
+                "chat_dropped_messages_total",
+                "Messages dropped from the full queue of a slow client",
+                &self.dropped_messages_total,
+            ),
+            (
+                "chat_evicted_clients_total",
+                "Clients disconnected because their queue was full",
+                &self.evicted_clients_total,
+            ),
+            (
+                "chat_heartbeat_timeouts_total",
+                "Clients disconnected for leaving the pings unanswered",
+                &self.heartbeat_timeouts_total,
+            ),
+            (
+                "chat_frame_timeouts_total",
+                "Clients disconnected for sending a message slower than the read timeout",
+                &self.frame_timeouts_total,
+            ),
+            (
+                "chat_idle_timeouts_total",
+                "Clients disconnected for sending no message within the idle timeout",
+                &self.idle_timeouts_total,
+            ),
+        ];
+        write_header(&mut out, "chat_connections", "Open connections", "gauge");
+        let _ = writeln!(out, "chat_connections {}", self.connections.get());
+        for (name, help, counter) in counters {
+            write_header(&mut out, name, help, "counter");
+            let _ = writeln!(out, "{} {}", name, counter.get());
+        }
+        write_histogram(
+            &mut out,
+            "chat_message_size_bytes",
+            "Size of the messages received from the clients",
+            &self.message_size_bytes,
+        );
+        write_histogram(
+            &mut out,
+            "chat_write_duration_seconds",
+            "Time to write the queued messages to a client",
+            &self.write_duration_seconds,
+        );
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    write_header(out, name, help, "histogram");
+    // The buckets of the format are cumulative
+    let mut count = 0;
+    for (bound, bucket) in histogram.bounds.iter().zip(&histogram.buckets) {
+        count += bucket.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+    }
+    count += histogram.buckets[histogram.bounds.len()].load(Ordering::Relaxed);
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let _ = writeln!(
+        out,
+        "{}_sum {}",
+        name,
+        f64::from_bits(histogram.sum.load(Ordering::Relaxed))
+    );
+    let _ = writeln!(out, "{}_count {}", name, count);
+}
+
+/// Answers `GET /metrics` with the current values.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, sockaddr)) => {
+                let metrics = Arc::clone(&metrics);
+                spawn(async move {
+                    match timeout(REQUEST_TIMEOUT, respond(stream, &metrics)).await {
+                        Ok(Ok(())) => (),
+                        Ok(Err(e)) => warn!(addr = %sockaddr, error = %e, "metrics request failed"),
+                        Err(_) => warn!(addr = %sockaddr, "metrics request timed out"),
+                    }
+                });
+            }
+            Err(e) => warn!(error = %e, "cannot accept metrics connection"),
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut lines = BufReader::new(reader.take(MAX_REQUEST_LEN)).lines();
+    let request = lines.next_line().await?.unwrap_or_default();
+    // The headers are not needed
+    while let Some(line) = lines.next_line().await? {
+        if line.trim_end().is_empty() {
+            break;
+        }
+    }
+    let (status, content_type, body) = match request.split_whitespace().collect::<Vec<_>>()[..] {
+        ["GET", "/metrics", _] => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+        ["GET", _, _] => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Only GET is allowed\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    writer.write_all(response.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::*;
+
+    #[test]
+    fn render_test() {
+        let metrics = Metrics::default();
+        metrics.connections.set(2);
+        metrics.oversize_messages_total.inc();
+        metrics.sent_bytes_total.add(100);
+        metrics.message_size_bytes.observe(10.0);
+        metrics.message_size_bytes.observe(100.0);
+        metrics.message_size_bytes.observe(100_000.0);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE chat_connections gauge\nchat_connections 2\n"));
+        assert!(text.contains("\nchat_oversize_messages_total 1\n"));
+        assert!(text.contains("\nchat_sent_bytes_total 100\n"));
+        assert!(text.contains("\nchat_message_size_bytes_bucket{le=\"64\"} 1\n"));
+        assert!(text.contains("\nchat_message_size_bytes_bucket{le=\"256\"} 2\n"));
+        assert!(text.contains("\nchat_message_size_bytes_bucket{le=\"16384\"} 2\n"));
+        assert!(text.contains("\nchat_message_size_bytes_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("\nchat_message_size_bytes_sum 100110\n"));
+        assert!(text.contains("\nchat_message_size_bytes_count 3\n"));
+        assert!(text.contains("\nchat_write_duration_seconds_count 0\n"));
+    }
+
+    async fn get(port: u16, request: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serve_test() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let metrics = Arc::new(Metrics::default());
+        metrics.connections_total.add(7);
+        spawn(serve(listener, metrics));
+
+        let response = get(port, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nchat_connections_total 7\n"));
+
+        let response = get(port, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get(port, "POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
@@ -0,0 +1,322 @@
+use crate::{commands::Role, Connection, Connections, Entry, SERVER_INFO_HEADER};
+use async_chat::message::{InfoKind, SerializedMessage};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::spawn;
+use tracing::info;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sanction {
+    pub until: Instant,
+    pub reason: String,
+}
+
+/// Bans and mutes of the accounts, which outlive their connections.
+#[derive(Debug, Default)]
+pub struct Sanctions {
+    bans: HashMap<String, Sanction>,
+    mutes: HashMap<String, Instant>,
+}
+
+impl Sanctions {
+    pub fn ban(&mut self, account: &str, until: Instant, reason: &str) {
+        let _ = self.bans.insert(
+            account.to_string(),
+            Sanction {
+                until,
+                reason: reason.to_string(),
+            },
+        );
+    }
+
+    pub fn mute(&mut self, account: &str, until: Instant) {
+        let _ = self.mutes.insert(account.to_string(), until);
+    }
+
+    /// The ban of `account`, if it is still running at `now`.
+    pub fn ban_of(&mut self, account: &str, now: Instant) -> Option<&Sanction> {
+        self.bans.retain(|_, ban| ban.until > now);
+        self.bans.get(account)
+    }
+
+    /// The end of the mute of `account`, if it is still running at `now`.
+    pub fn mute_of(&mut self, account: &str, now: Instant) -> Option<Instant> {
+        self.mutes.retain(|_, until| *until > now);
+        self.mutes.get(account).copied()
+    }
+}
+
+/// Formats `duration` with the largest unit understood by the moderation commands.
+#[must_use]
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0 => "less than a second".to_string(),
+        s if s % (24 * 60 * 60) == 0 => format!("{}d", s / (24 * 60 * 60)),
+        s if s % (60 * 60) == 0 => format!("{}h", s / (60 * 60)),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+pub fn with_reason(text: String, reason: &str) -> String {
+    if reason.is_empty() {
+        text
+    } else {
+        format!("{}: {}", text, reason)
+    }
+}
+
+impl Connections {
+    /// The connection of `nick`, if the moderator at `sockaddr` outranks it.
+    fn moderation_target(&self, sockaddr: SocketAddr, nick: &str) -> Option<SocketAddr> {
+        let role = self.entries.get(&sockaddr)?.role;
+        let Some((target, entry)) = self.find_by_nick(nick) else {
+            let msg = format!("User '{}' not found", nick);
+            self.send_to_user(
+                sockaddr,
+                SerializedMessage::from_info(InfoKind::UserNotFound, &msg),
+            );
+            return None;
+        };
+        if entry.role >= role {
+            let msg = format!("You cannot moderate {}", entry.nick);
+            self.send_to_user(
+                sockaddr,
+                SerializedMessage::from_info(InfoKind::PermissionDenied, &msg),
+            );
+            return None;
+        }
+        Some(target)
+    }
+
+    fn nick_of(&self, sockaddr: SocketAddr) -> String {
+        self.entries
+            .get(&sockaddr)
+            .map(|entry| entry.nick.clone())
+            .unwrap_or_default()
+    }
+
+    fn confirm(&self, sockaddr: SocketAddr, text: String) {
+        self.send_to_user(
+            sockaddr,
+            SerializedMessage::from_string(&format!("{}{}", SERVER_INFO_HEADER, text)),
+        );
+    }
+
+    /// Tells `sockaddr` why, then drops its connection.
+    pub(crate) fn disconnect(&self, sockaddr: SocketAddr, kind: InfoKind, text: String) {
+        if !self.entries.contains_key(&sockaddr) {
+            return;
+        }
+        self.send_to_user(sockaddr, SerializedMessage::from_info(kind, &text));
+        // The connection is closed once the notice is written
+        let conn_sender = self.conn_sender.clone();
+        spawn(async move {
+            let _ = conn_sender.send(Connection::Pop(sockaddr)).await;
+        });
+    }
+
+    pub(crate) fn kick(&mut self, sockaddr: SocketAddr, nick: String, reason: String) {
+        let Some(target) = self.moderation_target(sockaddr, &nick) else {
+            return;
+        };
+        let text = with_reason(
+            format!("You were kicked by {}", self.nick_of(sockaddr)),
+            &reason,
+        );
+        if let Some(entry) = self.entries.get(&target) {
+            info!(parent: &entry.span, by = %self.nick_of(sockaddr), reason, "kicked");
+        }
+        self.disconnect(target, InfoKind::Kicked, text);
+        self.confirm(sockaddr, format!("{} was kicked", nick));
+    }
+
+    pub(crate) fn ban(
+        &mut self,
+        sockaddr: SocketAddr,
+        target: String,
+        duration: Duration,
+        reason: String,
+    ) {
+        let Some(role) = self.entries.get(&sockaddr).map(|entry| entry.role) else {
+            return;
+        };
+        let text = with_reason(
+            format!(
+                "You are banned for {} by {}",
+                format_duration(duration),
+                self.nick_of(sockaddr)
+            ),
+            &reason,
+        );
+        if let Ok(ip) = target.parse::<IpAddr>() {
+            self.ban_address(ip, duration, &text, |entry| entry.role < role);
+        } else {
+            let Some(target_addr) = self.moderation_target(sockaddr, &target) else {
+                return;
+            };
+            self.ban_user(target_addr, duration, &reason, text);
+        }
+        info!(
+            %target,
+            by = %self.nick_of(sockaddr),
+            duration = %format_duration(duration),
+            reason,
+            "banned"
+        );
+        self.confirm(
+            sockaddr,
+            format!("{} is banned for {}", target, format_duration(duration)),
+        );
+    }
+
+    /// Refuses `ip` for `duration` and drops its connections for which `affects` holds.
+    pub(crate) fn ban_address(
+        &self,
+        ip: IpAddr,
+        duration: Duration,
+        text: &str,
+        affects: impl Fn(&Entry) -> bool,
+    ) {
+        let ip = ip.to_canonical();
+        self.gate.ban(ip, duration);
+        for (target, _) in self
+            .entries
+            .iter()
+            .filter(|(addr, entry)| addr.ip().to_canonical() == ip && affects(entry))
+        {
+            self.disconnect(*target, InfoKind::Banned, text.to_string());
+        }
+    }
+
+    /// Bans the account of the user at `target`, then drops it.
+    /// Guests have nothing but their address, which is banned instead.
+    pub(crate) fn ban_user(
+        &mut self,
+        target: SocketAddr,
+        duration: Duration,
+        reason: &str,
+        text: String,
+    ) {
+        let Some(entry) = self.entries.get(&target) else {
+            return;
+        };
+        match &entry.account {
+            Some(account) => {
+                self.sanctions
+                    .ban(account, Instant::now() + duration, reason);
+            }
+            None => self.gate.ban(target.ip(), duration),
+        }
+        self.disconnect(target, InfoKind::Banned, text);
+    }
+
+    pub(crate) fn mute(
+        &mut self,
+        sockaddr: SocketAddr,
+        nick: String,
+        duration: Duration,
+        reason: String,
+    ) {
+        let Some(target) = self.moderation_target(sockaddr, &nick) else {
+            return;
+        };
+        let until = Instant::now() + duration;
+        let moderator = self.nick_of(sockaddr);
+        let Some(entry) = self.entries.get_mut(&target) else {
+            return;
+        };
+        entry.muted_until = Some(until);
+        info!(
+            parent: &entry.span,
+            by = %moderator,
+            duration = %format_duration(duration),
+            reason,
+            "muted"
+        );
+        if let Some(account) = &entry.account {
+            self.sanctions.mute(account, until);
+        }
+        let text = with_reason(
+            format!(
+                "You are muted for {} by {}",
+                format_duration(duration),
+                moderator
+            ),
+            &reason,
+        );
+        self.send_to_user(target, SerializedMessage::from_info(InfoKind::Muted, &text));
+        self.confirm(
+            sockaddr,
+            format!("{} is muted for {}", nick, format_duration(duration)),
+        );
+    }
+
+    /// Applies the sanctions of `account` to its new connection.
+    /// Returns false if the account is banned, and the connection is being dropped.
+    pub(crate) fn check_sanctions(&mut self, sockaddr: SocketAddr, account: &str) -> bool {
+        let now = Instant::now();
+        if let Some(ban) = self.sanctions.ban_of(account, now) {
+            let text = with_reason(
+                format!(
+                    "You are banned for another {}",
+                    format_duration(ban.until - now)
+                ),
+                &ban.reason,
+            );
+            self.disconnect(sockaddr, InfoKind::Banned, text);
+            return false;
+        }
+        let muted_until = self.sanctions.mute_of(account, now);
+        if let Some(entry) = self.entries.get_mut(&sockaddr) {
+            entry.muted_until = muted_until;
+            entry.role = if self
+                .config
+                .moderators
+                .iter()
+                .any(|moderator| moderator.eq_ignore_ascii_case(account))
+            {
+                Role::Moderator
+            } else {
+                Role::User
+            };
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod moderation_tests {
+    use super::*;
+
+    #[test]
+    fn sanctions_test() {
+        let now = Instant::now();
+        let mut sanctions = Sanctions::default();
+        sanctions.ban("bob", now + Duration::from_secs(10), "spam");
+        sanctions.mute("carol", now + Duration::from_secs(10));
+        assert_eq!(sanctions.ban_of("bob", now).unwrap().reason, "spam");
+        assert!(sanctions.ban_of("carol", now).is_none());
+        assert!(sanctions.mute_of("carol", now).is_some());
+
+        let later = now + Duration::from_secs(10);
+        assert!(sanctions.ban_of("bob", later).is_none());
+        assert!(sanctions.mute_of("carol", later).is_none());
+    }
+
+    #[test]
+    fn format_duration_test() {
+        assert_eq!(format_duration(Duration::from_secs(90)), "90s");
+        assert_eq!(format_duration(Duration::from_secs(600)), "10m");
+        assert_eq!(format_duration(Duration::from_secs(7200)), "2h");
+        assert_eq!(format_duration(Duration::from_secs(86_400)), "1d");
+        assert_eq!(
+            format_duration(Duration::from_millis(10)),
+            "less than a second"
+        );
+    }
+}
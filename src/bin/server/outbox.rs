@@ -0,0 +1,374 @@
+use crate::{metrics::Metrics, Connection, StreamWriter};
+use async_chat::message::SerializedMessage;
+use serde::Deserialize;
+use std::{
+    collections::VecDeque,
+    mem,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    spawn,
+    sync::{mpsc::Sender, Notify},
+    task::JoinHandle,
+};
+use tracing::{debug, info_span, warn, Instrument};
+
+/// What happens to a client whose queue is full, because it reads slower than it is written to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowClientPolicy {
+    /// Its oldest queued message is dropped to make room
+    DropOldest,
+    /// Its connection is dropped
+    #[default]
+    Disconnect,
+}
+
+impl FromStr for SlowClientPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_oldest" => Ok(Self::DropOldest),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(format!(
+                "invalid slow client policy '{}', use drop_oldest or disconnect",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboxConfig {
+    /// Messages queued for a client, besides the ones being written
+    pub len: usize,
+    pub policy: SlowClientPolicy,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            len: 256,
+            policy: SlowClientPolicy::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    msgs: VecDeque<SerializedMessage>,
+    /// No more messages are queued, the writer stops once the queue is empty
+    closed: bool,
+    /// The writer stops right away, dropping what is queued
+    evicted: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    // Wakes the writer up
+    changed: Notify,
+}
+
+enum Next {
+    Msgs(VecDeque<SerializedMessage>),
+    Closed,
+    Evicted,
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Queue> {
+        self.queue.lock().expect("Outbox lock is poisoned")
+    }
+
+    async fn next(&self) -> Next {
+        loop {
+            {
+                let mut queue = self.lock();
+                if queue.evicted {
+                    return Next::Evicted;
+                }
+                if !queue.msgs.is_empty() {
+                    return Next::Msgs(mem::take(&mut queue.msgs));
+                }
+                if queue.closed {
+                    return Next::Closed;
+                }
+            }
+            // A notification sent since the queue was checked is not lost
+            self.changed.notified().await;
+        }
+    }
+
+    async fn evicted(&self) {
+        while !self.lock().evicted {
+            self.changed.notified().await;
+        }
+    }
+}
+
+/// The messages on their way to a client, written in order by a task of their own.
+#[derive(Clone)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+    config: OutboxConfig,
+    metrics: Arc<Metrics>,
+}
+
+impl Outbox {
+    /// Starts the writer of `stream`. A client that cannot be written to, or is evicted
+    /// for being too slow, is dropped with a Pop sent to `conn_sender`.
+    pub fn spawn(
+        stream: StreamWriter,
+        config: OutboxConfig,
+        sockaddr: SocketAddr,
+        conn_sender: Sender<Connection>,
+        metrics: Arc<Metrics>,
+    ) -> (Self, JoinHandle<()>) {
+        let shared = Arc::new(Shared::default());
+        let writer = spawn(
+            write_queued(
+                stream,
+                Arc::clone(&shared),
+                sockaddr,
+                conn_sender,
+                Arc::clone(&metrics),
+            )
+            .instrument(info_span!("conn", addr = %sockaddr)),
+        );
+        let outbox = Self {
+            shared,
+            config,
+            metrics,
+        };
+        (outbox, writer)
+    }
+
+    /// Queues `msg`, unless the outbox is closed or the client evicted.
+    pub fn send(&self, msg: SerializedMessage) {
+        let mut queue = self.shared.lock();
+        if queue.closed || queue.evicted {
+            return;
+        }
+        if queue.msgs.len() >= self.config.len {
+            match self.config.policy {
+                SlowClientPolicy::DropOldest => {
+                    let _ = queue.msgs.pop_front();
+                    self.metrics.dropped_messages_total.inc();
+                }
+                SlowClientPolicy::Disconnect => {
+                    queue.evicted = true;
+                    queue.msgs.clear();
+                    self.metrics.evicted_clients_total.inc();
+                    drop(queue);
+                    self.shared.changed.notify_one();
+                    return;
+                }
+            }
+        }
+        queue.msgs.push_back(msg);
+        drop(queue);
+        self.shared.changed.notify_one();
+    }
+
+    /// Lets the writer shut the stream down once the queued messages are written.
+    pub fn close(&self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_one();
+    }
+}
+
+async fn write_queued(
+    mut stream: StreamWriter,
+    shared: Arc<Shared>,
+    sockaddr: SocketAddr,
+    conn_sender: Sender<Connection>,
+    metrics: Arc<Metrics>,
+) {
+    loop {
+        let msgs = match shared.next().await {
+            Next::Msgs(msgs) => msgs,
+            Next::Closed => break,
+            Next::Evicted => {
+                warn!("evicted slow client");
+                let _ = conn_sender.send(Connection::Pop(sockaddr)).await;
+                return;
+            }
+        };
+        // A client that stopped reading blocks the write, until it gets evicted
+        let written = tokio::select! {
+            written = write_msgs(&mut stream, &msgs, &metrics) => written,
+            () = shared.evicted() => {
+                warn!("evicted slow client");
+                let _ = conn_sender.send(Connection::Pop(sockaddr)).await;
+                return;
+            }
+        };
+        if let Err(e) = written {
+            metrics.write_failures_total.inc();
+            warn!(error = %e, "cannot write to stream");
+            let _ = conn_sender.send(Connection::Pop(sockaddr)).await;
+            return;
+        }
+    }
+    if let Err(e) = stream.shutdown().await {
+        debug!(error = %e, "cannot shut down stream");
+    }
+}
+
+async fn write_msgs(
+    stream: &mut StreamWriter,
+    msgs: &VecDeque<SerializedMessage>,
+    metrics: &Metrics,
+) -> std::io::Result<()> {
+    let started = Instant::now();
+    for msg in msgs {
+        stream.write_all(msg.as_bytes()).await?;
+        metrics.messages_sent_total.inc();
+        metrics.sent_bytes_total.add(msg.as_bytes().len() as u64);
+    }
+    // Once per batch, a tls stream buffers the records until then
+    stream.flush().await?;
+    metrics
+        .write_duration_seconds
+        .observe(started.elapsed().as_secs_f64());
+    Ok(())
+}
+
+#[cfg(test)]
+mod outbox_tests {
+    use super::*;
+    use async_chat::message::ParsedMsg;
+    use tokio::{
+        io::{duplex, AsyncReadExt, DuplexStream},
+        sync::mpsc,
+    };
+
+    const SOCKADDR: &str = "127.0.0.1:5000";
+
+    fn outbox(
+        stream: DuplexStream,
+        config: OutboxConfig,
+    ) -> (
+        Outbox,
+        JoinHandle<()>,
+        mpsc::Receiver<Connection>,
+        Arc<Metrics>,
+    ) {
+        let (conn_sender, conn_recv) = mpsc::channel(1);
+        let metrics = Arc::new(Metrics::default());
+        let (outbox, writer) = Outbox::spawn(
+            Box::new(stream),
+            config,
+            SOCKADDR.parse().unwrap(),
+            conn_sender,
+            Arc::clone(&metrics),
+        );
+        (outbox, writer, conn_recv, metrics)
+    }
+
+    async fn read_text(stream: &mut DuplexStream) -> String {
+        let size = stream.read_u32().await.unwrap();
+        let mut buf = size.to_be_bytes().to_vec();
+        buf.resize(size as usize, 0);
+        let _ = stream
+            .read_exact(&mut buf[SerializedMessage::size_of_len()..])
+            .await
+            .unwrap();
+        let Some(ParsedMsg::Text(text)) = ParsedMsg::from_bytes(&buf) else {
+            panic!("Invalid msg");
+        };
+        text
+    }
+
+    async fn popped(conn_recv: &mut mpsc::Receiver<Connection>) -> SocketAddr {
+        let Some(Connection::Pop(sockaddr)) = conn_recv.recv().await else {
+            panic!("Connection not dropped");
+        };
+        sockaddr
+    }
+
+    #[tokio::test]
+    async fn order_test() {
+        let (stream, mut client) = duplex(1024);
+        let (outbox, writer, _conn_recv, metrics) = outbox(stream, OutboxConfig::default());
+        for n in 0..100 {
+            outbox.send(SerializedMessage::from_string(&n.to_string()));
+        }
+        outbox.close();
+        // Ignored once closed
+        outbox.send(SerializedMessage::from_string("late"));
+        for n in 0..100 {
+            assert_eq!(read_text(&mut client).await, n.to_string());
+        }
+        writer.await.unwrap();
+        // Shut down once everything was written
+        assert_eq!(client.read_u8().await.ok(), None);
+        assert_eq!(metrics.messages_sent_total.get(), 100);
+    }
+
+    #[tokio::test]
+    async fn write_failure_test() {
+        let (stream, client) = duplex(64);
+        // The client is gone
+        drop(client);
+        let (outbox, writer, mut conn_recv, metrics) = outbox(stream, OutboxConfig::default());
+        outbox.send(SerializedMessage::from_string("hello"));
+        assert_eq!(popped(&mut conn_recv).await, SOCKADDR.parse().unwrap());
+        writer.await.unwrap();
+        assert_eq!(metrics.write_failures_total.get(), 1);
+        assert_eq!(metrics.messages_sent_total.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_test() {
+        // Not even one message fits, the writer blocks on the first one
+        let (stream, mut client) = duplex(4);
+        let config = OutboxConfig {
+            len: 2,
+            policy: SlowClientPolicy::DropOldest,
+        };
+        let (outbox, _writer, _conn_recv, metrics) = outbox(stream, config);
+        outbox.send(SerializedMessage::from_string("first"));
+        tokio::task::yield_now().await;
+        for text in ["second", "third", "fourth"] {
+            outbox.send(SerializedMessage::from_string(text));
+        }
+        assert_eq!(metrics.dropped_messages_total.get(), 1);
+        assert_eq!(read_text(&mut client).await, "first");
+        assert_eq!(read_text(&mut client).await, "third");
+        assert_eq!(read_text(&mut client).await, "fourth");
+    }
+
+    #[tokio::test]
+    async fn evict_test() {
+        let (stream, _client) = duplex(4);
+        let config = OutboxConfig {
+            len: 2,
+            policy: SlowClientPolicy::Disconnect,
+        };
+        let (outbox, writer, mut conn_recv, metrics) = outbox(stream, config);
+        outbox.send(SerializedMessage::from_string("first"));
+        tokio::task::yield_now().await;
+        for text in ["second", "third", "fourth"] {
+            outbox.send(SerializedMessage::from_string(text));
+        }
+        // The writer was stuck on the first message, nobody reads it
+        assert_eq!(popped(&mut conn_recv).await, SOCKADDR.parse().unwrap());
+        writer.await.unwrap();
+        assert_eq!(metrics.evicted_clients_total.get(), 1);
+        assert_eq!(metrics.messages_sent_total.get(), 0);
+    }
+
+    #[test]
+    fn policy_test() {
+        assert_eq!("drop_oldest".parse(), Ok(SlowClientPolicy::DropOldest));
+        assert_eq!("disconnect".parse(), Ok(SlowClientPolicy::Disconnect));
+        assert!("block".parse::<SlowClientPolicy>().is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use async_chat::message::InfoKind;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitConfig {
+    pub msgs_per_sec: f64,
+    pub msg_burst: f64,
+    pub bytes_per_sec: f64,
+    /// Must be at least the maximum length of a message, or such messages never get through
+    pub byte_burst: f64,
+    /// Throttled messages within `strike_window` before the connection gets muted
+    pub strikes_before_mute: u32,
+    pub strike_window: Duration,
+    pub mute_duration: Duration,
+    /// Mutes before the connection gets closed at the next offence
+    pub mutes_before_disconnect: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            msgs_per_sec: 5.0,
+            msg_burst: 10.0,
+            bytes_per_sec: 8.0 * 1024.0,
+            byte_burst: 20.0 * 1024.0,
+            strikes_before_mute: 20,
+            strike_window: Duration::from_secs(10),
+            mute_duration: Duration::from_secs(30),
+            mutes_before_disconnect: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// The message is dropped. `warn` is set only for the first one of a burst
+    Throttle {
+        warn: bool,
+    },
+    /// The message is dropped. `notify` is set only when the mute begins
+    Mute {
+        notify: bool,
+    },
+    Disconnect,
+}
+
+impl Verdict {
+    /// The notice to send to the client, if any.
+    #[must_use]
+    pub fn notice(self, config: &RateLimitConfig) -> Option<(InfoKind, String)> {
+        match self {
+            Self::Allow | Self::Throttle { warn: false } | Self::Mute { notify: false } => None,
+            Self::Throttle { warn: true } => Some((
+                InfoKind::Throttled,
+                format!(
+                    "You are sending too fast, your messages are dropped. Limit is {} messages per second",
+                    config.msgs_per_sec
+                ),
+            )),
+            Self::Mute { notify: true } => Some((
+                InfoKind::Muted,
+                format!(
+                    "You are muted for {} seconds for flooding",
+                    config.mute_duration.as_secs()
+                ),
+            )),
+            Self::Disconnect => Some((
+                InfoKind::Disconnected,
+                "You are disconnected for flooding".to_string(),
+            )),
+        }
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    fn resize(&mut self, rate: f64, capacity: f64) {
+        self.rate = rate;
+        self.capacity = capacity;
+        self.tokens = self.tokens.min(capacity);
+    }
+
+    fn has(&self, n: f64) -> bool {
+        self.tokens >= n
+    }
+
+    fn take(&mut self, n: f64) {
+        self.tokens -= n;
+    }
+}
+
+/// Flood protection of a single connection.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    msgs: TokenBucket,
+    bytes: TokenBucket,
+    throttled: bool,
+    strikes: u32,
+    first_strike: Instant,
+    mutes: u32,
+    muted_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub fn new(config: RateLimitConfig, now: Instant) -> Self {
+        Self {
+            config,
+            msgs: TokenBucket::new(config.msgs_per_sec, config.msg_burst, now),
+            bytes: TokenBucket::new(config.bytes_per_sec, config.byte_burst, now),
+            throttled: false,
+            strikes: 0,
+            first_strike: now,
+            mutes: 0,
+            muted_until: None,
+        }
+    }
+
+    /// Applies new limits, keeping the tokens left and the strikes so far.
+    pub fn reconfigure(&mut self, config: RateLimitConfig, now: Instant) {
+        self.msgs.refill(now);
+        self.bytes.refill(now);
+        self.msgs.resize(config.msgs_per_sec, config.msg_burst);
+        self.bytes.resize(config.bytes_per_sec, config.byte_burst);
+        self.config = config;
+    }
+
+    /// Accounts for a message of `len` bytes received at `now`.
+    pub fn check(&mut self, len: usize, now: Instant) -> Verdict {
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return Verdict::Mute { notify: false };
+            }
+            self.muted_until = None;
+        }
+        self.msgs.refill(now);
+        self.bytes.refill(now);
+        let len = len as f64;
+        if self.msgs.has(1.0) && self.bytes.has(len) {
+            self.msgs.take(1.0);
+            self.bytes.take(len);
+            self.throttled = false;
+            return Verdict::Allow;
+        }
+
+        if self.strikes == 0 || now.duration_since(self.first_strike) > self.config.strike_window {
+            self.strikes = 0;
+            self.first_strike = now;
+        }
+        self.strikes += 1;
+        if self.strikes < self.config.strikes_before_mute {
+            let warn = !self.throttled;
+            self.throttled = true;
+            return Verdict::Throttle { warn };
+        }
+
+        if self.mutes >= self.config.mutes_before_disconnect {
+            return Verdict::Disconnect;
+        }
+        self.strikes = 0;
+        self.throttled = false;
+        self.mutes += 1;
+        self.muted_until = Some(now + self.config.mute_duration);
+        Verdict::Mute { notify: true }
+    }
+}
+
+#[cfg(test)]
+mod ratelimit_tests {
+    use super::*;
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            msgs_per_sec: 1.0,
+            msg_burst: 2.0,
+            bytes_per_sec: 100.0,
+            byte_burst: 100.0,
+            strikes_before_mute: 3,
+            strike_window: Duration::from_secs(10),
+            mute_duration: Duration::from_secs(5),
+            mutes_before_disconnect: 1,
+        }
+    }
+
+    #[test]
+    fn token_bucket_test() {
+        let now = Instant::now();
+        let config = RateLimitConfig {
+            strikes_before_mute: 10,
+            ..config()
+        };
+        let mut limiter = RateLimiter::new(config, now);
+        assert_eq!(limiter.check(10, now), Verdict::Allow);
+        assert_eq!(limiter.check(10, now), Verdict::Allow);
+        assert_eq!(limiter.check(10, now), Verdict::Throttle { warn: true });
+        assert_eq!(limiter.check(10, now), Verdict::Throttle { warn: false });
+
+        let now = now + Duration::from_secs(1);
+        assert_eq!(limiter.check(10, now), Verdict::Allow);
+
+        // The bytes are limited on their own
+        let now = now + Duration::from_secs(5);
+        assert_eq!(limiter.check(90, now), Verdict::Allow);
+        assert_eq!(limiter.check(20, now), Verdict::Throttle { warn: true });
+
+        // Smaller bursts take effect right away
+        let now = now + Duration::from_secs(5);
+        limiter.reconfigure(
+            RateLimitConfig {
+                msg_burst: 1.0,
+                ..config
+            },
+            now,
+        );
+        assert_eq!(limiter.check(10, now), Verdict::Allow);
+        assert_eq!(limiter.check(10, now), Verdict::Throttle { warn: true });
+    }
+
+    #[test]
+    fn mute_and_disconnect_test() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(config(), now);
+        for _ in 0..2 {
+            assert_eq!(limiter.check(1, now), Verdict::Allow);
+        }
+        for _ in 0..2 {
+            assert!(matches!(limiter.check(1, now), Verdict::Throttle { .. }));
+        }
+        assert_eq!(limiter.check(1, now), Verdict::Mute { notify: true });
+        assert_eq!(limiter.check(1, now), Verdict::Mute { notify: false });
+
+        let now = now + config().mute_duration;
+        for _ in 0..2 {
+            assert_eq!(limiter.check(1, now), Verdict::Allow);
+        }
+        for _ in 0..2 {
+            assert!(matches!(limiter.check(1, now), Verdict::Throttle { .. }));
+        }
+        assert_eq!(limiter.check(1, now), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn strikes_expire_test() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(config(), now);
+        for _ in 0..2 {
+            assert_eq!(limiter.check(1, now), Verdict::Allow);
+        }
+        for _ in 0..2 {
+            assert!(matches!(limiter.check(1, now), Verdict::Throttle { .. }));
+        }
+        // A late strike starts counting again
+        let now = now + config().strike_window + Duration::from_secs(1);
+        for _ in 0..2 {
+            assert_eq!(limiter.check(1, now), Verdict::Allow);
+        }
+        assert_eq!(limiter.check(1, now), Verdict::Throttle { warn: true });
+    }
+}
@@ -0,0 +1,56 @@
+use async_chat::message::DEFAULT_ROOM;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+};
+
+/// Membership of the chat rooms. The default room always exists, the other
+/// rooms are created on the first join and dropped when the last member leaves.
+pub struct Rooms {
+    members: HashMap<String, HashSet<SocketAddr>>,
+}
+
+impl Default for Rooms {
+    fn default() -> Self {
+        Self {
+            members: HashMap::from([(DEFAULT_ROOM.to_string(), HashSet::new())]),
+        }
+    }
+}
+
+impl Rooms {
+    pub fn join(&mut self, room: &str, sockaddr: SocketAddr) {
+        let _ = self
+            .members
+            .entry(room.to_string())
+            .or_default()
+            .insert(sockaddr);
+    }
+
+    pub fn leave(&mut self, room: &str, sockaddr: SocketAddr) {
+        if let Some(members) = self.members.get_mut(room) {
+            let _ = members.remove(&sockaddr);
+            if members.is_empty() && room != DEFAULT_ROOM {
+                let _ = self.members.remove(room);
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn contains(&self, room: &str, sockaddr: SocketAddr) -> bool {
+        self.members
+            .get(room)
+            .is_some_and(|members| members.contains(&sockaddr))
+    }
+
+    pub fn members(&self, room: &str) -> impl Iterator<Item = &SocketAddr> {
+        self.members.get(room).into_iter().flatten()
+    }
+
+    #[must_use]
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = self.members.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+}
@@ -0,0 +1,73 @@
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+use serde::Deserialize;
+use std::{io, path::PathBuf, sync::Arc};
+use tokio_rustls::TlsAcceptor;
+
+/// Paths of the PEM encoded certificate chain and private key of the server.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+            .map_err(|e| invalid_data(&self.cert, e))?;
+        let key =
+            PrivateKeyDer::from_pem_file(&self.key).map_err(|e| invalid_data(&self.key, e))?;
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn invalid_data(path: &std::path::Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    )
+}
+
+#[cfg(test)]
+pub mod tls_tests {
+    use super::*;
+
+    /// Writes a self-signed certificate for `localhost` and `127.0.0.1` in a temp dir.
+    pub fn self_signed(name: &str) -> (TlsConfig, CertificateDer<'static>) {
+        let cert =
+            rcgen::generate_simple_self_signed(["localhost".to_string(), "127.0.0.1".to_string()])
+                .expect("Cannot generate certificate");
+        let dir = std::env::temp_dir().join(format!("async_chat_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = TlsConfig {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+        };
+        std::fs::write(&config.cert, cert.cert.pem()).unwrap();
+        std::fs::write(&config.key, cert.key_pair.serialize_pem()).unwrap();
+        (config, cert.cert.der().clone())
+    }
+
+    #[test]
+    fn acceptor_test() {
+        let (config, _) = self_signed("acceptor");
+        assert!(config.acceptor().is_ok());
+
+        let swapped = TlsConfig {
+            cert: config.key.clone(),
+            key: config.cert.clone(),
+        };
+        assert_eq!(
+            swapped.acceptor().err().map(|e| e.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+    }
+}
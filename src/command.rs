@@ -0,0 +1,365 @@
+use std::{fmt, time::Duration};
+
+pub const CMD_PREFIX: char = '/';
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Cmd {
+    UserCount,
+    Help(Option<String>),
+    Nick(String),
+    Join(String),
+    Leave,
+    Rooms,
+    DirectMsg {
+        nick: String,
+        text: String,
+    },
+    Kick {
+        nick: String,
+        reason: String,
+    },
+    /// `target` is either a nick or an ip address
+    Ban {
+        target: String,
+        duration: Duration,
+        reason: String,
+    },
+    Mute {
+        nick: String,
+        duration: Duration,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CmdError {
+    UnknownCommand(String),
+    MissingArgument {
+        cmd: String,
+        arg: &'static str,
+    },
+    TooManyArguments(String),
+    InvalidArgument {
+        cmd: String,
+        arg: &'static str,
+        value: String,
+    },
+    UnterminatedQuote,
+}
+
+impl fmt::Display for CmdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownCommand(cmd) => write!(
+                f,
+                "Unknown command '{}{}'. Type {}help for the list of commands",
+                CMD_PREFIX, cmd, CMD_PREFIX
+            ),
+            Self::MissingArgument { cmd, arg } => {
+                write!(f, "Missing argument <{}> for {}{}", arg, CMD_PREFIX, cmd)
+            }
+            Self::TooManyArguments(cmd) => {
+                write!(f, "Too many arguments for {}{}", CMD_PREFIX, cmd)
+            }
+            Self::InvalidArgument { cmd, arg, value } => {
+                write!(f, "Invalid <{}> '{}' for {}{}", arg, value, CMD_PREFIX, cmd)
+            }
+            Self::UnterminatedQuote => write!(f, "Unterminated quoted argument"),
+        }
+    }
+}
+
+impl std::error::Error for CmdError {}
+
+/// Parses a duration like `90`, `30s`, `10m`, `2h` or `7d`. Plain numbers are seconds.
+#[must_use]
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let (value, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => text.split_at(i),
+        None => (text, "s"),
+    };
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    let secs = value.parse::<u64>().ok()?.checked_mul(unit)?;
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Splits a command line into whitespace separated tokens. A token can be
+/// enclosed in double quotes to contain whitespace, and `\"` / `\\` escape a
+/// quote / backslash inside quotes.
+pub struct Tokens<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tokens<'a> {
+    #[must_use]
+    pub fn new(text: &'a str) -> Self {
+        Self { rest: text }
+    }
+
+    /// The input that has not been tokenized yet, without leading whitespace.
+    #[must_use]
+    pub fn rest(&self) -> &'a str {
+        self.rest.trim_start()
+    }
+
+    fn quoted(&mut self) -> Result<String, CmdError> {
+        let mut token = String::new();
+        let mut chars = self.rest.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + c.len_utf8()..];
+                    return Ok(token);
+                }
+                '\\' => match chars.next() {
+                    Some((_, c @ ('"' | '\\'))) => token.push(c),
+                    Some((_, c)) => {
+                        token.push('\\');
+                        token.push(c);
+                    }
+                    None => break,
+                },
+                c => token.push(c),
+            }
+        }
+        Err(CmdError::UnterminatedQuote)
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Result<String, CmdError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rest = self.rest.trim_start();
+        if self.rest.is_empty() {
+            return None;
+        }
+        if self.rest.starts_with('"') {
+            return Some(self.quoted());
+        }
+        let end = self
+            .rest
+            .find(char::is_whitespace)
+            .unwrap_or(self.rest.len());
+        let (token, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Some(Ok(token.to_string()))
+    }
+}
+
+impl Cmd {
+    /// The name used to invoke the command, without the prefix.
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::UserCount => "count",
+            Self::Help(_) => "help",
+            Self::Nick(_) => "nick",
+            Self::Join(_) => "join",
+            Self::Leave => "leave",
+            Self::Rooms => "rooms",
+            Self::DirectMsg { .. } => "msg",
+            Self::Kick { .. } => "kick",
+            Self::Ban { .. } => "ban",
+            Self::Mute { .. } => "mute",
+        }
+    }
+
+    /// Parses a command line like `/name arg1 "quoted arg"`.
+    /// Returns `None` if `text` is not a command. A leading `//` escapes the
+    /// prefix, so such a text is not a command either.
+    #[must_use]
+    pub fn parse(text: &str) -> Option<Result<Self, CmdError>> {
+        let line = text.trim().strip_prefix(CMD_PREFIX)?;
+        if line.starts_with(CMD_PREFIX) {
+            return None;
+        }
+        Some(Self::parse_line(line))
+    }
+
+    fn parse_line(line: &str) -> Result<Self, CmdError> {
+        let mut tokens = Tokens::new(line);
+        let name = tokens.next().transpose()?.unwrap_or_default();
+        let mut arg = |arg: &'static str| match tokens.next() {
+            Some(token) => token,
+            None => Err(CmdError::MissingArgument {
+                cmd: name.clone(),
+                arg,
+            }),
+        };
+        let duration = |value: String| {
+            parse_duration(&value).ok_or_else(|| CmdError::InvalidArgument {
+                cmd: name.clone(),
+                arg: "duration",
+                value,
+            })
+        };
+        let cmd = match name.as_str() {
+            "count" => Self::UserCount,
+            "help" => Self::Help(tokens.next().transpose()?),
+            "nick" => Self::Nick(arg("name")?),
+            "join" => Self::Join(arg("room")?),
+            "leave" => Self::Leave,
+            "rooms" => Self::Rooms,
+            "msg" => {
+                let nick = arg("nick")?;
+                let text = tokens.rest().to_string();
+                if text.is_empty() {
+                    return Err(CmdError::MissingArgument {
+                        cmd: name,
+                        arg: "text",
+                    });
+                }
+                return Ok(Self::DirectMsg { nick, text });
+            }
+            // The reason is the rest of the line, and it is optional
+            "kick" => {
+                let nick = arg("nick")?;
+                let reason = tokens.rest().to_string();
+                return Ok(Self::Kick { nick, reason });
+            }
+            "ban" => {
+                let target = arg("nick|ip")?;
+                let duration = duration(arg("duration")?)?;
+                let reason = tokens.rest().to_string();
+                return Ok(Self::Ban {
+                    target,
+                    duration,
+                    reason,
+                });
+            }
+            "mute" => {
+                let nick = arg("nick")?;
+                let duration = duration(arg("duration")?)?;
+                let reason = tokens.rest().to_string();
+                return Ok(Self::Mute {
+                    nick,
+                    duration,
+                    reason,
+                });
+            }
+            _ => return Err(CmdError::UnknownCommand(name)),
+        };
+        match tokens.next() {
+            Some(_) => Err(CmdError::TooManyArguments(name)),
+            None => Ok(cmd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod command_tests {
+    use super::*;
+
+    fn tokenize(text: &str) -> Result<Vec<String>, CmdError> {
+        Tokens::new(text).collect()
+    }
+
+    #[test]
+    fn tokens_test() {
+        assert_eq!(
+            tokenize(r#"  join   "my room" x\y "a \"b\" \\" "#).unwrap(),
+            vec!["join", "my room", r"x\y", r#"a "b" \"#]
+        );
+        assert_eq!(tokenize("a \"b"), Err(CmdError::UnterminatedQuote));
+        assert!(tokenize("   ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(Cmd::parse("/count\n"), Some(Ok(Cmd::UserCount)));
+        assert_eq!(
+            Cmd::parse("/help nick"),
+            Some(Ok(Cmd::Help(Some("nick".to_string()))))
+        );
+        assert_eq!(
+            Cmd::parse("/nick \"alice\""),
+            Some(Ok(Cmd::Nick("alice".to_string())))
+        );
+        assert_eq!(
+            Cmd::parse("/msg bob  hello   there"),
+            Some(Ok(Cmd::DirectMsg {
+                nick: "bob".to_string(),
+                text: "hello   there".to_string()
+            }))
+        );
+        assert_eq!(
+            Cmd::parse("/ban 10.0.0.1 2h  \"flooding\" again"),
+            Some(Ok(Cmd::Ban {
+                target: "10.0.0.1".to_string(),
+                duration: Duration::from_secs(2 * 60 * 60),
+                reason: "\"flooding\" again".to_string()
+            }))
+        );
+        assert_eq!(
+            Cmd::parse("/kick bob"),
+            Some(Ok(Cmd::Kick {
+                nick: "bob".to_string(),
+                reason: String::new()
+            }))
+        );
+        assert_eq!(Cmd::parse("hello"), None);
+        assert_eq!(Cmd::parse("//not a command"), None);
+    }
+
+    #[test]
+    fn duration_test() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_duration("1d"), Some(Duration::from_secs(86_400)));
+        assert_eq!(parse_duration("0s"), None);
+        assert_eq!(parse_duration("5w"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("-1"), None);
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX)), None);
+    }
+
+    #[test]
+    fn parse_error_test() {
+        assert_eq!(
+            Cmd::parse("/cuont"),
+            Some(Err(CmdError::UnknownCommand("cuont".to_string())))
+        );
+        assert_eq!(
+            Cmd::parse("/"),
+            Some(Err(CmdError::UnknownCommand(String::new())))
+        );
+        assert_eq!(
+            Cmd::parse("/join"),
+            Some(Err(CmdError::MissingArgument {
+                cmd: "join".to_string(),
+                arg: "room"
+            }))
+        );
+        assert_eq!(
+            Cmd::parse("/msg bob"),
+            Some(Err(CmdError::MissingArgument {
+                cmd: "msg".to_string(),
+                arg: "text"
+            }))
+        );
+        assert_eq!(
+            Cmd::parse("/mute bob forever"),
+            Some(Err(CmdError::InvalidArgument {
+                cmd: "mute".to_string(),
+                arg: "duration",
+                value: "forever".to_string()
+            }))
+        );
+        assert_eq!(
+            Cmd::parse("/leave now"),
+            Some(Err(CmdError::TooManyArguments("leave".to_string())))
+        );
+        assert_eq!(
+            Cmd::parse("/nick \"alice"),
+            Some(Err(CmdError::UnterminatedQuote))
+        );
+    }
+}
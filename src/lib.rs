@@ -1 +1,4 @@
+pub mod admin;
+pub mod command;
+pub mod logging;
 pub mod message;
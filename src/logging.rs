@@ -0,0 +1,143 @@
+use serde::Deserialize;
+use std::{fmt, fs::OpenOptions, io, path::PathBuf, str::FromStr, sync::Mutex};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, EnvFilter};
+
+/// Every event at info level or above.
+pub const DEFAULT_LOG_FILTER: &str = "info";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// A line of text per event
+    #[default]
+    Text,
+    /// A json object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("invalid log format '{}', use text or json", s)),
+        }
+    }
+}
+
+/// Where the events go and which ones. `filter` has the syntax of `RUST_LOG`,
+/// like `info` or `warn,server=debug`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    pub filter: String,
+    pub format: LogFormat,
+    /// Appended to, instead of printing to stdout
+    pub file: Option<PathBuf>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            filter: DEFAULT_LOG_FILTER.to_string(),
+            format: LogFormat::default(),
+            file: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LogError {
+    Filter(String),
+    Io(PathBuf, io::Error),
+    Init(String),
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Filter(e) => write!(f, "Invalid log filter: {}", e),
+            Self::Io(path, e) => write!(f, "Cannot open log file {}: {}", path.display(), e),
+            Self::Init(e) => write!(f, "Cannot set up logging: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LogError {}
+
+/// Checks the syntax of a filter, without installing it.
+pub fn check_filter(filter: &str) -> Result<(), LogError> {
+    EnvFilter::try_new(filter)
+        .map(|_| ())
+        .map_err(|e| LogError::Filter(e.to_string()))
+}
+
+impl LogConfig {
+    /// Installs the logger of the whole process. Fails if there is one already.
+    pub fn init(&self) -> Result<(), LogError> {
+        let filter =
+            EnvFilter::try_new(&self.filter).map_err(|e| LogError::Filter(e.to_string()))?;
+        let writer = match &self.file {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| LogError::Io(path.clone(), e))?;
+                BoxMakeWriter::new(Mutex::new(file))
+            }
+            None => BoxMakeWriter::new(io::stdout),
+        };
+        let builder = tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_writer(writer)
+            .with_ansi(self.file.is_none());
+        let installed = match self.format {
+            LogFormat::Text => builder.try_init(),
+            LogFormat::Json => builder.json().try_init(),
+        };
+        installed.map_err(|e| LogError::Init(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod logging_tests {
+    use super::*;
+
+    #[test]
+    fn format_test() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!("text".parse(), Ok(LogFormat::Text));
+        assert!("xml".parse::<LogFormat>().is_err());
+        assert!(check_filter("warn,server=debug").is_ok());
+        assert!(check_filter("server=loud").is_err());
+    }
+
+    #[test]
+    fn init_test() {
+        let path = std::env::temp_dir().join(format!("async_chat_log_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = LogConfig {
+            filter: "info".to_string(),
+            format: LogFormat::Json,
+            file: Some(path.clone()),
+        };
+        config.init().unwrap();
+        tracing::info_span!("conn", nick = "alice").in_scope(|| {
+            tracing::info!(bytes = 5, "message received");
+            tracing::debug!("filtered out");
+        });
+        assert!(matches!(config.init(), Err(LogError::Init(_))));
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 1);
+        let event: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(event["level"], "INFO");
+        assert_eq!(event["fields"]["message"], "message received");
+        assert_eq!(event["fields"]["bytes"], 5);
+        assert_eq!(event["span"]["nick"], "alice");
+        let _ = std::fs::remove_file(&path);
+    }
+}
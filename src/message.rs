@@ -1,6 +1,13 @@
+use crate::command::{Cmd, CmdError, CMD_PREFIX};
+
 type Size = u32;
 
 pub const MAX_MSG_LEN: usize = 5 * 1024;
+pub const MAX_NICK_LEN: usize = 32;
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+pub const DEFAULT_ROOM: &str = "lobby";
+// The chat messages are numbered from 1
+const NO_SEQ: u64 = 0;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SerializedMessage(Vec<u8>);
@@ -22,7 +29,7 @@ impl SerializedMessage {
         Self(serialize(
             size,
             msg_type,
-            payload.as_bytes().into_iter().cloned(),
+            payload.as_bytes().iter().copied(),
         ))
     }
 
@@ -36,6 +43,131 @@ impl SerializedMessage {
         Self::from_string_generic(payload, MsgType::Help)
     }
 
+    #[must_use]
+    pub fn from_info(info_kind: InfoKind, text: &str) -> Self {
+        let size = (Self::size_of_header() + InfoKind::size() + text.len()) as u32;
+        Self(serialize(
+            size,
+            MsgType::Info,
+            [info_kind as u8]
+                .into_iter()
+                .chain(text.as_bytes().iter().copied()),
+        ))
+    }
+
+    /// `seq` is the place of a chat message in the order of the server, the notices
+    /// of the server have none.
+    #[must_use]
+    pub fn from_room_text(seq: Option<u64>, room: &str, text: &str) -> Self {
+        let seq = seq.unwrap_or(NO_SEQ);
+        let size =
+            (Self::size_of_header() + std::mem::size_of_val(&seq) + 1 + room.len() + text.len())
+                as u32;
+        Self(serialize(
+            size,
+            MsgType::RoomText,
+            seq.to_be_bytes()
+                .into_iter()
+                .chain(short_str(room))
+                .chain(text.as_bytes().iter().copied()),
+        ))
+    }
+
+    #[must_use]
+    pub fn from_whisper(from: &str, to: &str, text: &str) -> Self {
+        let size = (Self::size_of_header() + 2 + from.len() + to.len() + text.len()) as u32;
+        Self(serialize(
+            size,
+            MsgType::Whisper,
+            short_str(from)
+                .chain(short_str(to))
+                .chain(text.as_bytes().iter().copied()),
+        ))
+    }
+
+    /// `prev_seq` is the message before it in the same room, which tells the clients whether
+    /// they missed some.
+    #[must_use]
+    pub fn from_history(
+        seq: u64,
+        prev_seq: Option<u64>,
+        timestamp: u64,
+        room: &str,
+        sender: &str,
+        text: &str,
+    ) -> Self {
+        let prev_seq = prev_seq.unwrap_or(NO_SEQ);
+        let size = (Self::size_of_header()
+            + std::mem::size_of_val(&seq)
+            + std::mem::size_of_val(&prev_seq)
+            + std::mem::size_of_val(&timestamp)
+            + 2
+            + room.len()
+            + sender.len()
+            + text.len()) as u32;
+        Self(serialize(
+            size,
+            MsgType::History,
+            seq.to_be_bytes()
+                .into_iter()
+                .chain(prev_seq.to_be_bytes())
+                .chain(timestamp.to_be_bytes())
+                .chain(short_str(room))
+                .chain(short_str(sender))
+                .chain(text.as_bytes().iter().copied()),
+        ))
+    }
+
+    #[must_use]
+    pub fn from_auth_request(kind: AuthKind, user: &str, password: &str) -> Self {
+        let size = (Self::size_of_header() + 2 + user.len() + password.len()) as u32;
+        Self(serialize(
+            size,
+            MsgType::AuthRequest,
+            [kind as u8]
+                .into_iter()
+                .chain(short_str(user))
+                .chain(password.as_bytes().iter().copied()),
+        ))
+    }
+
+    #[must_use]
+    pub fn from_auth_response(status: AuthStatus, text: &str) -> Self {
+        let size = (Self::size_of_header() + 1 + text.len()) as u32;
+        Self(serialize(
+            size,
+            MsgType::AuthResponse,
+            [status as u8]
+                .into_iter()
+                .chain(text.as_bytes().iter().copied()),
+        ))
+    }
+
+    /// Turns a connection away, asking it to try again after `retry_after_secs`.
+    #[must_use]
+    pub fn from_refusal(retry_after_secs: u32, text: &str) -> Self {
+        let size =
+            (Self::size_of_header() + std::mem::size_of_val(&retry_after_secs) + text.len()) as u32;
+        Self(serialize(
+            size,
+            MsgType::Refused,
+            retry_after_secs
+                .to_be_bytes()
+                .into_iter()
+                .chain(text.as_bytes().iter().copied()),
+        ))
+    }
+
+    #[must_use]
+    pub fn from_room_list<S: AsRef<str>>(rooms: &[S]) -> Self {
+        let payload = rooms
+            .iter()
+            .map(AsRef::as_ref)
+            .collect::<Vec<_>>()
+            .join("\n");
+        Self::from_string_generic(&payload, MsgType::RoomList)
+    }
+
     #[must_use]
     pub fn from_user_count(n: u32) -> Self {
         let size = (Self::size_of_header() + std::mem::size_of_val(&n)) as u32;
@@ -43,6 +175,23 @@ impl SerializedMessage {
         Self(serialize(size, msg_type, n.to_be_bytes().into_iter()))
     }
 
+    /// Asks the peer for a pong with the same `token`, to tell it is still there.
+    #[must_use]
+    pub fn from_ping(token: u64) -> Self {
+        Self::from_token(token, MsgType::Ping)
+    }
+
+    #[must_use]
+    pub fn from_pong(token: u64) -> Self {
+        Self::from_token(token, MsgType::Pong)
+    }
+
+    #[must_use]
+    fn from_token(token: u64, msg_type: MsgType) -> Self {
+        let size = (Self::size_of_header() + std::mem::size_of_val(&token)) as u32;
+        Self(serialize(size, msg_type, token.to_be_bytes().into_iter()))
+    }
+
     #[must_use]
     pub fn as_bytes(&self) -> &[u8] {
         &self.0
@@ -55,21 +204,137 @@ impl From<SerializedMessage> for Vec<u8> {
     }
 }
 
+/// A string prefixed by its length in one byte, used for names inside a payload.
+fn short_str(s: &str) -> impl Iterator<Item = u8> + '_ {
+    debug_assert!(s.len() <= u8::MAX as usize);
+    [s.len() as u8]
+        .into_iter()
+        .chain(s.as_bytes().iter().copied())
+}
+
+/// Reads a big endian u64 at `start`, returning it with the position of the following byte.
+#[must_use]
+fn read_u64(bytes: &[u8], start: usize) -> Option<(u64, usize)> {
+    let end = start + std::mem::size_of::<u64>();
+    let n = u64::from_be_bytes(bytes.get(start..end)?.try_into().ok()?);
+    Some((n, end))
+}
+
+/// Reads a string written by `short_str` at `start`, returning it with the position
+/// of the following byte.
+#[must_use]
+fn read_short_str(bytes: &[u8], start: usize) -> Option<(String, usize)> {
+    let len = *bytes.get(start)? as usize;
+    let end = start + 1 + len;
+    let s = std::str::from_utf8(bytes.get(start + 1..end)?).ok()?;
+    Some((s.to_string(), end))
+}
+
 #[must_use]
 fn serialize(size: u32, msg_type: MsgType, payload: impl Iterator<Item = u8>) -> Vec<u8> {
     size.to_be_bytes()
         .into_iter()
-        .chain([msg_type as u8].into_iter())
+        .chain([msg_type as u8])
         .chain(payload)
         .collect()
 }
 
+/// Why the decoder yields no frame for some bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The size in the header does not even cover the header. Only the header is skipped
+    TooShort(u32),
+    /// The size is over the limit of the decoder. The frame is skipped as its bytes arrive
+    TooLong(u32),
+}
+
+/// Splits the bytes of a stream into frames, whatever the chunks they arrive in.
+///
+/// The bytes go in with [`FrameDecoder::push`], then [`FrameDecoder::next_frame`] yields the
+/// whole frames, header included, until it needs more bytes. Besides the latest chunk, at most
+/// one frame of up to `max_len` bytes is buffered: the longer ones are never stored.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    max_len: usize,
+    buf: Vec<u8>,
+    // Where the bytes not decoded yet start in buf
+    start: usize,
+    // The bytes still to come of a frame too long
+    to_skip: usize,
+}
+
+impl FrameDecoder {
+    #[must_use]
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            buf: Vec::new(),
+            start: 0,
+            to_skip: 0,
+        }
+    }
+
+    /// Applies from the next header decoded.
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = max_len;
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        // The frames already yielded make room for the new bytes
+        self.buf.drain(..self.start);
+        self.start = 0;
+        // Nothing is buffered while skipping
+        let skipped = self.to_skip.min(bytes.len());
+        self.to_skip -= skipped;
+        self.buf.extend_from_slice(&bytes[skipped..]);
+    }
+
+    /// The next whole frame, or None until more bytes are pushed.
+    pub fn next_frame(&mut self) -> Option<Result<&[u8], FrameError>> {
+        let pending = &self.buf[self.start..];
+        let header = pending.get(..SerializedMessage::size_of_header())?;
+        let size = Size::from_be_bytes(header[..SerializedMessage::size_of_len()].try_into().ok()?);
+        if (size as usize) < SerializedMessage::size_of_header() {
+            self.start += SerializedMessage::size_of_header();
+            return Some(Err(FrameError::TooShort(size)));
+        }
+        if size as usize > self.max_len {
+            let buffered = pending.len().min(size as usize);
+            self.start += buffered;
+            self.to_skip = size as usize - buffered;
+            return Some(Err(FrameError::TooLong(size)));
+        }
+        let frame = self.start..self.start + size as usize;
+        if frame.end > self.buf.len() {
+            return None;
+        }
+        self.start = frame.end;
+        Some(Ok(&self.buf[frame]))
+    }
+
+    /// Whether part of a frame was received, once the whole frames were taken.
+    #[must_use]
+    pub fn in_frame(&self) -> bool {
+        self.to_skip > 0 || self.start < self.buf.len()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[repr(u8)]
 pub enum MsgType {
     Text = 0,
     UserCount = 1,
     Help = 2,
+    Info = 3,
+    RoomText = 4,
+    RoomList = 5,
+    Whisper = 6,
+    History = 7,
+    AuthRequest = 8,
+    AuthResponse = 9,
+    Refused = 10,
+    Ping = 11,
+    Pong = 12,
 }
 
 impl MsgType {
@@ -86,6 +351,16 @@ impl TryInto<MsgType> for u8 {
             0 => Ok(MsgType::Text),
             1 => Ok(MsgType::UserCount),
             2 => Ok(MsgType::Help),
+            3 => Ok(MsgType::Info),
+            4 => Ok(MsgType::RoomText),
+            5 => Ok(MsgType::RoomList),
+            6 => Ok(MsgType::Whisper),
+            7 => Ok(MsgType::History),
+            8 => Ok(MsgType::AuthRequest),
+            9 => Ok(MsgType::AuthResponse),
+            10 => Ok(MsgType::Refused),
+            11 => Ok(MsgType::Ping),
+            12 => Ok(MsgType::Pong),
             _ => Err(()),
         }
     }
@@ -93,16 +368,104 @@ impl TryInto<MsgType> for u8 {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[repr(u8)]
-pub enum Cmd {
-    UserCount,
-    Help,
+pub enum InfoKind {
+    MessageTooLong = 0,
+    ServerFull = 1,
+    NickTaken = 2,
+    NickInvalid = 3,
+    RoomInvalid = 4,
+    UserNotFound = 5,
+    UnknownCommand = 6,
+    InvalidCommand = 7,
+    PermissionDenied = 8,
+    NotAuthenticated = 9,
+    Throttled = 10,
+    Muted = 11,
+    Disconnected = 12,
+    ShuttingDown = 13,
+    Kicked = 14,
+    Banned = 15,
+    Announcement = 16,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl InfoKind {
+    #[must_use]
+    const fn size() -> usize {
+        std::mem::size_of::<Self>()
+    }
+}
+
+impl TryInto<InfoKind> for u8 {
+    type Error = ();
+    fn try_into(self) -> Result<InfoKind, Self::Error> {
+        match self {
+            0 => Ok(InfoKind::MessageTooLong),
+            1 => Ok(InfoKind::ServerFull),
+            2 => Ok(InfoKind::NickTaken),
+            3 => Ok(InfoKind::NickInvalid),
+            4 => Ok(InfoKind::RoomInvalid),
+            5 => Ok(InfoKind::UserNotFound),
+            6 => Ok(InfoKind::UnknownCommand),
+            7 => Ok(InfoKind::InvalidCommand),
+            8 => Ok(InfoKind::PermissionDenied),
+            9 => Ok(InfoKind::NotAuthenticated),
+            10 => Ok(InfoKind::Throttled),
+            11 => Ok(InfoKind::Muted),
+            12 => Ok(InfoKind::Disconnected),
+            13 => Ok(InfoKind::ShuttingDown),
+            14 => Ok(InfoKind::Kicked),
+            15 => Ok(InfoKind::Banned),
+            16 => Ok(InfoKind::Announcement),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
-pub enum InfoKind {
-    MessageTooLong,
-    ServerFull,
+pub enum AuthKind {
+    Login = 0,
+    Register = 1,
+}
+
+impl TryInto<AuthKind> for u8 {
+    type Error = ();
+    fn try_into(self) -> Result<AuthKind, Self::Error> {
+        match self {
+            0 => Ok(AuthKind::Login),
+            1 => Ok(AuthKind::Register),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AuthStatus {
+    /// Sent on connection when the server requires a login
+    Required = 0,
+    Ok = 1,
+    InvalidCredentials = 2,
+    UserExists = 3,
+    InvalidUser = 4,
+    InvalidPassword = 5,
+    AlreadyLoggedIn = 6,
+}
+
+impl TryInto<AuthStatus> for u8 {
+    type Error = ();
+    fn try_into(self) -> Result<AuthStatus, Self::Error> {
+        match self {
+            0 => Ok(AuthStatus::Required),
+            1 => Ok(AuthStatus::Ok),
+            2 => Ok(AuthStatus::InvalidCredentials),
+            3 => Ok(AuthStatus::UserExists),
+            4 => Ok(AuthStatus::InvalidUser),
+            5 => Ok(AuthStatus::InvalidPassword),
+            6 => Ok(AuthStatus::AlreadyLoggedIn),
+            _ => Err(()),
+        }
+    }
 }
 
 // NOTE: Should I create 2 message types, one for the server and one for the client?
@@ -111,16 +474,51 @@ pub enum ParsedMsg {
     UserCount(u32),
     Text(String),
     Command(Cmd),
-    Info(InfoKind),
+    BadCommand(CmdError),
+    Info(InfoKind, String),
     Help(String),
+    RoomText {
+        /// None for the notices of the server
+        seq: Option<u64>,
+        room: String,
+        text: String,
+    },
+    RoomList(Vec<String>),
+    Whisper {
+        from: String,
+        to: String,
+        text: String,
+    },
+    AuthRequest {
+        kind: AuthKind,
+        user: String,
+        password: String,
+    },
+    AuthResponse(AuthStatus, String),
+    /// A message broadcast before the client connected
+    History {
+        seq: u64,
+        /// The message before it in the same room, None for the first one
+        prev_seq: Option<u64>,
+        timestamp: u64,
+        room: String,
+        sender: String,
+        text: String,
+    },
+    /// Sent by either side, answered with a pong of the same token
+    Ping(u64),
+    Pong(u64),
+    /// The server closes the connection without letting it in
+    Refused {
+        retry_after_secs: u32,
+        text: String,
+    },
 }
 
 impl ParsedMsg {
     #[must_use]
     pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
-        let msg_type: MsgType = bytes
-            .get(SerializedMessage::size_of_len())?
-            .clone()
+        let msg_type: MsgType = (*bytes.get(SerializedMessage::size_of_len())?)
             .try_into()
             .ok()?;
         match msg_type {
@@ -144,18 +542,123 @@ impl ParsedMsg {
                 let text =
                     String::from_utf8_lossy(bytes.get(SerializedMessage::size_of_header()..)?);
 
-                match text.as_ref().trim_end() {
-                    "/count" => Some(Self::Command(Cmd::UserCount)),
-                    "/help" => Some(Self::Command(Cmd::Help)),
-                    _ => Some(Self::Text(text.to_string())),
+                match Cmd::parse(&text) {
+                    Some(Ok(cmd)) => Some(Self::Command(cmd)),
+                    Some(Err(e)) => Some(Self::BadCommand(e)),
+                    None => {
+                        // A leading "//" sends a text starting with the command prefix,
+                        // only the escaping one is dropped
+                        let mut text = text.into_owned();
+                        let start = text.len() - text.trim_start().len();
+                        let escaped = text[start..]
+                            .strip_prefix(CMD_PREFIX)
+                            .is_some_and(|rest| rest.starts_with(CMD_PREFIX));
+                        if escaped {
+                            text.remove(start);
+                        }
+                        Some(Self::Text(text))
+                    }
                 }
             }
+            MsgType::RoomText => {
+                let (seq, room_start) = read_u64(bytes, SerializedMessage::size_of_header())?;
+                let (room, text_start) = read_short_str(bytes, room_start)?;
+                let text = String::from_utf8_lossy(bytes.get(text_start..)?);
+                Some(Self::RoomText {
+                    seq: (seq != NO_SEQ).then_some(seq),
+                    room,
+                    text: text.to_string(),
+                })
+            }
+            MsgType::AuthRequest => {
+                let start = SerializedMessage::size_of_header();
+                let kind: AuthKind = (*bytes.get(start)?).try_into().ok()?;
+                let (user, password_start) = read_short_str(bytes, start + 1)?;
+                let password = std::str::from_utf8(bytes.get(password_start..)?).ok()?;
+                Some(Self::AuthRequest {
+                    kind,
+                    user,
+                    password: password.to_string(),
+                })
+            }
+            MsgType::AuthResponse => {
+                let start = SerializedMessage::size_of_header();
+                let status: AuthStatus = (*bytes.get(start)?).try_into().ok()?;
+                let text = String::from_utf8_lossy(bytes.get(start + 1..)?);
+                Some(Self::AuthResponse(status, text.to_string()))
+            }
+            MsgType::History => {
+                let (seq, prev_start) = read_u64(bytes, SerializedMessage::size_of_header())?;
+                let (prev_seq, timestamp_start) = read_u64(bytes, prev_start)?;
+                let (timestamp, room_start) = read_u64(bytes, timestamp_start)?;
+                let (room, sender_start) = read_short_str(bytes, room_start)?;
+                let (sender, text_start) = read_short_str(bytes, sender_start)?;
+                let text = String::from_utf8_lossy(bytes.get(text_start..)?);
+                Some(Self::History {
+                    seq,
+                    prev_seq: (prev_seq != NO_SEQ).then_some(prev_seq),
+                    timestamp,
+                    room,
+                    sender,
+                    text: text.to_string(),
+                })
+            }
+            MsgType::Whisper => {
+                let (from, to_start) = read_short_str(bytes, SerializedMessage::size_of_header())?;
+                let (to, text_start) = read_short_str(bytes, to_start)?;
+                let text = String::from_utf8_lossy(bytes.get(text_start..)?);
+                Some(Self::Whisper {
+                    from,
+                    to,
+                    text: text.to_string(),
+                })
+            }
+            MsgType::Ping | MsgType::Pong => {
+                let (token, end) = read_u64(bytes, SerializedMessage::size_of_header())?;
+                if end != bytes.len() {
+                    return None;
+                }
+                Some(if msg_type == MsgType::Ping {
+                    Self::Ping(token)
+                } else {
+                    Self::Pong(token)
+                })
+            }
+            MsgType::Refused => {
+                let start = SerializedMessage::size_of_header();
+                let end = start + std::mem::size_of::<u32>();
+                let retry_after_secs = u32::from_be_bytes(bytes.get(start..end)?.try_into().ok()?);
+                let text = String::from_utf8_lossy(bytes.get(end..)?);
+                Some(Self::Refused {
+                    retry_after_secs,
+                    text: text.to_string(),
+                })
+            }
+            MsgType::RoomList => {
+                let text =
+                    String::from_utf8_lossy(bytes.get(SerializedMessage::size_of_header()..)?);
+                Some(Self::RoomList(
+                    text.split('\n')
+                        .filter(|room| !room.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                ))
+            }
+            MsgType::Info => {
+                let info_kind: InfoKind = (*bytes.get(SerializedMessage::size_of_header())?)
+                    .try_into()
+                    .ok()?;
+                let text = String::from_utf8_lossy(
+                    bytes.get(SerializedMessage::size_of_header() + InfoKind::size()..)?,
+                );
+                Some(Self::Info(info_kind, text.to_string()))
+            }
         }
     }
 
     #[must_use]
     pub fn from_info(info_kind: InfoKind) -> Self {
-        Self::Info(info_kind)
+        Self::Info(info_kind, String::new())
     }
 }
 
@@ -168,13 +671,10 @@ mod message_tests {
         let s = "Hello, World!".to_owned();
         let msg = SerializedMessage::from_string(&s);
         let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
-        match parsed {
-            ParsedMsg::UserCount(_) => assert!(false),
-            ParsedMsg::Text(txt) => assert_eq!(txt, s),
-            ParsedMsg::Command(_) => assert!(false),
-            ParsedMsg::Info(_) => assert!(false),
-            ParsedMsg::Help(_) => assert!(false),
+        let ParsedMsg::Text(txt) = parsed else {
+            panic!("Invalid msg: {:?}", parsed);
         };
+        assert_eq!(txt, s);
     }
 
     #[test]
@@ -182,25 +682,321 @@ mod message_tests {
         let n = 11u32;
         let msg = SerializedMessage::from_user_count(n);
         let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
-        match parsed {
-            ParsedMsg::UserCount(m) => assert_eq!(n, m),
-            ParsedMsg::Text(_) => assert!(false),
-            ParsedMsg::Command(_) => assert!(false),
-            ParsedMsg::Info(_) => assert!(false),
-            ParsedMsg::Help(_) => assert!(false),
+        let ParsedMsg::UserCount(m) = parsed else {
+            panic!("Invalid msg: {:?}", parsed);
         };
+        assert_eq!(n, m);
     }
 
     #[test]
     fn cmd_test() {
         let msg = SerializedMessage::from_string("/count");
         let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
-        match parsed {
-            ParsedMsg::UserCount(_) => assert!(false),
-            ParsedMsg::Text(_) => assert!(false),
-            ParsedMsg::Command(cmd) => assert_eq!(cmd, Cmd::UserCount),
-            ParsedMsg::Info(_) => assert!(false),
-            ParsedMsg::Help(_) => assert!(false),
+        let ParsedMsg::Command(cmd) = parsed else {
+            panic!("Invalid msg: {:?}", parsed);
+        };
+        assert_eq!(cmd, Cmd::UserCount);
+    }
+
+    #[test]
+    fn nick_cmd_test() {
+        let msg = SerializedMessage::from_string("/nick  alice \n");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(parsed, ParsedMsg::Command(Cmd::Nick("alice".to_string())));
+
+        let msg = SerializedMessage::from_string("/nickname");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(
+            parsed,
+            ParsedMsg::BadCommand(CmdError::UnknownCommand("nickname".to_string()))
+        );
+
+        let msg = SerializedMessage::from_string("//nickname");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(parsed, ParsedMsg::Text("/nickname".to_string()));
+
+        // Only the escape is dropped from the text
+        let msg = SerializedMessage::from_string("  //nick  ");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(parsed, ParsedMsg::Text("  /nick  ".to_string()));
+        let msg = SerializedMessage::from_string("  indented\n");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(parsed, ParsedMsg::Text("  indented\n".to_string()));
+    }
+
+    #[test]
+    fn info_test() {
+        let msg = SerializedMessage::from_info(InfoKind::NickTaken, "taken");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(
+            parsed,
+            ParsedMsg::Info(InfoKind::NickTaken, "taken".to_string())
+        );
+    }
+
+    #[test]
+    fn refusal_test() {
+        let msg = SerializedMessage::from_refusal(30, "Server is full");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(
+            parsed,
+            ParsedMsg::Refused {
+                retry_after_secs: 30,
+                text: "Server is full".to_string()
+            }
+        );
+        assert_eq!(ParsedMsg::from_bytes(&msg.as_bytes()[..7]), None);
+    }
+
+    #[test]
+    fn heartbeat_test() {
+        let msg = SerializedMessage::from_ping(u64::MAX);
+        assert_eq!(
+            ParsedMsg::from_bytes(msg.as_bytes()),
+            Some(ParsedMsg::Ping(u64::MAX))
+        );
+        let msg = SerializedMessage::from_pong(7);
+        assert_eq!(
+            ParsedMsg::from_bytes(msg.as_bytes()),
+            Some(ParsedMsg::Pong(7))
+        );
+        // The token is the whole payload
+        let mut bytes = Vec::from(msg);
+        assert_eq!(ParsedMsg::from_bytes(&bytes[..bytes.len() - 1]), None);
+        bytes.push(0);
+        assert_eq!(ParsedMsg::from_bytes(&bytes), None);
+    }
+
+    fn frames(decoder: &mut FrameDecoder) -> Vec<Result<Vec<u8>, FrameError>> {
+        std::iter::from_fn(|| decoder.next_frame().map(|frame| frame.map(<[u8]>::to_vec))).collect()
+    }
+
+    #[test]
+    fn decoder_split_test() {
+        let msgs = [
+            SerializedMessage::from_string("Hello"),
+            SerializedMessage::from_ping(7),
+            SerializedMessage::from_room_text(Some(1), "lobby", "alice: Hi"),
+        ];
+        let bytes: Vec<u8> = msgs
+            .iter()
+            .flat_map(|msg| msg.as_bytes().to_vec())
+            .collect();
+        let expected: Vec<_> = msgs.iter().map(|msg| Ok(msg.as_bytes().to_vec())).collect();
+
+        // All at once
+        let mut decoder = FrameDecoder::new(MAX_MSG_LEN);
+        decoder.push(&bytes);
+        assert_eq!(frames(&mut decoder), expected);
+        assert!(!decoder.in_frame());
+
+        // Cut anywhere
+        for cut in 0..=bytes.len() {
+            let mut decoder = FrameDecoder::new(MAX_MSG_LEN);
+            decoder.push(&bytes[..cut]);
+            let mut decoded = frames(&mut decoder);
+            decoder.push(&bytes[cut..]);
+            decoded.extend(frames(&mut decoder));
+            assert_eq!(decoded, expected, "cut at {}", cut);
+        }
+
+        // One byte at a time
+        let ends: Vec<usize> = msgs
+            .iter()
+            .scan(0, |end, msg| {
+                *end += msg.as_bytes().len();
+                Some(*end)
+            })
+            .collect();
+        let mut decoder = FrameDecoder::new(MAX_MSG_LEN);
+        let mut decoded = Vec::new();
+        for (i, byte) in bytes.iter().enumerate() {
+            decoder.push(&[*byte]);
+            decoded.extend(frames(&mut decoder));
+            assert_eq!(decoder.in_frame(), !ends.contains(&(i + 1)));
+        }
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn decoder_partial_test() {
+        let mut decoder = FrameDecoder::new(MAX_MSG_LEN);
+        assert!(!decoder.in_frame());
+        assert_eq!(decoder.next_frame(), None);
+
+        let msg = SerializedMessage::from_user_count(3);
+        let (head, tail) = msg.as_bytes().split_at(2);
+        decoder.push(head);
+        assert_eq!(decoder.next_frame(), None);
+        assert!(decoder.in_frame());
+        decoder.push(tail);
+        assert_eq!(decoder.next_frame(), Some(Ok(msg.as_bytes())));
+        assert_eq!(decoder.next_frame(), None);
+        assert!(!decoder.in_frame());
+
+        // A frame with no payload is still a frame
+        let empty = SerializedMessage::from_string("");
+        decoder.push(empty.as_bytes());
+        assert_eq!(decoder.next_frame(), Some(Ok(empty.as_bytes())));
+    }
+
+    #[test]
+    fn decoder_too_short_test() {
+        let mut decoder = FrameDecoder::new(MAX_MSG_LEN);
+        let msg = SerializedMessage::from_string("after");
+        for size in 0..SerializedMessage::size_of_header() as u32 {
+            decoder.push(&size.to_be_bytes());
+            // Not before the whole header
+            assert_eq!(decoder.next_frame(), None);
+            decoder.push(&[MsgType::Text as u8]);
+            decoder.push(msg.as_bytes());
+            assert_eq!(
+                frames(&mut decoder),
+                vec![Err(FrameError::TooShort(size)), Ok(msg.as_bytes().to_vec())]
+            );
+        }
+    }
+
+    #[test]
+    fn decoder_too_long_test() {
+        let long = SerializedMessage::from_string(&"x".repeat(100));
+        let size = long.as_bytes().len() as u32;
+        let msg = SerializedMessage::from_string("after");
+        let mut decoder = FrameDecoder::new(64);
+
+        // All at once, along with the next frame
+        let bytes = [long.as_bytes(), msg.as_bytes()].concat();
+        decoder.push(&bytes);
+        assert_eq!(
+            frames(&mut decoder),
+            vec![Err(FrameError::TooLong(size)), Ok(msg.as_bytes().to_vec())]
+        );
+
+        // Skipped as it arrives, without storing it
+        let (head, tail) = bytes.split_at(10);
+        decoder.push(head);
+        assert_eq!(frames(&mut decoder), vec![Err(FrameError::TooLong(size))]);
+        assert!(decoder.in_frame());
+        for byte in &tail[..tail.len() - msg.as_bytes().len()] {
+            decoder.push(&[*byte]);
+            assert_eq!(decoder.next_frame(), None);
+            assert!(decoder.buf.is_empty());
+        }
+        assert!(!decoder.in_frame());
+        decoder.push(msg.as_bytes());
+        assert_eq!(decoder.next_frame(), Some(Ok(msg.as_bytes())));
+
+        // A new limit applies from the next frame
+        decoder.set_max_len(size as usize);
+        decoder.push(long.as_bytes());
+        assert_eq!(decoder.next_frame(), Some(Ok(long.as_bytes())));
+    }
+
+    #[test]
+    fn room_cmd_test() {
+        let msg = SerializedMessage::from_string("/join rust");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(parsed, ParsedMsg::Command(Cmd::Join("rust".to_string())));
+
+        let msg = SerializedMessage::from_string("/leave");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(parsed, ParsedMsg::Command(Cmd::Leave));
+    }
+
+    #[test]
+    fn room_text_test() {
+        let msg = SerializedMessage::from_room_text(Some(42), "rust", "alice: Hi");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(
+            parsed,
+            ParsedMsg::RoomText {
+                seq: Some(42),
+                room: "rust".to_string(),
+                text: "alice: Hi".to_string()
+            }
+        );
+
+        let msg = SerializedMessage::from_room_text(None, "rust", "bob left the room");
+        let Some(ParsedMsg::RoomText { seq, .. }) = ParsedMsg::from_bytes(msg.as_bytes()) else {
+            panic!("Invalid msg");
         };
+        assert_eq!(seq, None);
+
+        let msg = SerializedMessage::from_room_list(&["lobby", "rust"]);
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(
+            parsed,
+            ParsedMsg::RoomList(vec!["lobby".to_string(), "rust".to_string()])
+        );
+    }
+
+    #[test]
+    fn whisper_test() {
+        let msg = SerializedMessage::from_string("/msg bob  how are you?");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(
+            parsed,
+            ParsedMsg::Command(Cmd::DirectMsg {
+                nick: "bob".to_string(),
+                text: "how are you?".to_string()
+            })
+        );
+
+        let msg = SerializedMessage::from_whisper("alice", "bob", "fine");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(
+            parsed,
+            ParsedMsg::Whisper {
+                from: "alice".to_string(),
+                to: "bob".to_string(),
+                text: "fine".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn history_test() {
+        let msg =
+            SerializedMessage::from_history(7, Some(4), 1_700_000_000, "lobby", "alice", "Hi");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(
+            parsed,
+            ParsedMsg::History {
+                seq: 7,
+                prev_seq: Some(4),
+                timestamp: 1_700_000_000,
+                room: "lobby".to_string(),
+                sender: "alice".to_string(),
+                text: "Hi".to_string()
+            }
+        );
+
+        let msg = SerializedMessage::from_history(1, None, 1_700_000_000, "lobby", "alice", "Hi");
+        let Some(ParsedMsg::History { prev_seq, .. }) = ParsedMsg::from_bytes(msg.as_bytes())
+        else {
+            panic!("Invalid msg");
+        };
+        assert_eq!(prev_seq, None);
+    }
+
+    #[test]
+    fn auth_test() {
+        let msg = SerializedMessage::from_auth_request(AuthKind::Register, "alice", "pass word");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(
+            parsed,
+            ParsedMsg::AuthRequest {
+                kind: AuthKind::Register,
+                user: "alice".to_string(),
+                password: "pass word".to_string()
+            }
+        );
+
+        let msg = SerializedMessage::from_auth_response(AuthStatus::UserExists, "taken");
+        let parsed = ParsedMsg::from_bytes(msg.as_bytes()).unwrap();
+        assert_eq!(
+            parsed,
+            ParsedMsg::AuthResponse(AuthStatus::UserExists, "taken".to_string())
+        );
     }
 }